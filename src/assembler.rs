@@ -1,4 +1,23 @@
-use std::collections::HashMap;
+use crate::isa::{self, Instruction};
+use std::collections::{BTreeMap, HashMap};
+
+/// Source location of one assembled instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceLine {
+    /// 1-based line number in the assembler source.
+    pub line: usize,
+    pub instr: Instruction,
+}
+
+/// Output of the assembler: encoded bytes plus the symbol table and line info.
+#[derive(Debug, Clone, Default)]
+pub struct Program {
+    pub bytes: Vec<u8>,
+    /// Label name -> address.
+    pub labels: HashMap<String, usize>,
+    /// Instruction address -> source line.
+    pub line_info: BTreeMap<usize, SourceLine>,
+}
 
 /// Assemble the toy ISA source into bytes.
 /// - Two-pass assembler: first collects labels (and handles `ORG` directive), then encodes.
@@ -6,8 +25,14 @@ use std::collections::HashMap;
 /// - Registers: R0..R3
 /// - Numeric formats: decimal (e.g. 42) or hex (0x2A).
 pub fn assemble(src: &str) -> Result<Vec<u8>, String> {
+    assemble_program(src).map(|p| p.bytes)
+}
+
+/// Like `assemble`, but also returns the label table and line info
+/// (used by the profiler, coverage and the REPL).
+pub fn assemble_program(src: &str) -> Result<Program, String> {
     let mut labels: HashMap<String, usize> = HashMap::new();
-    let mut lines: Vec<String> = Vec::new();
+    let mut lines: Vec<(usize, usize, String)> = Vec::new();
     let mut pc: usize = 0;
 
     // Normalize lines and collect for second pass
@@ -55,87 +80,89 @@ pub fn assemble(src: &str) -> Result<Vec<u8>, String> {
         let (mnemonic, _) = split_mnemonic_operands(&line);
         let instr_size = instruction_size(&mnemonic)
             .ok_or_else(|| format!("Unknown mnemonic '{}' at line {}", mnemonic, lineno+1))?;
-        lines.push(line);
+        lines.push((lineno, pc, line));
         pc = pc.wrapping_add(instr_size);
     }
 
     // Second pass: encode
     let mut out: Vec<u8> = Vec::new();
-    let mut cur_pc = 0usize;
-    for (lineno, line) in lines.into_iter().enumerate() {
+    let mut line_info: BTreeMap<usize, SourceLine> = BTreeMap::new();
+    for (lineno, addr, line) in lines {
+        let start = out.len();
         let (mnemonic, operands) = split_mnemonic_operands(&line);
         let mnemonic_upper = mnemonic.to_uppercase();
         match mnemonic_upper.as_str() {
             "LDI" => {
                 let (r, imm) = parse_two_operands_reg_imm(&operands, lineno+1)?;
                 if r > 3 { return Err(format!("Invalid register R{} at line {}", r, lineno+1)); }
-                out.push(0x10 | (r as u8));
+                out.push(0x10 | r);
                 out.push(imm);
-                cur_pc += 2;
             }
             "ADD" => {
                 let (d, s) = parse_two_operands_reg_reg(&operands, lineno+1)?;
                 if d > 3 || s > 3 { return Err(format!("Invalid register at line {}", lineno+1)); }
-                out.push(0x20 | (d as u8));
-                out.push(s as u8);
-                cur_pc += 2;
+                out.push(0x20 | d);
+                out.push(s);
             }
             "SUB" => {
                 let (d, s) = parse_two_operands_reg_reg(&operands, lineno+1)?;
                 if d > 3 || s > 3 { return Err(format!("Invalid register at line {}", lineno+1)); }
-                out.push(0x21 | (d as u8));
-                out.push(s as u8);
-                cur_pc += 2;
+                out.push(0x24 | d);
+                out.push(s);
             }
             "LOAD" => {
                 let (d, addr) = parse_two_operands_reg_addr(&operands, lineno+1, &labels)?;
                 if d > 3 { return Err(format!("Invalid register R{} at line {}", d, lineno+1)); }
-                out.push(0x30 | (d as u8));
+                out.push(0x30 | d);
                 out.push(addr);
-                cur_pc += 2;
             }
             "STORE" => {
                 let (s, addr) = parse_two_operands_reg_addr(&operands, lineno+1, &labels)?;
                 if s > 3 { return Err(format!("Invalid register R{} at line {}", s, lineno+1)); }
-                out.push(0x31 | (s as u8));
+                out.push(0x34 | s);
                 out.push(addr);
-                cur_pc += 2;
             }
             "JMP" => {
-                let addr = parse_addr_operand(&operands.trim(), lineno+1, &labels)?;
+                let addr = parse_addr_operand(operands.trim(), lineno+1, &labels)?;
                 out.push(0x40);
                 out.push(addr);
-                cur_pc += 2;
             }
             "JZ" => {
                 // form: JZ Rn, addr
                 let (r, addr) = parse_two_operands_reg_addr(&operands, lineno+1, &labels)?;
                 if r > 3 { return Err(format!("Invalid register R{} at line {}", r, lineno+1)); }
-                out.push(0x41 | (r as u8));
+                out.push(0x44 | r);
                 out.push(addr);
-                cur_pc += 2;
             }
             "OUT" => {
-                let r = parse_reg_operand(&operands.trim(), lineno+1)?;
+                let r = parse_reg_operand(operands.trim(), lineno+1)?;
                 if r > 3 { return Err(format!("Invalid register R{} at line {}", r, lineno+1)); }
-                out.push(0x50 | (r as u8));
-                cur_pc += 1;
+                out.push(0x50 | r);
+            }
+            "EI" => {
+                out.push(0xFC);
+            }
+            "DI" => {
+                out.push(0xFD);
+            }
+            "RETI" => {
+                out.push(0xFE);
             }
             "HLT" => {
                 out.push(0xFF);
-                cur_pc += 1;
             }
             "NOP" => {
                 out.push(0x00);
-                cur_pc += 1;
             }
             _ => {
                 return Err(format!("Unknown mnemonic '{}' at assembly pass line {}", mnemonic, lineno+1));
             }
         }
+        let instr = isa::decode(out[start], out.get(start + 1).copied().unwrap_or(0));
+        line_info.insert(addr, SourceLine { line: lineno + 1, instr });
     }
 
-    Ok(out)
+    Ok(Program { bytes: out, labels, line_info })
 }
 
 fn split_mnemonic_operands(line: &str) -> (String, String) {
@@ -158,6 +185,9 @@ fn instruction_size(mnemonic: &str) -> Option<usize> {
         "JMP" => Some(2),
         "JZ" => Some(2),
         "OUT" => Some(1),
+        "EI" => Some(1),
+        "DI" => Some(1),
+        "RETI" => Some(1),
         "HLT" => Some(1),
         "NOP" => Some(1),
         _ => None,
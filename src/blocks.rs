@@ -0,0 +1,371 @@
+// src/blocks.rs
+use crate::assembler::assemble;
+use crate::cpu::CPU;
+use crate::isa::{self, Instruction};
+use crate::memory::{AccessKind, Memory};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+/// Longest basic block, in instructions.
+pub const MAX_BLOCK_LEN: usize = 32;
+
+/// Default program for `benchmark`: a loop of loads, stores, arithmetic and branches.
+pub const BENCH_PROGRAM: &str = r#"
+    LDI R1, 1
+    loop:
+    LOAD R0, 0x80
+    ADD R0, R1
+    STORE R0, 0x80
+    SUB R2, R1
+    JZ R2, skip
+    ADD R3, R1
+    skip:
+    JMP loop
+"#;
+
+/// An instruction decoded ahead of execution.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decoded {
+    pub pc: usize,
+    pub opcode: u8,
+    pub operand: Option<u8>,
+    /// Where the operand byte was fetched from.
+    pub operand_addr: usize,
+    pub instr: Instruction,
+    /// Address of the following instruction.
+    pub next: usize,
+    /// Region wait states for fetching the instruction's bytes.
+    pub fetch_wait: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BlockStats {
+    /// Blocks decoded, including rebuilds.
+    pub built: u64,
+    /// Instructions served from an already decoded block.
+    pub hits: u64,
+    /// Blocks dropped because code they decoded was overwritten.
+    pub invalidated: u64,
+}
+
+#[derive(Debug, Clone)]
+struct Block {
+    /// Distinguishes a rebuilt block from the one it replaced.
+    serial: u64,
+    instrs: Vec<Decoded>,
+}
+
+/// Predecoded basic blocks by start address: straight-line runs of instructions up to
+/// and including a branch, RETI or HLT. Decoded bytes are marked in `Memory` and any
+/// write clears the mark, so an overwritten instruction is decoded again before it runs
+/// (self-modifying code, DMA or `cpu.mem` writes alike), and every block holding
+/// overwritten code is dropped before a new block is decoded.
+#[derive(Debug)]
+pub struct BlockCache {
+    blocks: Vec<Option<Block>>,
+    /// Block start and index of the instruction expected next.
+    cursor: Option<(usize, usize)>,
+    /// Unique to this cache (clones get their own), so serials of different caches,
+    /// which all count from 1, are never mistaken for each other.
+    generation: u64,
+    /// Serial of the last block built.
+    serial: u64,
+    pub stats: BlockStats,
+}
+
+/// Source of `BlockCache::generation`.
+static GENERATIONS: AtomicU64 = AtomicU64::new(1);
+
+impl Default for BlockCache {
+    fn default() -> Self {
+        BlockCache {
+            blocks: Vec::new(),
+            cursor: None,
+            generation: GENERATIONS.fetch_add(1, Ordering::Relaxed),
+            serial: 0,
+            stats: BlockStats::default(),
+        }
+    }
+}
+
+impl Clone for BlockCache {
+    fn clone(&self) -> Self {
+        BlockCache { blocks: self.blocks.clone(), cursor: self.cursor, serial: self.serial, stats: self.stats, ..BlockCache::default() }
+    }
+}
+
+impl BlockCache {
+    pub fn new() -> Self {
+        BlockCache::default()
+    }
+
+    /// Identifies this cache among all others; see `block`.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Number of cached blocks.
+    pub fn len(&self) -> usize {
+        self.blocks.iter().flatten().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+        self.cursor = None;
+    }
+
+    /// The instruction at `pc`, decoded now unless a block still holds it.
+    pub fn fetch(&mut self, mem: &mut Memory, pc: usize) -> Decoded {
+        if let Some((start, i)) = self.cursor {
+            if let Some(d) = self.blocks.get(start).and_then(Option::as_ref).and_then(|b| b.instrs.get(i)) {
+                if d.pc == pc && intact(mem, d) {
+                    let d = *d;
+                    self.cursor = Some((start, i + 1));
+                    self.stats.hits += 1;
+                    return d;
+                }
+            }
+        }
+        if let Some(Some(block)) = self.blocks.get(pc) {
+            if intact(mem, &block.instrs[0]) {
+                let d = block.instrs[0];
+                self.cursor = Some((pc, 1));
+                self.stats.hits += 1;
+                return d;
+            }
+        }
+        self.build(mem, pc)
+    }
+
+    /// The block starting at `pc` and its serial number, if it is cached and none of its
+    /// code has been overwritten. A serial is only unique within this cache's `generation`.
+    pub fn block(&self, mem: &Memory, pc: usize) -> Option<(u64, &[Decoded])> {
+        let block = self.blocks.get(pc)?.as_ref()?;
+        block.instrs.iter().all(|d| intact(mem, d)).then_some((block.serial, &block.instrs[..]))
+    }
+
+    /// Decode the block starting at `pc` and return its first instruction.
+    fn build(&mut self, mem: &mut Memory, pc: usize) -> Decoded {
+        self.purge(mem);
+        self.blocks.resize(mem.size(), None);
+        let size = mem.size();
+        let mut block = Vec::new();
+        let mut at = pc;
+        loop {
+            let opcode = mem.read(at);
+            let operand_addr = (at + 1) % size;
+            let mut next = operand_addr;
+            let mut fetch_wait = mem.wait_states(at, AccessKind::Fetch);
+            let mut operand = None;
+            if isa::has_operand(opcode) {
+                operand = Some(mem.read(operand_addr));
+                mem.mark_code(operand_addr);
+                fetch_wait += mem.wait_states(operand_addr, AccessKind::Fetch);
+                next = (next + 1) % size;
+            }
+            mem.mark_code(at);
+            let instr = isa::decode(opcode, operand.unwrap_or(0));
+            block.push(Decoded { pc: at, opcode, operand, operand_addr, instr, next, fetch_wait });
+            if instr.is_branch() || instr == Instruction::Hlt || block.len() == MAX_BLOCK_LEN || next == pc % size {
+                break;
+            }
+            at = next;
+        }
+        self.stats.built += 1;
+        self.serial += 1;
+        let first = block[0];
+        if pc < size {
+            self.blocks[pc] = Some(Block { serial: self.serial, instrs: block });
+            self.cursor = Some((pc, 1));
+        }
+        first
+    }
+
+    /// Drop every block with an overwritten byte.
+    fn purge(&mut self, mem: &Memory) {
+        for slot in self.blocks.iter_mut() {
+            if slot.as_ref().is_some_and(|b| !b.instrs.iter().all(|d| intact(mem, d))) {
+                *slot = None;
+                self.stats.invalidated += 1;
+            }
+        }
+        self.cursor = None;
+    }
+}
+
+/// Whether the bytes `d` was decoded from are unchanged.
+fn intact(mem: &Memory, d: &Decoded) -> bool {
+    mem.is_code(d.pc) && (d.operand.is_none() || mem.is_code(d.operand_addr))
+}
+
+/// Instructions per second of the plain interpreter and of the block cache.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Benchmark {
+    pub instructions: u64,
+    pub interpreter: f64,
+    pub predecoded: f64,
+}
+
+impl Benchmark {
+    pub fn speedup(&self) -> f64 {
+        self.predecoded / self.interpreter
+    }
+}
+
+/// Rounds `benchmark` splits its instructions into, alternating which path runs first.
+const BENCH_ROUNDS: u64 = 4;
+
+/// Run `program` (loaded at 0 and restarted whenever it halts) for `instructions`
+/// instructions on a fresh CPU, once without and once with a `BlockCache`. Both paths
+/// are warmed up first, and the timed runs alternate so neither always goes second.
+pub fn benchmark(program: &[u8], instructions: u64) -> Benchmark {
+    let time = |predecoded: bool, n: u64| {
+        let mut cpu = CPU::new();
+        cpu.block_cache = predecoded.then(BlockCache::new);
+        cpu.load(program, 0);
+        let start = Instant::now();
+        for _ in 0..n {
+            if cpu.halted {
+                cpu.reset();
+            }
+            cpu.step_and_tick_instruction();
+        }
+        start.elapsed().as_secs_f64()
+    };
+    let per_round = (instructions / BENCH_ROUNDS).max(1);
+    time(false, per_round);
+    time(true, per_round);
+    let (mut interpreter, mut predecoded) = (0.0, 0.0);
+    for round in 0..BENCH_ROUNDS {
+        if round % 2 == 0 {
+            interpreter += time(false, per_round);
+            predecoded += time(true, per_round);
+        } else {
+            predecoded += time(true, per_round);
+            interpreter += time(false, per_round);
+        }
+    }
+    let timed = per_round * BENCH_ROUNDS;
+    let rate = |secs: f64| timed as f64 / secs.max(1e-9);
+    Benchmark { instructions: timed, interpreter: rate(interpreter), predecoded: rate(predecoded) }
+}
+
+/// `benchmark` on `BENCH_PROGRAM`.
+pub fn benchmark_default(instructions: u64) -> Benchmark {
+    benchmark(&assemble(BENCH_PROGRAM).expect("BENCH_PROGRAM assembles"), instructions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn self_modifying_code_is_decoded_again() {
+        // each pass increments the immediate of the LDI at 0x04 (its operand is at 0x05)
+        let program = r#"
+            LDI R1, 1
+            LDI R3, 3
+            patch:
+            LDI R0, 5
+            ADD R2, R0
+            LOAD R0, 0x05
+            ADD R0, R1
+            STORE R0, 0x05
+            SUB R3, R1
+            JZ R3, done
+            JMP patch
+            done:
+            HLT
+        "#;
+        let bytes = assemble(program).unwrap();
+        let run = |blocks: Option<BlockCache>| {
+            let mut cpu = CPU::new();
+            cpu.block_cache = blocks;
+            cpu.load(&bytes, 0);
+            cpu.run();
+            // patch the code from outside and run it again
+            cpu.mem.write_bytes(0x04, &assemble("LDI R0, 100").unwrap());
+            cpu.reset();
+            cpu.run();
+            (cpu.regs, cpu.cycles, cpu.block_cache.map(|b| b.stats))
+        };
+        let (regs, cycles, _) = run(None);
+        let (cached_regs, cached_cycles, stats) = run(Some(BlockCache::new()));
+        // registers are cleared by the reset; the second run adds the patched immediates
+        assert_eq!(regs[2] as u32, (100 + 101 + 102) % 256);
+        assert_eq!((cached_regs, cached_cycles), (regs, cycles));
+        let stats = stats.unwrap();
+        assert!(stats.invalidated >= 3 && stats.hits > 0, "{:?}", stats);
+    }
+
+    #[test]
+    fn blocks_end_at_branches_and_benchmark_runs() {
+        let mut mem = Memory::new();
+        mem.write_bytes(0, &assemble("LDI R0, 1\nADD R0, R0\nJMP 0\nHLT").unwrap());
+        let mut cache = BlockCache::new();
+        let first = cache.fetch(&mut mem, 0);
+        assert_eq!((first.instr, first.next), (Instruction::Ldi { reg: 0, imm: 1 }, 2));
+        assert_eq!(cache.fetch(&mut mem, 2).instr, Instruction::Add { dest: 0, src: 0 });
+        assert_eq!(cache.fetch(&mut mem, 4).instr, Instruction::Jmp { addr: 0 });
+        assert!(!mem.is_code(6));
+        assert_eq!((cache.len(), cache.stats.built, cache.stats.hits), (1, 1, 2));
+
+        let bench = benchmark_default(20_000);
+        assert!(bench.interpreter > 0.0 && bench.predecoded > 0.0);
+        assert_eq!(bench.instructions, 20_000);
+    }
+
+    #[test]
+    fn fetches_reach_devices_unless_they_opt_out() {
+        use crate::device::Device;
+        use crate::memory::MemAccess;
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        struct FetchLog(Rc<RefCell<Vec<(u64, usize)>>>);
+        impl Device for FetchLog {
+            fn tick(&mut self, _current_cycle: u64) {}
+            fn bus_activity(&mut self, current_cycle: u64, access: &MemAccess) {
+                if access.kind == AccessKind::Fetch {
+                    self.0.borrow_mut().push((current_cycle, access.addr));
+                }
+            }
+        }
+        struct OptedOut(FetchLog);
+        impl Device for OptedOut {
+            fn tick(&mut self, _current_cycle: u64) {}
+            fn bus_activity(&mut self, current_cycle: u64, access: &MemAccess) {
+                self.0.bus_activity(current_cycle, access);
+            }
+            fn watches_fetches(&self) -> bool {
+                false
+            }
+        }
+
+        let bytes = assemble(BENCH_PROGRAM).unwrap();
+        let run = |blocks: Option<BlockCache>, opt_out: bool| {
+            let log = Rc::new(RefCell::new(Vec::new()));
+            let mut cpu = CPU::new();
+            cpu.block_cache = blocks;
+            let device = FetchLog(log.clone());
+            if opt_out {
+                cpu.attach_device(Box::new(OptedOut(device)));
+            } else {
+                cpu.attach_device(Box::new(device));
+            }
+            cpu.load(&bytes, 0);
+            cpu.step_n_instructions(50);
+            let fetches = log.borrow().clone();
+            (fetches, cpu.cycles)
+        };
+        let (fetches, cycles) = run(None, false);
+        assert!(!fetches.is_empty());
+        assert_eq!(run(Some(BlockCache::new()), false), (fetches, cycles));
+        // fetches from predecoded blocks are only counted when nobody watches them
+        assert_eq!(run(Some(BlockCache::new()), true), (Vec::new(), cycles));
+    }
+}
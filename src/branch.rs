@@ -0,0 +1,373 @@
+// src/branch.rs
+use crate::isa::Instruction;
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Formatter};
+
+/// A branch direction/target predictor. `predict` is consulted when a branch is
+/// fetched, `update` once its outcome is known.
+pub trait BranchPredictor {
+    /// Predicted destination of the branch at `pc`: `Some(addr)` to predict taken
+    /// to `addr`, `None` to predict fall-through. `target` is the branch's encoded
+    /// destination, which direction-only predictors simply return when predicting taken.
+    fn predict(&mut self, pc: usize, target: usize) -> Option<usize>;
+    fn update(&mut self, pc: usize, target: usize, taken: bool);
+    fn name(&self) -> String;
+}
+
+impl Debug for dyn BranchPredictor {
+    fn fmt(&self, _: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        Ok(())
+    }
+}
+
+/// Built-in predictor models.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PredictorKind {
+    /// Always predict fall-through.
+    StaticNotTaken,
+    /// Backward branches (loops) taken, forward branches not taken.
+    StaticBackwardTaken,
+    /// Table of last outcomes indexed by PC.
+    OneBit { entries: usize },
+    /// Table of 2-bit saturating counters indexed by PC.
+    TwoBit { entries: usize },
+    /// 2-bit counters indexed by PC xor a global history of `history_bits` outcomes.
+    Gshare { history_bits: u32 },
+    /// Direct-mapped branch target buffer: a hit predicts taken to the stored target.
+    Btb { entries: usize },
+}
+
+impl PredictorKind {
+    pub fn build(self) -> Box<dyn BranchPredictor> {
+        match self {
+            PredictorKind::StaticNotTaken => Box::new(StaticNotTaken),
+            PredictorKind::StaticBackwardTaken => Box::new(StaticBackwardTaken),
+            PredictorKind::OneBit { entries } => Box::new(OneBit { table: vec![false; entries.max(1)] }),
+            PredictorKind::TwoBit { entries } => Box::new(TwoBit { table: vec![1; entries.max(1)] }),
+            PredictorKind::Gshare { history_bits } => Box::new(Gshare {
+                history_bits,
+                history: 0,
+                table: vec![1; 1 << history_bits.min(16)],
+            }),
+            PredictorKind::Btb { entries } => Box::new(Btb { table: vec![None; entries.max(1)] }),
+        }
+    }
+}
+
+pub struct StaticNotTaken;
+
+impl BranchPredictor for StaticNotTaken {
+    fn predict(&mut self, _pc: usize, _target: usize) -> Option<usize> {
+        None
+    }
+
+    fn update(&mut self, _pc: usize, _target: usize, _taken: bool) {}
+
+    fn name(&self) -> String {
+        "static-not-taken".to_string()
+    }
+}
+
+pub struct StaticBackwardTaken;
+
+impl BranchPredictor for StaticBackwardTaken {
+    fn predict(&mut self, pc: usize, target: usize) -> Option<usize> {
+        if target <= pc {
+            Some(target)
+        } else {
+            None
+        }
+    }
+
+    fn update(&mut self, _pc: usize, _target: usize, _taken: bool) {}
+
+    fn name(&self) -> String {
+        "static-backward-taken".to_string()
+    }
+}
+
+pub struct OneBit {
+    table: Vec<bool>,
+}
+
+impl BranchPredictor for OneBit {
+    fn predict(&mut self, pc: usize, target: usize) -> Option<usize> {
+        if self.table[pc % self.table.len()] {
+            Some(target)
+        } else {
+            None
+        }
+    }
+
+    fn update(&mut self, pc: usize, _target: usize, taken: bool) {
+        let n = self.table.len();
+        self.table[pc % n] = taken;
+    }
+
+    fn name(&self) -> String {
+        format!("1-bit({})", self.table.len())
+    }
+}
+
+fn bump(counter: &mut u8, taken: bool) {
+    if taken {
+        *counter = (*counter + 1).min(3);
+    } else {
+        *counter = counter.saturating_sub(1);
+    }
+}
+
+pub struct TwoBit {
+    table: Vec<u8>,
+}
+
+impl BranchPredictor for TwoBit {
+    fn predict(&mut self, pc: usize, target: usize) -> Option<usize> {
+        if self.table[pc % self.table.len()] >= 2 {
+            Some(target)
+        } else {
+            None
+        }
+    }
+
+    fn update(&mut self, pc: usize, _target: usize, taken: bool) {
+        let n = self.table.len();
+        bump(&mut self.table[pc % n], taken);
+    }
+
+    fn name(&self) -> String {
+        format!("2-bit({})", self.table.len())
+    }
+}
+
+pub struct Gshare {
+    history_bits: u32,
+    history: usize,
+    table: Vec<u8>,
+}
+
+impl Gshare {
+    fn index(&self, pc: usize) -> usize {
+        (pc ^ self.history) % self.table.len()
+    }
+}
+
+impl BranchPredictor for Gshare {
+    fn predict(&mut self, pc: usize, target: usize) -> Option<usize> {
+        if self.table[self.index(pc)] >= 2 {
+            Some(target)
+        } else {
+            None
+        }
+    }
+
+    fn update(&mut self, pc: usize, _target: usize, taken: bool) {
+        let i = self.index(pc);
+        bump(&mut self.table[i], taken);
+        self.history = ((self.history << 1) | taken as usize) % self.table.len();
+    }
+
+    fn name(&self) -> String {
+        format!("gshare({} bits)", self.history_bits)
+    }
+}
+
+pub struct Btb {
+    table: Vec<Option<(usize, usize)>>,
+}
+
+impl BranchPredictor for Btb {
+    fn predict(&mut self, pc: usize, _target: usize) -> Option<usize> {
+        match self.table[pc % self.table.len()] {
+            Some((tag, dest)) if tag == pc => Some(dest),
+            _ => None,
+        }
+    }
+
+    fn update(&mut self, pc: usize, target: usize, taken: bool) {
+        let n = self.table.len();
+        self.table[pc % n] = if taken { Some((pc, target)) } else { None };
+    }
+
+    fn name(&self) -> String {
+        format!("btb({})", self.table.len())
+    }
+}
+
+/// Outcome statistics for one branch instruction address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BranchSite {
+    pub instr: Instruction,
+    pub executed: u64,
+    pub taken: u64,
+    pub mispredicted: u64,
+}
+
+impl BranchSite {
+    pub fn accuracy(&self) -> f64 {
+        if self.executed == 0 {
+            0.0
+        } else {
+            1.0 - self.mispredicted as f64 / self.executed as f64
+        }
+    }
+}
+
+/// Branch prediction unit attached to the CPU (`cpu.branch_unit`). Every executed
+/// JMP/JZ is predicted and checked; a misprediction adds `penalty` cycles.
+#[derive(Debug)]
+pub struct BranchUnit {
+    predictor: Box<dyn BranchPredictor>,
+    pub penalty: u64,
+    sites: BTreeMap<usize, BranchSite>,
+}
+
+impl BranchUnit {
+    pub fn new(kind: PredictorKind, penalty: u64) -> Self {
+        BranchUnit::with_predictor(kind.build(), penalty)
+    }
+
+    pub fn with_predictor(predictor: Box<dyn BranchPredictor>, penalty: u64) -> Self {
+        BranchUnit { predictor, penalty, sites: BTreeMap::new() }
+    }
+
+    /// Check the prediction for the branch `instr` at `pc`, which actually continued
+    /// at `next_pc` in a memory of `mem_size` bytes (targets and the fall-through wrap
+    /// around it, as they do for the CPU). Returns the extra cycles to charge.
+    pub fn resolve(&mut self, pc: usize, instr: Instruction, next_pc: usize, mem_size: usize) -> u64 {
+        let target = match instr {
+            Instruction::Jmp { addr } | Instruction::Jz { addr, .. } => addr as usize % mem_size,
+            _ => return 0,
+        };
+        let fallthrough = (pc + instr.size()) % mem_size;
+        let taken = next_pc != fallthrough || matches!(instr, Instruction::Jmp { .. });
+        let predicted = self.predictor.predict(pc, target).unwrap_or(fallthrough);
+        self.predictor.update(pc, target, taken);
+
+        let site = self.sites.entry(pc).or_insert(BranchSite { instr, executed: 0, taken: 0, mispredicted: 0 });
+        site.executed += 1;
+        site.taken += taken as u64;
+        if predicted != next_pc {
+            site.mispredicted += 1;
+            self.penalty
+        } else {
+            0
+        }
+    }
+
+    pub fn predictor_name(&self) -> String {
+        self.predictor.name()
+    }
+
+    /// Per-site statistics keyed by branch address.
+    pub fn sites(&self) -> &BTreeMap<usize, BranchSite> {
+        &self.sites
+    }
+
+    /// (executed, mispredicted) over all sites.
+    pub fn totals(&self) -> (u64, u64) {
+        self.sites.values().fold((0, 0), |(e, m), s| (e + s.executed, m + s.mispredicted))
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.sites.clear();
+    }
+
+    pub fn report(&self) -> String {
+        let (executed, mispredicted) = self.totals();
+        let accuracy = if executed == 0 { 0.0 } else { 100.0 * (1.0 - mispredicted as f64 / executed as f64) };
+        let mut s = format!(
+            "Predictor {} (penalty {}): {} branches, {} mispredicted, accuracy {:.1}%\n",
+            self.predictor.name(),
+            self.penalty,
+            executed,
+            mispredicted,
+            accuracy
+        );
+        for (pc, site) in &self.sites {
+            s.push_str(&format!(
+                "  {:02X} {:<16} executed={} taken={} mispredicted={} accuracy={:.1}%\n",
+                pc,
+                site.instr.to_string(),
+                site.executed,
+                site.taken,
+                site.mispredicted,
+                100.0 * site.accuracy()
+            ));
+        }
+        s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::cpu::CPU;
+
+    const LOOP: &str = r#"
+        LDI R0, 8
+        LDI R1, 1
+        loop:
+        SUB R0, R1
+        JZ R0, done
+        JMP loop
+        done:
+        HLT
+    "#;
+
+    fn run(kind: PredictorKind) -> CPU {
+        let mut cpu = CPU::new();
+        cpu.branch_unit = Some(BranchUnit::new(kind, 2));
+        cpu.load(&assemble(LOOP).unwrap(), 0);
+        cpu.run();
+        cpu
+    }
+
+    #[test]
+    fn predictors_compare_on_same_program() {
+        let mut base = CPU::new();
+        base.load(&assemble(LOOP).unwrap(), 0);
+        base.run();
+
+        let nt = run(PredictorKind::StaticNotTaken);
+        let unit = nt.branch_unit.as_ref().unwrap();
+        // JZ falls through 7 times and is taken once; every JMP is mispredicted
+        assert_eq!(unit.sites()[&0x06].mispredicted, 1);
+        assert_eq!(unit.sites()[&0x08].mispredicted, 7);
+        assert_eq!(nt.cycles, base.cycles + 2 * 8);
+
+        let btfn = run(PredictorKind::StaticBackwardTaken);
+        assert_eq!(btfn.branch_unit.as_ref().unwrap().totals(), (15, 1));
+
+        let two_bit = run(PredictorKind::TwoBit { entries: 16 });
+        let one_bit = run(PredictorKind::OneBit { entries: 16 });
+        let gshare = run(PredictorKind::Gshare { history_bits: 4 });
+        let btb = run(PredictorKind::Btb { entries: 4 });
+        for cpu in [&two_bit, &one_bit, &gshare, &btb] {
+            assert_eq!(cpu.regs, base.regs);
+            let (executed, mispredicted) = cpu.branch_unit.as_ref().unwrap().totals();
+            assert_eq!(executed, 15);
+            assert!(mispredicted < 8, "{}", cpu.branch_unit.as_ref().unwrap().report());
+        }
+        assert!(two_bit.branch_unit.as_ref().unwrap().report().contains("08 JMP 0x04"));
+    }
+
+    #[test]
+    fn targets_wrap_around_small_memory() {
+        use crate::memory::Memory;
+
+        // in 64 bytes of memory, JMP 0x44 lands on the loop at 0x04 like JMP 0x04 does
+        let run = |jmp: &str| {
+            let mut cpu = CPU::new();
+            cpu.mem = Memory::with_size(64);
+            cpu.branch_unit = Some(BranchUnit::new(PredictorKind::Btb { entries: 4 }, 2));
+            cpu.load(&assemble(&LOOP.replace("JMP loop", jmp)).unwrap(), 0);
+            cpu.run();
+            (cpu.regs, cpu.cycles, cpu.branch_unit.unwrap().totals())
+        };
+        let wrapped = run("JMP 0x44");
+        assert_eq!(wrapped, run("JMP 0x04"));
+        assert_eq!(wrapped.2, (15, 2));
+    }
+}
@@ -0,0 +1,320 @@
+// src/cache.rs
+
+/// Victim selection within a set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Replacement {
+    Lru,
+    Fifo,
+    /// Pseudo-random victim from a seeded xorshift generator (reproducible).
+    Random { seed: u64 },
+}
+
+/// How writes reach memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WritePolicy {
+    /// Writes mark the line dirty; dirty lines are written to memory on eviction.
+    /// Write misses allocate the line.
+    WriteBack,
+    /// Every write also goes to memory. Write misses do not allocate.
+    WriteThrough,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheConfig {
+    /// Total capacity in bytes.
+    pub size: usize,
+    pub line_size: usize,
+    /// Ways per set (1 = direct-mapped, size / line_size = fully associative).
+    pub associativity: usize,
+    pub replacement: Replacement,
+    pub write_policy: WritePolicy,
+    /// Extra cycles added to an instruction for each access that hits.
+    pub hit_latency: u64,
+    /// Extra cycles for each memory transfer: a line fill, a dirty write-back or a write-through.
+    pub miss_latency: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            size: 64,
+            line_size: 8,
+            associativity: 2,
+            replacement: Replacement::Lru,
+            write_policy: WritePolicy::WriteBack,
+            hit_latency: 0,
+            miss_latency: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub reads: u64,
+    pub read_misses: u64,
+    pub writes: u64,
+    pub write_misses: u64,
+    pub evictions: u64,
+    pub writebacks: u64,
+    /// Total latency this cache added to the cycle count.
+    pub cycles: u64,
+}
+
+impl CacheStats {
+    pub fn accesses(&self) -> u64 {
+        self.reads + self.writes
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.read_misses + self.write_misses
+    }
+
+    pub fn hit_rate(&self) -> f64 {
+        if self.accesses() == 0 {
+            0.0
+        } else {
+            1.0 - self.misses() as f64 / self.accesses() as f64
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Line {
+    valid: bool,
+    dirty: bool,
+    tag: usize,
+    /// Last use (LRU) or fill time (FIFO).
+    stamp: u64,
+}
+
+/// Set-associative cache timing model. It tracks tags only: data always lives in
+/// `Memory`, so the cache changes how many cycles accesses take, never their values.
+#[derive(Debug, Clone)]
+pub struct Cache {
+    config: CacheConfig,
+    sets: Vec<Vec<Line>>,
+    stats: CacheStats,
+    clock: u64,
+    rng: u64,
+}
+
+impl Cache {
+    pub fn new(config: CacheConfig) -> Result<Self, String> {
+        if config.line_size == 0 || !config.line_size.is_power_of_two() {
+            return Err(format!("line size must be a power of two, got {}", config.line_size));
+        }
+        if config.associativity == 0 || !config.size.is_multiple_of(config.line_size * config.associativity) {
+            return Err(format!(
+                "size {} is not a multiple of line size {} x associativity {}",
+                config.size, config.line_size, config.associativity
+            ));
+        }
+        let num_sets = config.size / (config.line_size * config.associativity);
+        if num_sets == 0 || !num_sets.is_power_of_two() {
+            return Err(format!("number of sets must be a power of two, got {}", num_sets));
+        }
+        let rng = match config.replacement {
+            Replacement::Random { seed } => seed.max(1),
+            _ => 1,
+        };
+        Ok(Cache {
+            config,
+            sets: vec![vec![Line::default(); config.associativity]; num_sets],
+            stats: CacheStats::default(),
+            clock: 0,
+            rng,
+        })
+    }
+
+    pub fn config(&self) -> CacheConfig {
+        self.config
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /// Invalidate every line and clear the statistics.
+    pub fn reset(&mut self) {
+        *self = Cache::new(self.config).expect("config was validated");
+    }
+
+    /// Model one access and return the extra cycles it costs.
+    pub fn access(&mut self, addr: usize, write: bool) -> u64 {
+        self.access_detail(addr, write).1
+    }
+
+    /// Like `access`, but also reports whether the access hit.
+    pub fn access_detail(&mut self, addr: usize, write: bool) -> (bool, u64) {
+        self.clock += 1;
+        let line_addr = addr / self.config.line_size;
+        let set_idx = line_addr % self.sets.len();
+        let tag = line_addr / self.sets.len();
+        let mut latency = self.config.hit_latency;
+        if write {
+            self.stats.writes += 1;
+        } else {
+            self.stats.reads += 1;
+        }
+
+        let way = self.sets[set_idx].iter().position(|l| l.valid && l.tag == tag);
+        let hit = way.is_some();
+        let write_through = self.config.write_policy == WritePolicy::WriteThrough;
+        match way {
+            Some(w) => {
+                let line = &mut self.sets[set_idx][w];
+                if self.config.replacement == Replacement::Lru {
+                    line.stamp = self.clock;
+                }
+                if write && !write_through {
+                    line.dirty = true;
+                }
+            }
+            None => {
+                if write {
+                    self.stats.write_misses += 1;
+                } else {
+                    self.stats.read_misses += 1;
+                }
+                if !(write && write_through) {
+                    latency += self.fill(set_idx, tag, write);
+                }
+            }
+        }
+        if write && write_through {
+            latency += self.config.miss_latency;
+        }
+        self.stats.cycles += latency;
+        (hit, latency)
+    }
+
+    /// Bring a line into `set_idx`, evicting a victim if needed. Returns the transfer latency.
+    fn fill(&mut self, set_idx: usize, tag: usize, dirty: bool) -> u64 {
+        let mut latency = self.config.miss_latency;
+        let way = match self.sets[set_idx].iter().position(|l| !l.valid) {
+            Some(w) => w,
+            None => {
+                self.stats.evictions += 1;
+                self.victim(set_idx)
+            }
+        };
+        let clock = self.clock;
+        let line = &mut self.sets[set_idx][way];
+        if line.valid && line.dirty {
+            self.stats.writebacks += 1;
+            latency += self.config.miss_latency;
+        }
+        *line = Line { valid: true, dirty, tag, stamp: clock };
+        latency
+    }
+
+    fn victim(&mut self, set_idx: usize) -> usize {
+        match self.config.replacement {
+            Replacement::Lru | Replacement::Fifo => {
+                let set = &self.sets[set_idx];
+                (0..set.len()).min_by_key(|&w| set[w].stamp).unwrap_or(0)
+            }
+            Replacement::Random { .. } => {
+                // xorshift64
+                self.rng ^= self.rng << 13;
+                self.rng ^= self.rng >> 7;
+                self.rng ^= self.rng << 17;
+                (self.rng % self.config.associativity as u64) as usize
+            }
+        }
+    }
+
+    /// One-line statistics summary, e.g. for the REPL.
+    pub fn report(&self, name: &str) -> String {
+        let s = self.stats;
+        let c = self.config;
+        format!(
+            "{}: {}B {}B lines {}-way {:?} {:?} | reads={} (miss {}) writes={} (miss {}) hit rate={:.1}% evictions={} writebacks={} cycles={}",
+            name,
+            c.size,
+            c.line_size,
+            c.associativity,
+            c.replacement,
+            c.write_policy,
+            s.reads,
+            s.read_misses,
+            s.writes,
+            s.write_misses,
+            100.0 * s.hit_rate(),
+            s.evictions,
+            s.writebacks,
+            s.cycles
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(size: usize, assoc: usize, replacement: Replacement, write_policy: WritePolicy) -> Cache {
+        Cache::new(CacheConfig {
+            size,
+            line_size: 4,
+            associativity: assoc,
+            replacement,
+            write_policy,
+            hit_latency: 1,
+            miss_latency: 10,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn associativity_and_replacement() {
+        // 0x00 and 0x10 conflict in a 16-byte direct-mapped cache
+        let mut dm = cache(16, 1, Replacement::Lru, WritePolicy::WriteBack);
+        for _ in 0..3 {
+            dm.access(0x00, false);
+            dm.access(0x10, false);
+        }
+        assert_eq!(dm.stats().read_misses, 6);
+
+        let mut two_way = cache(16, 2, Replacement::Lru, WritePolicy::WriteBack);
+        for _ in 0..3 {
+            two_way.access(0x00, false);
+            two_way.access(0x10, false);
+        }
+        assert_eq!(two_way.stats().read_misses, 2);
+        assert_eq!(two_way.access(0x01, false), 1);
+
+        // A B A C: LRU evicts B, FIFO evicts A
+        let mut lru = cache(8, 2, Replacement::Lru, WritePolicy::WriteBack);
+        let mut fifo = cache(8, 2, Replacement::Fifo, WritePolicy::WriteBack);
+        for c in [&mut lru, &mut fifo] {
+            for a in [0x00, 0x10, 0x00, 0x20, 0x00] {
+                c.access(a, false);
+            }
+        }
+        assert_eq!(lru.stats().read_misses, 3);
+        assert_eq!(fifo.stats().read_misses, 4);
+
+        let mut r1 = cache(8, 2, Replacement::Random { seed: 7 }, WritePolicy::WriteBack);
+        let mut r2 = cache(8, 2, Replacement::Random { seed: 7 }, WritePolicy::WriteBack);
+        for a in (0..64).map(|i| (i * 37) % 256) {
+            assert_eq!(r1.access(a, false), r2.access(a, false));
+        }
+    }
+
+    #[test]
+    fn write_policies() {
+        let mut wb = cache(8, 1, Replacement::Lru, WritePolicy::WriteBack);
+        assert_eq!(wb.access(0x00, true), 11); // allocate on write miss
+        assert_eq!(wb.access(0x00, true), 1);
+        assert_eq!(wb.access(0x08, false), 21); // conflict: fill + dirty write-back
+        assert_eq!(wb.stats().writebacks, 1);
+
+        let mut wt = cache(8, 1, Replacement::Lru, WritePolicy::WriteThrough);
+        assert_eq!(wt.access(0x00, true), 11); // no allocate, write to memory
+        assert_eq!(wt.access(0x00, false), 11);
+        assert_eq!(wt.access(0x00, true), 11); // hit, still written through
+        assert_eq!(wt.stats().writebacks, 0);
+
+        assert!(Cache::new(CacheConfig { size: 48, ..CacheConfig::default() }).is_err());
+    }
+}
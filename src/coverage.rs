@@ -0,0 +1,186 @@
+// src/coverage.rs
+use crate::assembler::Program;
+use crate::isa::Instruction;
+use crate::trace::{TraceRecord, Tracer};
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+/// Outcome counts for one conditional branch site.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BranchCounts {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+/// Code coverage collector. Attach it as a `Tracer`; it records how often each
+/// instruction address executed and, for conditional branches (`JZ`), how often
+/// the branch was taken or fell through. Reports map addresses back to source
+/// lines through `Program::line_info`.
+#[derive(Debug, Default)]
+pub struct Coverage {
+    hits: BTreeMap<usize, u64>,
+    branches: BTreeMap<usize, BranchCounts>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Coverage::default()
+    }
+
+    /// Number of times the instruction at `addr` executed.
+    pub fn hits(&self, addr: usize) -> u64 {
+        self.hits.get(&addr).copied().unwrap_or(0)
+    }
+
+    pub fn branch(&self, addr: usize) -> Option<BranchCounts> {
+        self.branches.get(&addr).copied()
+    }
+
+    /// Addresses that executed at least once, in ascending order.
+    pub fn executed_addresses(&self) -> Vec<usize> {
+        self.hits.keys().copied().collect()
+    }
+
+    /// (covered, total) instruction lines of `prog`.
+    pub fn line_totals(&self, prog: &Program) -> (usize, usize) {
+        let covered = prog.line_info.keys().filter(|a| self.hits(**a) > 0).count();
+        (covered, prog.line_info.len())
+    }
+
+    /// (covered, total) branch outcomes of `prog`. Every conditional branch has two outcomes.
+    pub fn branch_totals(&self, prog: &Program) -> (usize, usize) {
+        let mut covered = 0;
+        let mut total = 0;
+        for addr in branch_sites(prog) {
+            total += 2;
+            if let Some(b) = self.branch(addr) {
+                covered += (b.taken > 0) as usize + (b.not_taken > 0) as usize;
+            }
+        }
+        (covered, total)
+    }
+
+    /// Text summary listing totals and every line that never executed.
+    pub fn summary(&self, prog: &Program) -> String {
+        let pct = |c: usize, t: usize| if t == 0 { 100.0 } else { 100.0 * c as f64 / t as f64 };
+        let (lc, lt) = self.line_totals(prog);
+        let (bc, bt) = self.branch_totals(prog);
+        let mut s = format!("Lines:    {}/{} ({:.1}%)\n", lc, lt, pct(lc, lt));
+        s.push_str(&format!("Branches: {}/{} ({:.1}%)\n", bc, bt, pct(bc, bt)));
+        for (addr, src) in &prog.line_info {
+            if self.hits(*addr) == 0 {
+                s.push_str(&format!("  not executed: line {} ({:02X}: {})\n", src.line, addr, src.instr));
+            } else if let Some(b) = self.branch(*addr) {
+                if b.taken == 0 || b.not_taken == 0 {
+                    let missing = if b.taken == 0 { "taken" } else { "not taken" };
+                    s.push_str(&format!("  branch never {}: line {} ({:02X}: {})\n", missing, src.line, addr, src.instr));
+                }
+            }
+        }
+        s
+    }
+
+    /// Write an LCOV tracefile for `prog`, naming the assembler source `source_path`.
+    pub fn write_lcov<W: Write>(&self, prog: &Program, test_name: &str, source_path: &str, mut out: W) -> io::Result<()> {
+        writeln!(out, "TN:{}", test_name)?;
+        writeln!(out, "SF:{}", source_path)?;
+        for (addr, src) in &prog.line_info {
+            if let Instruction::Jz { .. } = src.instr {
+                let fmt = |n: u64| if self.hits(*addr) == 0 { "-".to_string() } else { n.to_string() };
+                let b = self.branch(*addr).unwrap_or_default();
+                writeln!(out, "BRDA:{},0,0,{}", src.line, fmt(b.taken))?;
+                writeln!(out, "BRDA:{},0,1,{}", src.line, fmt(b.not_taken))?;
+            }
+        }
+        let (bc, bt) = self.branch_totals(prog);
+        writeln!(out, "BRF:{}", bt)?;
+        writeln!(out, "BRH:{}", bc)?;
+        for (addr, src) in &prog.line_info {
+            writeln!(out, "DA:{},{}", src.line, self.hits(*addr))?;
+        }
+        let (lc, lt) = self.line_totals(prog);
+        writeln!(out, "LF:{}", lt)?;
+        writeln!(out, "LH:{}", lc)?;
+        writeln!(out, "end_of_record")
+    }
+}
+
+fn branch_sites(prog: &Program) -> impl Iterator<Item = usize> + '_ {
+    prog.line_info
+        .iter()
+        .filter(|(_, src)| matches!(src.instr, Instruction::Jz { .. }))
+        .map(|(addr, _)| *addr)
+}
+
+impl Tracer for Coverage {
+    fn record(&mut self, rec: &TraceRecord) {
+        *self.hits.entry(rec.pc).or_insert(0) += 1;
+        if let Instruction::Jz { .. } = rec.instr {
+            let b = self.branches.entry(rec.pc).or_default();
+            if rec.taken {
+                b.taken += 1;
+            } else {
+                b.not_taken += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble_program;
+    use crate::cpu::CPU;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn lines_and_branches_are_mapped_to_source() {
+        let src = "LDI R0, 1\nJZ R0, skip\nLDI R1, 2\nskip:\nHLT\nOUT R1\n";
+        let prog = assemble_program(src).expect("assemble failed");
+        let cov = Rc::new(RefCell::new(Coverage::new()));
+        let mut cpu = CPU::new();
+        cpu.attach_tracer(Box::new(cov.clone()));
+        cpu.load(&prog.bytes, 0);
+        cpu.run();
+
+        let cov = cov.borrow();
+        assert_eq!(cov.branch(0x02), Some(BranchCounts { taken: 0, not_taken: 1 }));
+        assert_eq!(cov.line_totals(&prog), (4, 5));
+        assert_eq!(cov.branch_totals(&prog), (1, 2));
+        assert!(cov.summary(&prog).contains("not executed: line 6 (07: OUT R1)"));
+
+        let mut lcov = Vec::new();
+        cov.write_lcov(&prog, "t", "prog.asm", &mut lcov).unwrap();
+        let lcov = String::from_utf8(lcov).unwrap();
+        assert!(lcov.contains("BRDA:2,0,0,0\nBRDA:2,0,1,1\n"));
+        assert!(lcov.contains("DA:6,0\nLF:5\nLH:4\nend_of_record"));
+
+        // a JZ to the next instruction continues there either way, but still has an outcome
+        let prog = assemble_program("LDI R0, 1\nJZ R0, next\nnext:\nJZ R1, done\ndone:\nHLT").unwrap();
+        let cov = Rc::new(RefCell::new(Coverage::new()));
+        let mut cpu = CPU::new();
+        cpu.attach_tracer(Box::new(cov.clone()));
+        cpu.load(&prog.bytes, 0);
+        cpu.run();
+        let cov = cov.borrow();
+        assert_eq!(cov.branch(0x02), Some(BranchCounts { taken: 0, not_taken: 1 }));
+        assert_eq!(cov.branch(0x04), Some(BranchCounts { taken: 1, not_taken: 0 }));
+    }
+
+    #[test]
+    fn branches_taken_past_the_end_of_small_memory() {
+        use crate::memory::Memory;
+
+        // in 64 bytes of memory, JZ to 0x46 lands on the HLT at 0x06
+        let prog = assemble_program("LDI R0, 1\nJZ R1, 0x46\nLDI R0, 2\nHLT").unwrap();
+        let cov = Rc::new(RefCell::new(Coverage::new()));
+        let mut cpu = CPU::new();
+        cpu.mem = Memory::with_size(64);
+        cpu.attach_tracer(Box::new(cov.clone()));
+        cpu.load(&prog.bytes, 0);
+        cpu.run();
+        assert_eq!(cpu.regs[0], 1);
+        assert_eq!(cov.borrow().branch(0x02), Some(BranchCounts { taken: 1, not_taken: 0 }));
+    }
+}
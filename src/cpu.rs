@@ -1,6 +1,148 @@
 // src/cpu.rs
-use crate::device::Device;
-use crate::memory::Memory;
+use crate::blocks::BlockCache;
+use crate::branch::BranchUnit;
+use crate::cache::Cache;
+use crate::device::{CpuRequest, Device, DeviceContext, DeviceId};
+use crate::isa::{self, Instruction, MicroStage};
+use crate::jit::Jit;
+use crate::memory::{AccessKind, MemAccess, Memory};
+use crate::trace::{RegDelta, TextTracer, TraceRecord, Tracer};
+use std::collections::BTreeSet;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+
+/// How `step_and_tick_instruction` (and therefore `run` / `step_n_instructions`) executes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExecMode {
+    /// Execute a whole instruction, then tick devices for each cycle it took (fast).
+    #[default]
+    Instruction,
+    /// Execute one micro-step per cycle (see `CPU::tick`), so bus accesses and
+    /// state changes happen in the cycle they belong to.
+    MicroCycle,
+}
+
+/// When devices are ticked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Scheduling {
+    /// Tick every device on every cycle.
+    PerCycle,
+    /// Tick a device only at the cycle it asks for (`Device::next_event`), at a wakeup,
+    /// or to catch up before a register access. Same results as `PerCycle`, faster.
+    #[default]
+    EventDriven,
+}
+
+/// Why `run`, `run_for_cycles`, `run_for_instructions`, `run_until` or
+/// `step_n_instructions` returned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopReason {
+    /// The CPU executed `HLT` (or was already halted).
+    Halted,
+    /// The cycle or instruction budget ran out.
+    BudgetExhausted,
+    /// PC reached one of `CPU::breakpoints`, or the `run_until` predicate held. Holds
+    /// the PC of the instruction about to execute.
+    Breakpoint(usize),
+    /// A device stopped the CPU with `CpuRequest::Fault` (see `CPU::fault`).
+    Fault(String),
+    /// A device halted or reset the CPU (`CpuRequest::Halt`, `CpuRequest::Reset`).
+    DeviceRequest(CpuRequest),
+}
+
+impl Display for StopReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            StopReason::Halted => write!(f, "halted"),
+            StopReason::BudgetExhausted => write!(f, "budget exhausted"),
+            StopReason::Breakpoint(pc) => write!(f, "breakpoint at {:02X}", pc),
+            StopReason::Fault(reason) => write!(f, "fault: {}", reason),
+            StopReason::DeviceRequest(request) => write!(f, "device request: {:?}", request),
+        }
+    }
+}
+
+/// Cycles added by the memory timing model.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BusStats {
+    /// Region wait states (see `memory::Region`) charged to CPU accesses.
+    pub wait_state_cycles: u64,
+    /// Cycles the CPU waited because a device owned the bus.
+    pub contention_cycles: u64,
+    /// Cycles a device (e.g. DMA) mastered the bus.
+    pub device_cycles: u64,
+}
+
+/// Default interrupt vector (see `CPU::irq_vector`).
+pub const IRQ_VECTOR: usize = 0xD0;
+/// Default non-maskable interrupt vector (see `CPU::nmi_vector`). There is room for
+/// one `JMP` to the handler just below the IRQ vector.
+pub const NMI_VECTOR: usize = 0xCE;
+
+/// An attached device, the base address of its register window (if mapped) and the
+/// state it controls through its `DeviceContext`.
+#[derive(Debug)]
+struct Slot {
+    id: DeviceId,
+    dev: Box<dyn Device>,
+    base: Option<usize>,
+    /// Whether the device's interrupt line reaches the CPU.
+    connected: bool,
+    irq: bool,
+    wakeup: Option<u64>,
+    /// Cycle the device was last ticked, and the next one it asked for.
+    last_tick: u64,
+    next_event: Option<u64>,
+    /// `Device::watches_fetches`.
+    watches_fetches: bool,
+}
+
+impl Slot {
+    /// Next cycle the device must be ticked under `Scheduling::EventDriven`.
+    fn due(&self) -> Option<u64> {
+        match (self.next_event, self.wakeup) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+}
+
+/// An attached device as listed by `CPU::device_info`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub id: DeviceId,
+    /// Register window base, if mapped.
+    pub base: Option<usize>,
+    pub mmio_size: usize,
+    /// Interrupt line connected and currently raised.
+    pub irq_connected: bool,
+    pub irq_pending: bool,
+    pub description: String,
+}
+
+/// Raw bytes and decoded form of the instruction most recently fetched.
+#[derive(Debug, Clone, Copy)]
+struct Fetched {
+    opcode: u8,
+    operand: Option<u8>,
+    instr: Instruction,
+}
+
+/// An instruction in flight in micro-cycle mode.
+#[derive(Debug, Clone, Copy)]
+struct MicroOp {
+    stage: usize,
+    /// Wait cycles still owed for memory latency before the next stage can run.
+    stall: u64,
+    fetched: Fetched,
+    /// Value latched by the Memory stage of a LOAD.
+    data: u8,
+    // architectural state when the instruction started, for tracing
+    pc: usize,
+    regs: [u8; 4],
+    z: bool,
+    cycle: u64,
+}
 
 #[derive(Debug)]
 pub struct CPU {
@@ -10,7 +152,58 @@ pub struct CPU {
     pub mem: Memory,
     pub cycles: u64,
     pub halted: bool,
-    devices: Vec<Box<dyn Device>>,
+    pub mode: ExecMode,
+    pub scheduling: Scheduling,
+    /// Predecoded basic blocks for `step_instruction`; `None` decodes every instruction
+    /// from memory as it is fetched.
+    pub block_cache: Option<BlockCache>,
+    /// Translates hot blocks of `block_cache` to host code for `run` (see `jit::Jit`).
+    pub jit: Option<Jit>,
+    /// Optional instruction cache; fetch latency is added to the cycle count.
+    pub icache: Option<Cache>,
+    /// Optional data cache; LOAD/STORE latency is added to the cycle count.
+    pub dcache: Option<Cache>,
+    /// Optional branch predictor; mispredicted JMP/JZ cost its penalty in extra cycles.
+    pub branch_unit: Option<BranchUnit>,
+    pub bus_stats: BusStats,
+    /// Set by `EI`, cleared by `DI` and on interrupt entry.
+    pub interrupts_enabled: bool,
+    /// Address the CPU jumps to when it takes an interrupt.
+    pub irq_vector: usize,
+    /// Address the CPU jumps to on a non-maskable interrupt (`CpuRequest::Nmi`).
+    pub nmi_vector: usize,
+    /// Why the CPU stopped, when a device raised `CpuRequest::Fault`. The CPU is halted.
+    pub fault: Option<String>,
+    /// Number of resets requested by devices.
+    pub resets: u64,
+    /// Runs stop before executing the instruction at any of these addresses, except
+    /// where they start (`StopReason::Breakpoint`).
+    pub breakpoints: BTreeSet<usize>,
+    devices: Vec<Slot>,
+    next_device_id: u32,
+    /// Bumped whenever devices (and so register windows) are attached, detached or replaced.
+    device_epoch: u64,
+    /// Device request waiting for the next instruction boundary.
+    request: Option<CpuRequest>,
+    /// Set when a carried out request ends the current run.
+    stop: Option<StopReason>,
+    /// PC and Z saved on interrupt entry, restored by `RETI`.
+    irq_return: Option<(usize, bool)>,
+    /// Cycles of the current instruction in which a device owned the bus.
+    stolen: u64,
+    tracers: Vec<Box<dyn Tracer>>,
+    last_fetch: Option<Fetched>,
+    micro: Option<MicroOp>,
+    accesses: Vec<MemAccess>,
+    /// Fetches of the current instruction left out of `accesses` (see `step_instruction`).
+    unlogged_fetches: u64,
+    bus_reported: usize,
+}
+
+impl Default for CPU {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl CPU {
@@ -22,26 +215,298 @@ impl CPU {
             mem: Memory::new(),
             cycles: 0,
             halted: false,
+            mode: ExecMode::Instruction,
+            scheduling: Scheduling::default(),
+            block_cache: Some(BlockCache::new()),
+            jit: None,
+            icache: None,
+            dcache: None,
+            branch_unit: None,
+            bus_stats: BusStats::default(),
+            interrupts_enabled: false,
+            irq_vector: IRQ_VECTOR,
+            nmi_vector: NMI_VECTOR,
+            fault: None,
+            resets: 0,
+            breakpoints: BTreeSet::new(),
             devices: Vec::new(),
+            next_device_id: 0,
+            device_epoch: 0,
+            request: None,
+            stop: None,
+            irq_return: None,
+            stolen: 0,
+            tracers: Vec::new(),
+            last_fetch: None,
+            micro: None,
+            accesses: Vec::new(),
+            unlogged_fetches: 0,
+            bus_reported: 0,
+        }
+    }
+
+    pub fn attach_device(&mut self, dev: Box<dyn Device>) -> DeviceId {
+        self.push_device(dev, None)
+    }
+
+    /// Attach a device whose registers (`Device::mmio_size` bytes) appear at `base`.
+    /// LOAD/STORE in that window go to the device instead of memory.
+    pub fn attach_mapped_device(&mut self, dev: Box<dyn Device>, base: usize) -> Result<DeviceId, String> {
+        self.check_window(base, dev.mmio_size(), None)?;
+        Ok(self.push_device(dev, Some(base)))
+    }
+
+    fn push_device(&mut self, dev: Box<dyn Device>, base: Option<usize>) -> DeviceId {
+        let id = DeviceId(self.next_device_id);
+        self.next_device_id += 1;
+        self.device_epoch += 1;
+        let (last_tick, next_event) = (self.cycles, Some(self.cycles + 1));
+        let watches_fetches = dev.watches_fetches();
+        self.devices.push(Slot { id, dev, base, connected: true, irq: false, wakeup: None, last_tick, next_event, watches_fetches });
+        id
+    }
+
+    /// Check that `size` registers fit at `base` without overlapping another device
+    /// (other than `except`).
+    fn check_window(&self, base: usize, size: usize, except: Option<DeviceId>) -> Result<(), String> {
+        if size == 0 || base.checked_add(size).is_none_or(|end| end > self.mem.size()) {
+            return Err(format!("cannot map {} register(s) at 0x{:02X}", size, base));
+        }
+        for slot in self.devices.iter().filter(|s| Some(s.id) != except) {
+            if let Some(b) = slot.base {
+                if base < b + slot.dev.mmio_size() && b < base + size {
+                    return Err(format!("register window at 0x{:02X} overlaps a device at 0x{:02X}", base, b));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn slot(&self, id: DeviceId) -> Option<&Slot> {
+        self.devices.iter().find(|s| s.id == id)
+    }
+
+    fn slot_mut(&mut self, id: DeviceId) -> Option<&mut Slot> {
+        self.devices.iter_mut().find(|s| s.id == id)
+    }
+
+    /// The device `id` if it is a `T`. Under `Scheduling::EventDriven` its state may lag
+    /// behind the current cycle; see `sync_devices`.
+    pub fn device<T: Device + 'static>(&self, id: DeviceId) -> Option<&T> {
+        self.slot(id)?.dev.as_ref().as_any().downcast_ref()
+    }
+
+    pub fn device_mut<T: Device + 'static>(&mut self, id: DeviceId) -> Option<&mut T> {
+        let i = self.devices.iter().position(|s| s.id == id)?;
+        self.catch_up(i);
+        self.devices[i].next_event = Some(self.cycles + 1);
+        self.slot_mut(id)?.dev.as_mut().as_any_mut().downcast_mut()
+    }
+
+    /// Remove a device and hand it back.
+    pub fn detach_device(&mut self, id: DeviceId) -> Option<Box<dyn Device>> {
+        let i = self.devices.iter().position(|s| s.id == id)?;
+        self.device_epoch += 1;
+        Some(self.devices.remove(i).dev)
+    }
+
+    /// Swap in `dev` at the same ID and register window; returns the old device.
+    pub fn replace_device(&mut self, id: DeviceId, dev: Box<dyn Device>) -> Result<Box<dyn Device>, String> {
+        let base = self.slot(id).ok_or_else(|| format!("no device {}", id))?.base;
+        if let Some(base) = base {
+            self.check_window(base, dev.mmio_size(), Some(id))?;
         }
+        let cycle = self.cycles;
+        self.device_epoch += 1;
+        let slot = self.slot_mut(id).expect("checked above");
+        slot.irq = false;
+        slot.wakeup = None;
+        slot.last_tick = cycle;
+        slot.next_event = Some(cycle + 1);
+        slot.watches_fetches = dev.watches_fetches();
+        Ok(std::mem::replace(&mut slot.dev, dev))
+    }
+
+    /// Connect or disconnect a device's interrupt line from the CPU.
+    pub fn set_irq_connected(&mut self, id: DeviceId, connected: bool) -> Result<(), String> {
+        self.slot_mut(id).map(|s| s.connected = connected).ok_or_else(|| format!("no device {}", id))
+    }
+
+    pub fn device_info(&self) -> Vec<DeviceInfo> {
+        self.devices
+            .iter()
+            .map(|s| DeviceInfo {
+                id: s.id,
+                base: s.base,
+                mmio_size: s.dev.mmio_size(),
+                irq_connected: s.connected,
+                irq_pending: s.irq || s.dev.irq_pending(),
+                description: s.dev.describe(),
+            })
+            .collect()
+    }
+
+    /// Index of the mapped device and register offset for `addr`, if any.
+    fn mmio_target(&self, addr: usize) -> Option<(usize, usize)> {
+        self.devices.iter().enumerate().find_map(|(i, slot)| {
+            let base = slot.base?;
+            (addr >= base && addr < base + slot.dev.mmio_size()).then(|| (i, addr - base))
+        })
     }
 
-    pub fn attach_device(&mut self, dev: Box<dyn Device>) {
-        self.devices.push(dev);
+    /// Attach a trace sink. Every executed instruction is reported to all attached sinks.
+    pub fn attach_tracer(&mut self, tracer: Box<dyn Tracer>) {
+        self.tracers.push(tracer);
+    }
+
+    /// Detach all trace sinks (calling `finish` on each) and hand them back.
+    pub fn take_tracers(&mut self) -> Vec<Box<dyn Tracer>> {
+        let mut tracers = std::mem::take(&mut self.tracers);
+        for t in tracers.iter_mut() {
+            t.finish();
+        }
+        tracers
     }
 
     pub fn load(&mut self, program: &[u8], addr: usize) {
-        let end = addr + program.len();
         self.mem.write_bytes(addr, program);
         self.pc = addr;
     }
 
     fn fetch(&mut self) -> u8 {
-        let b = self.mem.read(self.pc);
+        let addr = self.pc;
+        let b = self.mem.read(addr);
+        self.accesses.push(MemAccess { kind: AccessKind::Fetch, addr, value: b });
         self.pc = (self.pc + 1) % self.mem.size();
         b
     }
 
+    fn read_data(&mut self, addr: usize) -> u8 {
+        let value = match self.mmio_target(addr) {
+            Some((i, offset)) => {
+                self.catch_up(i);
+                let value = self.devices[i].dev.mmio_read(offset);
+                self.reschedule(i);
+                value
+            }
+            None => self.mem.read(addr),
+        };
+        self.accesses.push(MemAccess { kind: AccessKind::Read, addr, value });
+        value
+    }
+
+    fn write_data(&mut self, addr: usize, value: u8) {
+        if let Some((i, offset)) = self.mmio_target(addr) {
+            self.catch_up(i);
+            self.devices[i].dev.mmio_write(offset, value);
+            self.reschedule(i);
+        } else if !self.mem.is_read_only(addr) {
+            self.mem.write(addr, value);
+        }
+        self.accesses.push(MemAccess { kind: AccessKind::Write, addr, value });
+    }
+
+    /// Extra cycles for the bus accesses in `self.accesses[from..]` on top of the
+    /// instruction's base cost: cache hit/miss latency, plus the region's wait states
+    /// for every access that did not hit in a cache. Device registers are never cached.
+    fn access_latency(&mut self, from: usize) -> u64 {
+        if self.icache.is_none() && self.dcache.is_none() && self.mem.regions().is_empty() {
+            return 0;
+        }
+        let mut extra = 0;
+        for i in from..self.accesses.len() {
+            let a = self.accesses[i];
+            let cache = match a.kind {
+                AccessKind::Fetch => self.icache.as_mut(),
+                _ if self.dcache.is_none() || self.mmio_target(a.addr).is_some() => None,
+                AccessKind::Read | AccessKind::Write => self.dcache.as_mut(),
+            };
+            let (hit, latency) = match cache {
+                Some(c) => c.access_detail(a.addr, a.kind == AccessKind::Write),
+                None => (false, 0),
+            };
+            extra += latency;
+            if !hit {
+                let wait = self.mem.wait_states(a.addr, a.kind);
+                self.bus_stats.wait_state_cycles += wait;
+                extra += wait;
+            }
+        }
+        extra
+    }
+
+    /// Misprediction penalty for the branch `instr` at `pc`, which has just executed.
+    fn branch_penalty(&mut self, pc: usize, instr: Instruction) -> u64 {
+        match self.branch_unit.as_mut() {
+            Some(unit) => unit.resolve(pc, instr, self.pc, self.mem.size()),
+            None => 0,
+        }
+    }
+
+    fn begin_instruction(&mut self) {
+        self.accesses.clear();
+        self.unlogged_fetches = 0;
+        self.bus_reported = 0;
+        self.stolen = 0;
+    }
+
+    /// Warm reset: registers, flags and PC back to 0 with interrupts disabled. Memory,
+    /// devices and the cycle count are kept.
+    pub fn reset(&mut self) {
+        self.regs = [0; 4];
+        self.pc = 0;
+        self.z = false;
+        self.halted = false;
+        self.interrupts_enabled = false;
+        self.irq_return = None;
+        self.micro = None;
+    }
+
+    /// Carry out a pending device request, then take a pending device interrupt if
+    /// interrupts are enabled: save PC and Z, disable further interrupts and continue
+    /// at `irq_vector`. Called between instructions; entry itself costs no cycles.
+    /// Returns true if execution was redirected to a vector.
+    pub(crate) fn poll_interrupt(&mut self) -> bool {
+        match self.request.take() {
+            Some(CpuRequest::Reset) => {
+                self.reset();
+                self.resets += 1;
+                self.stop = Some(StopReason::DeviceRequest(CpuRequest::Reset));
+            }
+            // single level: an NMI inside a handler replaces its return address
+            Some(CpuRequest::Nmi) => {
+                self.enter_handler(self.nmi_vector);
+                return true;
+            }
+            Some(CpuRequest::Halt) => {
+                self.halted = true;
+                self.stop = Some(StopReason::DeviceRequest(CpuRequest::Halt));
+            }
+            Some(CpuRequest::Fault(reason)) => {
+                self.stop = Some(StopReason::Fault(reason.clone()));
+                self.fault = Some(reason);
+                self.halted = true;
+            }
+            None => {}
+        }
+        if !self.interrupts_enabled || self.halted || !self.irq_asserted() {
+            return false;
+        }
+        self.enter_handler(self.irq_vector);
+        true
+    }
+
+    /// Whether a connected device is raising its interrupt line.
+    fn irq_asserted(&self) -> bool {
+        self.devices.iter().any(|s| s.connected && (s.irq || s.dev.irq_pending()))
+    }
+
+    fn enter_handler(&mut self, vector: usize) {
+        self.irq_return = Some((self.pc, self.z));
+        self.interrupts_enabled = false;
+        self.pc = vector % self.mem.size();
+    }
+
     /// Execute a single instruction (decode + execute) and return the
     /// number of cycles the instruction requires.
     /// This does NOT advance the device ticks or the CPU's cycle counter.
@@ -49,154 +514,510 @@ impl CPU {
         if self.halted {
             return 0;
         }
+        self.poll_interrupt();
+        if self.halted {
+            return 0;
+        }
+        self.begin_instruction();
 
-        let opcode = self.fetch();
+        let pc = self.pc;
+        // predecoded fetches cost their precomputed wait states unless an I-cache models them
+        let mut fetch_wait = None;
+        let (opcode, operand, instr) = match self.block_cache.as_mut() {
+            Some(blocks) => {
+                let d = blocks.fetch(&mut self.mem, pc);
+                // fetch records only feed the I-cache and fetch watchers; otherwise count them
+                if self.icache.is_some() || self.devices.iter().any(|s| s.watches_fetches) {
+                    self.accesses.push(MemAccess { kind: AccessKind::Fetch, addr: pc, value: d.opcode });
+                    if let Some(b) = d.operand {
+                        self.accesses.push(MemAccess { kind: AccessKind::Fetch, addr: d.operand_addr, value: b });
+                    }
+                } else {
+                    self.unlogged_fetches = 1 + d.operand.is_some() as u64;
+                }
+                self.pc = d.next;
+                if self.icache.is_none() {
+                    fetch_wait = Some((self.accesses.len(), d.fetch_wait));
+                }
+                (d.opcode, d.operand, d.instr)
+            }
+            None => {
+                let opcode = self.fetch();
+                let operand = if isa::has_operand(opcode) { Some(self.fetch()) } else { None };
+                (opcode, operand, isa::decode(opcode, operand.unwrap_or(0)))
+            }
+        };
+        self.last_fetch = Some(Fetched { opcode, operand, instr });
+        self.execute(instr);
+        let latency = match fetch_wait {
+            Some((fetches, wait)) => {
+                self.bus_stats.wait_state_cycles += wait;
+                wait + self.access_latency(fetches)
+            }
+            None => self.access_latency(0),
+        };
+        let mut cycles = instr.cycles() + latency;
+        if instr.is_branch() {
+            cycles += self.branch_penalty(pc, instr);
+        }
+        cycles
+    }
 
-        match opcode {
-            // LDI reg, imm  => 0x10 | reg  imm   (2 cycles)
-            op if (op & 0xF0) == 0x10 => {
-                let reg = (op & 0x03) as usize;
-                let imm = self.fetch();
+    /// Apply the architectural effect of a decoded instruction.
+    fn execute(&mut self, instr: Instruction) {
+        match instr {
+            Instruction::Ldi { reg, imm } => {
                 self.regs[reg] = imm;
                 self.z = self.regs[reg] == 0;
-                2
             }
-
-            // ADD reg, reg => 0x20 | dest  src   (3 cycles)
-            op if (op & 0xF0) == 0x20 => {
-                let dest = (op & 0x03) as usize;
-                let src = (self.fetch() & 0x03) as usize;
+            Instruction::Add { dest, src } => {
                 let (res, _) = self.regs[dest].overflowing_add(self.regs[src]);
                 self.regs[dest] = res;
                 self.z = res == 0;
-                3
             }
-
-            // SUB reg, reg => 0x21 | dest src   (3 cycles)
-            op if (op & 0xF0) == 0x21 => {
-                let dest = (op & 0x03) as usize;
-                let src = (self.fetch() & 0x03) as usize;
+            Instruction::Sub { dest, src } => {
                 let (res, _) = self.regs[dest].overflowing_sub(self.regs[src]);
                 self.regs[dest] = res;
                 self.z = res == 0;
-                3
             }
-
-            // LOAD dest, addr => 0x30 | dest  addr  (4 cycles)
-            op if (op & 0xF0) == 0x30 => {
-                let dest = (op & 0x03) as usize;
-                let addr = self.fetch() as usize;
-                self.regs[dest] = self.mem.read(addr);
+            Instruction::Load { dest, addr } => {
+                self.regs[dest] = self.read_data(addr as usize);
                 self.z = self.regs[dest] == 0;
-                4
             }
-
-            // STORE src, addr => 0x31 | src addr  (4 cycles)
-            op if (op & 0xF0) == 0x31 => {
-                let src = (op & 0x03) as usize;
-                let addr = self.fetch() as usize;
-                self.mem.write(addr, self.regs[src]);
-                4
+            Instruction::Store { src, addr } => {
+                self.write_data(addr as usize, self.regs[src]);
+            }
+            Instruction::Jmp { addr } => {
+                self.pc = addr as usize % self.mem.size();
+            }
+            Instruction::Jz { reg, addr } => {
+                if self.regs[reg] == 0 {
+                    self.pc = addr as usize % self.mem.size();
+                }
+            }
+            // prints decimal + newline
+            Instruction::Out { reg } => {
+                println!("{}", self.regs[reg]);
+            }
+            Instruction::Ei => {
+                self.interrupts_enabled = true;
+            }
+            Instruction::Di => {
+                self.interrupts_enabled = false;
             }
+            // RETI outside a handler just enables interrupts
+            Instruction::Reti => {
+                if let Some((pc, z)) = self.irq_return.take() {
+                    self.pc = pc;
+                    self.z = z;
+                }
+                self.interrupts_enabled = true;
+            }
+            Instruction::Hlt => {
+                self.halted = true;
+            }
+            // NOP or unknown - treat as 1-cycle NOP
+            Instruction::Nop | Instruction::Unknown(_) => {}
+        }
+    }
 
-            // JMP addr => 0x40 addr  (3 cycles)
-            0x40 => {
-                let addr = self.fetch() as usize;
-                self.pc = addr % self.mem.size();
-                3
+    /// Advance exactly one cycle in micro-cycle fashion: perform the next micro-step
+    /// (see `Instruction::micro_stages`) of the current instruction, starting a new
+    /// instruction if none is in flight, then tick devices once. Memory latency
+    /// (e.g. a cache miss) holds the instruction in wait cycles after the stage
+    /// that caused it, and a branch misprediction does the same after Execute.
+    /// Returns true if an instruction completed in this cycle.
+    pub fn tick(&mut self) -> bool {
+        let mut op = match self.micro.take() {
+            Some(op) => op,
+            None => {
+                if self.halted {
+                    return false;
+                }
+                self.poll_interrupt();
+                if self.halted {
+                    return false;
+                }
+                self.begin_instruction();
+                MicroOp {
+                    stage: 0,
+                    stall: 0,
+                    fetched: Fetched { opcode: 0, operand: None, instr: Instruction::Nop },
+                    data: 0,
+                    pc: self.pc,
+                    regs: self.regs,
+                    z: self.z,
+                    cycle: self.cycles,
+                }
+            }
+        };
+        let mut bus_accesses = 0;
+        if op.stall > 0 {
+            op.stall -= 1;
+        } else {
+            let stage = if op.stage == 0 { MicroStage::Fetch } else { op.fetched.instr.micro_stages()[op.stage] };
+            let from = self.accesses.len();
+            self.micro_step(&mut op, stage);
+            op.stage += 1;
+            bus_accesses = (self.accesses.len() - from) as u64;
+            op.stall = self.access_latency(from);
+            if stage == MicroStage::Execute && op.fetched.instr.is_branch() {
+                op.stall += self.branch_penalty(op.pc, op.fetched.instr);
             }
+        }
+        if self.tick_devices(1) > 0 && bus_accesses > 0 {
+            // a device owned the bus this cycle: the stage's accesses are retried later
+            op.stall += bus_accesses;
+            self.bus_stats.contention_cycles += bus_accesses;
+        }
 
-            // JZ reg, addr => 0x41 | reg addr  (3 cycles)
-            op if (op & 0xF0) == 0x41 => {
-                let reg = (op & 0x03) as usize;
-                let addr = self.fetch() as usize;
-                if self.regs[reg] == 0 {
-                    self.pc = addr % self.mem.size();
+        if op.stall > 0 || op.stage < op.fetched.instr.micro_stages().len() {
+            self.micro = Some(op);
+            return false;
+        }
+        self.last_fetch = Some(op.fetched);
+        self.emit_trace(op.pc, op.regs, op.z, op.cycle, self.cycles - op.cycle);
+        true
+    }
+
+    fn micro_step(&mut self, op: &mut MicroOp, stage: MicroStage) {
+        let f = &mut op.fetched;
+        match stage {
+            MicroStage::Fetch => {
+                f.opcode = self.fetch();
+                f.instr = isa::decode(f.opcode, 0);
+                if f.instr.micro_stages().len() == 1 {
+                    self.execute(f.instr);
                 }
-                3
             }
+            MicroStage::Decode => {
+                if isa::has_operand(f.opcode) {
+                    f.operand = Some(self.fetch());
+                }
+                f.instr = isa::decode(f.opcode, f.operand.unwrap_or(0));
+                if let Instruction::Ldi { .. } = f.instr {
+                    self.execute(f.instr);
+                }
+            }
+            MicroStage::Execute => match f.instr {
+                Instruction::Add { .. }
+                | Instruction::Sub { .. }
+                | Instruction::Jmp { .. }
+                | Instruction::Jz { .. }
+                | Instruction::Reti => self.execute(f.instr),
+                _ => {}
+            },
+            MicroStage::Memory => match f.instr {
+                Instruction::Load { addr, .. } => op.data = self.read_data(addr as usize),
+                Instruction::Store { .. } => self.execute(f.instr),
+                _ => {}
+            },
+            MicroStage::Writeback => match f.instr {
+                Instruction::Load { dest, .. } => {
+                    self.regs[dest] = op.data;
+                    self.z = op.data == 0;
+                }
+                Instruction::Out { .. } => self.execute(f.instr),
+                _ => {}
+            },
+        }
+    }
 
-            // OUT reg => 0x50 | reg  (4 cycles) - prints decimal + newline
-            op if (op & 0xF0) == 0x50 => {
-                let reg = (op & 0x03) as usize;
-                println!("{}", self.regs[reg]);
-                4
+    /// Advance the cycle counter by `cycles`, ticking every device once per cycle, or
+    /// under `Scheduling::EventDriven` only on the cycles it is due.
+    /// Bus accesses not yet reported are delivered to devices on the first of these cycles.
+    /// Returns how many of the cycles a device owned the bus (`Device::bus_request`).
+    pub(crate) fn tick_devices(&mut self, cycles: u64) -> u64 {
+        let end = self.cycles + cycles;
+        let event_driven = self.scheduling == Scheduling::EventDriven;
+        let mut busy = 0;
+        while self.cycles < end {
+            if event_driven {
+                // skip straight past cycles on which no device is due
+                let due = self.devices.iter().filter_map(Slot::due).min().unwrap_or(u64::MAX);
+                if due > self.cycles + 1 {
+                    self.cycles = end.min(due - 1);
+                    self.bus_reported = self.accesses.len();
+                    continue;
+                }
             }
+            self.cycles += 1;
+            let pending = &self.accesses[self.bus_reported..];
+            let mut owned = false;
+            for slot in self.devices.iter_mut() {
+                if event_driven && slot.due().is_none_or(|at| at > self.cycles) {
+                    continue;
+                }
+                for a in pending {
+                    slot.dev.bus_activity(self.cycles, a);
+                }
+                if slot.dev.bus_request(self.cycles) && !owned {
+                    slot.dev.bus_grant(self.cycles, &mut self.mem);
+                    owned = true;
+                }
+                let mut ctx = DeviceContext::new(self.cycles, &mut self.mem, &mut slot.irq, &mut self.request, &mut slot.wakeup);
+                if ctx.next_wakeup().is_some_and(|at| at <= self.cycles) {
+                    ctx.cancel_wakeup();
+                    slot.dev.wakeup(&mut ctx);
+                }
+                slot.dev.tick_with(&mut ctx);
+                slot.last_tick = self.cycles;
+                slot.next_event = if event_driven { slot.dev.next_event(self.cycles) } else { Some(self.cycles + 1) };
+            }
+            self.bus_reported = self.accesses.len();
+            busy += owned as u64;
+        }
+        self.stolen += busy;
+        self.bus_stats.device_cycles += busy;
+        busy
+    }
 
-            // HLT => 0xFF  (1 cycle)
-            0xFF => {
-                self.halted = true;
-                1
+    /// Tick device `i` at the current cycle if it skipped cycles, so that it catches up
+    /// before its registers are accessed.
+    fn catch_up(&mut self, i: usize) {
+        let slot = &mut self.devices[i];
+        if slot.last_tick >= self.cycles {
+            return;
+        }
+        let mut ctx = DeviceContext::new(self.cycles, &mut self.mem, &mut slot.irq, &mut self.request, &mut slot.wakeup);
+        slot.dev.tick_with(&mut ctx);
+        slot.last_tick = self.cycles;
+    }
+
+    /// Ask device `i` for its next event after a register access changed its state.
+    fn reschedule(&mut self, i: usize) {
+        if self.scheduling == Scheduling::EventDriven {
+            let slot = &mut self.devices[i];
+            slot.next_event = slot.dev.next_event(self.cycles);
+        }
+    }
+
+    /// Bring every device up to the current cycle (see `Scheduling::EventDriven`), e.g.
+    /// before inspecting them with `device` or `device_info`.
+    pub fn sync_devices(&mut self) {
+        for i in 0..self.devices.len() {
+            self.catch_up(i);
+            self.reschedule(i);
+        }
+    }
+
+    /// Stall until `pending` CPU bus accesses have each found a cycle with a free bus.
+    /// Returns the cycles waited.
+    fn wait_for_bus(&mut self, mut pending: u64) -> u64 {
+        let mut waited = 0;
+        while pending > 0 {
+            if self.tick_devices(1) == 0 {
+                pending -= 1;
             }
+            waited += 1;
+        }
+        self.bus_stats.contention_cycles += waited;
+        waited
+    }
 
-            // NOP or unknown - treat as 1-cycle NOP
-            _ => 1,
+    /// Report the instruction described by `last_fetch` to all tracers.
+    fn emit_trace(&mut self, pc: usize, regs: [u8; 4], z: bool, cycle: u64, cycles: u64) {
+        if self.tracers.is_empty() {
+            return;
+        }
+        if let Some(f) = self.last_fetch {
+            let reg_deltas = (0..regs.len())
+                .filter(|&r| regs[r] != self.regs[r])
+                .map(|r| RegDelta { reg: r, before: regs[r], after: self.regs[r] })
+                .collect();
+            let rec = TraceRecord {
+                pc,
+                opcode: f.opcode,
+                operand: f.operand,
+                instr: f.instr,
+                next_pc: self.pc,
+                reg_deltas,
+                z_before: z,
+                z_after: self.z,
+                mem: self.accesses.iter().filter(|a| a.kind != AccessKind::Fetch).copied().collect(),
+                cycle,
+                cycles,
+                stolen: self.stolen,
+                taken: match f.instr {
+                    Instruction::Jmp { .. } => true,
+                    Instruction::Jz { reg, .. } => regs[reg] == 0,
+                    _ => false,
+                },
+            };
+            for t in self.tracers.iter_mut() {
+                t.record(&rec);
+            }
         }
     }
 
     /// Execute one instruction and perform device ticks for each consumed cycle.
     /// Returns the number of cycles consumed (0 if already halted).
     pub fn step_and_tick_instruction(&mut self) -> u64 {
+        if self.mode == ExecMode::MicroCycle || self.micro.is_some() {
+            let start = self.cycles;
+            while !self.halted || self.micro.is_some() {
+                if self.tick() {
+                    break;
+                }
+            }
+            return self.cycles - start;
+        }
         if self.halted {
             return 0;
         }
-        let cycles = self.step_instruction();
-        for _ in 0..cycles {
-            self.cycles += 1;
-            for dev in self.devices.iter_mut() {
-                dev.tick(self.cycles);
-            }
+
+        self.poll_interrupt();
+        if self.halted {
+            return 0;
         }
+        let (pc, regs, z, cycle) = (self.pc, self.regs, self.z, self.cycles);
+        let mut cycles = self.step_instruction();
+        let bus_accesses = self.accesses.len() as u64 + self.unlogged_fetches;
+        // without per-cycle timing, assume each device-owned cycle delays one CPU access
+        let busy = self.tick_devices(cycles);
+        cycles += self.wait_for_bus(busy.min(bus_accesses));
+        self.emit_trace(pc, regs, z, cycle, cycles);
         cycles
     }
 
-    /// Execute up to `n` instructions (or stop sooner if halted).
+    /// Execute up to `n` instructions, stopping sooner like `run_for_instructions`.
     /// Returns (executed_instructions, total_cycles_consumed).
     pub fn step_n_instructions(&mut self, n: usize) -> (usize, u64) {
-        let mut executed = 0usize;
-        let mut cycles_consumed = 0u64;
-        for _ in 0..n {
-            if self.halted {
-                break;
-            }
-            let c = self.step_and_tick_instruction();
-            executed += 1;
-            cycles_consumed += c;
-        }
-        (executed, cycles_consumed)
+        let start = self.cycles;
+        let (_, executed) = self.run_inner(u64::MAX, n as u64, None);
+        (executed as usize, self.cycles - start)
     }
 
-    /// Run until HLT (or until halted). Devices are ticked once per cycle.
-    pub fn run(&mut self) {
-        while !self.halted {
-            let cycles = self.step_instruction();
-            for _ in 0..cycles {
-                self.cycles += 1;
-                for dev in self.devices.iter_mut() {
-                    dev.tick(self.cycles);
-                }
+    /// Run until HLT, a fault, a device halt or a breakpoint; device resets do not end
+    /// the run. With a `jit`, translated blocks run natively where that is
+    /// indistinguishable.
+    pub fn run(&mut self) -> StopReason {
+        loop {
+            match self.run_inner(u64::MAX, u64::MAX, None).0 {
+                StopReason::DeviceRequest(CpuRequest::Reset) => {}
+                reason => return reason,
             }
         }
     }
 
-    /// Run with a simple trace: prints an instruction summary before each instruction.
-    pub fn run_with_trace(&mut self) {
-        while !self.halted {
-            let pc_before = self.pc;
-            let opcode = self.mem.read(pc_before);
-            // Print a short trace line
-            println!(
-                "[trace] PC={:02X} OPCODE={:02X} R=[{},{},{},{}] CYC={}",
-                pc_before, opcode, self.regs[0], self.regs[1], self.regs[2], self.regs[3], self.cycles
-            );
-            let cycles = self.step_instruction();
-            for _ in 0..cycles {
-                self.cycles += 1;
-                for dev in self.devices.iter_mut() {
-                    dev.tick(self.cycles);
+    /// Run for `n` cycles. In `ExecMode::Instruction` the instruction that uses up the
+    /// budget completes, so the run can end a few cycles later; in micro-cycle mode it
+    /// stops after exactly `n`, possibly with an instruction in flight.
+    pub fn run_for_cycles(&mut self, n: u64) -> StopReason {
+        self.run_inner(n, u64::MAX, None).0
+    }
+
+    pub fn run_for_instructions(&mut self, n: u64) -> StopReason {
+        self.run_inner(u64::MAX, n, None).0
+    }
+
+    /// Run until `pred` holds at an instruction boundary (`StopReason::Breakpoint`),
+    /// checking it before every instruction including the first. Translated blocks are
+    /// not used, since the predicate has to see every boundary.
+    pub fn run_until(&mut self, mut pred: impl FnMut(&CPU) -> bool) -> StopReason {
+        self.run_inner(u64::MAX, u64::MAX, Some(&mut pred)).0
+    }
+
+    /// The loop behind every run: execute until the CPU halts, a device request
+    /// (`StopReason::DeviceRequest`) or fault is carried out, a budget runs out, or a
+    /// breakpoint or `until` stops it. Then bring devices up to date (`sync_devices`).
+    /// Returns why it stopped and the number of instructions executed.
+    fn run_inner(&mut self, max_cycles: u64, max_instructions: u64, mut until: Option<&mut dyn FnMut(&CPU) -> bool>) -> (StopReason, u64) {
+        let (start, end) = (self.cycles, self.cycles.saturating_add(max_cycles));
+        let mut executed = 0;
+        self.stop = None;
+        let reason = loop {
+            let boundary = self.micro.is_none();
+            if boundary {
+                // take requests here, so the run ends before the next instruction
+                if self.request.is_some() && !self.halted {
+                    self.poll_interrupt();
+                }
+                if let Some(reason) = self.stop.take() {
+                    break reason;
+                }
+                if self.halted {
+                    break StopReason::Halted;
                 }
             }
+            if self.cycles >= end || executed >= max_instructions {
+                break StopReason::BudgetExhausted;
+            }
+            if boundary {
+                let moved = executed > 0 || self.cycles > start;
+                if (moved && self.breakpoints.contains(&self.pc)) || until.as_mut().is_some_and(|f| f(self)) {
+                    break StopReason::Breakpoint(self.pc);
+                }
+            }
+            if self.mode == ExecMode::MicroCycle || !boundary {
+                if self.tick() {
+                    executed += 1;
+                }
+                continue;
+            }
+            if until.is_none() {
+                if let Some(n) = self.run_native_block(end - self.cycles, max_instructions - executed) {
+                    executed += n;
+                    continue;
+                }
+            }
+            self.step_and_tick_instruction();
+            executed += 1;
+        };
+        self.sync_devices();
+        (reason, executed)
+    }
+
+    /// Run the block at `pc` as host code if the JIT has (or now makes) a translation and
+    /// nothing could observe the instruction boundaries inside it: no instruction-level
+    /// models or tracers, no interrupt or device request to take, and no device due before
+    /// its last cycle, no breakpoint inside it and no more than `max_cycles` cycles and
+    /// `max_instructions` instructions. Returns the instructions run, or `None` if the
+    /// interpreter has to step instead.
+    fn run_native_block(&mut self, max_cycles: u64, max_instructions: u64) -> Option<u64> {
+        if self.jit.is_none()
+            || self.mode != ExecMode::Instruction
+            || self.micro.is_some()
+            || !self.tracers.is_empty()
+            || self.icache.is_some()
+            || self.dcache.is_some()
+            || self.branch_unit.is_some()
+            || self.request.is_some()
+            || (self.interrupts_enabled && self.irq_asserted())
+        {
+            return None;
+        }
+        let due = self.devices.iter().filter_map(Slot::due).min().unwrap_or(u64::MAX);
+        let (jit, blocks) = (self.jit.as_mut()?, self.block_cache.as_ref()?);
+        let (serial, block) = blocks.block(&self.mem, self.pc)?;
+        if block[1..].iter().any(|d| self.breakpoints.contains(&d.pc)) {
+            return None;
         }
+        if jit.device_epoch != self.device_epoch {
+            jit.flush();
+            jit.device_epoch = self.device_epoch;
+        }
+        let devices = &self.devices;
+        let is_mmio = |addr: usize| devices.iter().any(|s| s.base.is_some_and(|b| addr >= b && addr < b + s.dev.mmio_size()));
+        let max_cycles = due.saturating_sub(self.cycles + 1).min(max_cycles);
+        let run = jit.run((blocks.generation(), serial, block), &mut self.mem, &mut self.regs, &mut self.z, is_mmio, (max_cycles, max_instructions))?;
+        self.begin_instruction();
+        self.pc = run.next_pc;
+        self.bus_stats.wait_state_cycles += run.wait;
+        self.tick_devices(run.cycles);
+        Some(run.instructions)
+    }
+
+    /// Run with a human-readable trace on stdout (see `trace::TextTracer`).
+    /// Any tracers already attached keep receiving records too.
+    pub fn run_with_trace(&mut self) -> StopReason {
+        let saved = std::mem::take(&mut self.tracers);
+        self.tracers.push(Box::new(TextTracer::new(io::stdout())));
+        self.tracers.extend(saved);
+        let reason = self.run();
+        let mut text = self.tracers.remove(0);
+        text.finish();
+        reason
     }
 
     pub fn dump_state(&self) {
@@ -237,4 +1058,314 @@ mod tests {
         assert_eq!(cpu.cycles, 12);
         assert!(cpu.halted);
     }
+
+    #[test]
+    fn test_sub_jz_loop() {
+        // count R0 down from 3 to 0, storing the iteration count in memory
+        let program: &[u8] = &[
+            0x10, 0x03, // 00: LDI R0,3
+            0x11, 0x01, // 02: LDI R1,1
+            0x44, 0x0C, // 04: JZ R0,0x0C
+            0x24, 0x01, // 06: SUB R0,R1
+            0x22, 0x01, // 08: ADD R2,R1
+            0x40, 0x04, // 0A: JMP 0x04
+            0x36, 0x80, // 0C: STORE R2,0x80
+            0xFF,       // 0E: HLT
+        ];
+        let mut cpu = CPU::new();
+        cpu.load(program, 0);
+        cpu.run();
+        assert_eq!(cpu.regs[0], 0);
+        assert_eq!(cpu.mem.read(0x80), 3);
+    }
+
+    #[test]
+    fn test_micro_cycle_mode_matches_instruction_mode() {
+        use crate::memory::AccessKind;
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        struct BusLog(Rc<RefCell<Vec<(u64, MemAccess)>>>);
+        impl Device for BusLog {
+            fn tick(&mut self, _current_cycle: u64) {}
+            fn bus_activity(&mut self, current_cycle: u64, access: &MemAccess) {
+                self.0.borrow_mut().push((current_cycle, *access));
+            }
+        }
+
+        let program: &[u8] = &[
+            0x10, 0x05, // LDI R0,5     cycles 1-2
+            0x34, 0x80, // STORE R0,0x80 cycles 3-6 (write in the Memory stage, cycle 6)
+            0x31, 0x80, // LOAD R1,0x80  cycles 7-10 (read in cycle 9)
+            0xFF,       // HLT
+        ];
+        let mut results = Vec::new();
+        for mode in [ExecMode::Instruction, ExecMode::MicroCycle] {
+            let log = Rc::new(RefCell::new(Vec::new()));
+            let mut cpu = CPU::new();
+            cpu.mode = mode;
+            cpu.attach_device(Box::new(BusLog(log.clone())));
+            cpu.load(program, 0);
+            cpu.run();
+            let data: Vec<(u64, AccessKind)> =
+                log.borrow().iter().filter(|(_, a)| a.kind != AccessKind::Fetch).map(|(c, a)| (*c, a.kind)).collect();
+            results.push((cpu.regs, cpu.cycles, cpu.mem.read(0x80), data));
+        }
+        assert_eq!(results[0].0, results[1].0);
+        assert_eq!(results[0].1, results[1].1);
+        assert_eq!(results[1].2, 5);
+        // instruction mode reports on the first cycle, micro mode on the exact micro-step
+        assert_eq!(results[0].3, vec![(3, AccessKind::Write), (7, AccessKind::Read)]);
+        assert_eq!(results[1].3, vec![(6, AccessKind::Write), (9, AccessKind::Read)]);
+    }
+
+    #[test]
+    fn test_cache_latency_adds_cycles() {
+        use crate::cache::CacheConfig;
+
+        let program: &[u8] = &[
+            0x10, 0x05, // LDI R0,5
+            0x34, 0x80, // STORE R0,0x80
+            0x31, 0x80, // LOAD R1,0x80
+            0xFF,       // HLT
+        ];
+        let config = CacheConfig { size: 16, line_size: 4, associativity: 1, miss_latency: 5, ..CacheConfig::default() };
+        let mut cycles = Vec::new();
+        for mode in [ExecMode::Instruction, ExecMode::MicroCycle] {
+            let mut cpu = CPU::new();
+            cpu.mode = mode;
+            cpu.icache = Some(Cache::new(config).unwrap());
+            cpu.dcache = Some(Cache::new(config).unwrap());
+            cpu.load(program, 0);
+            cpu.run();
+            let (i, d) = (cpu.icache.as_ref().unwrap().stats(), cpu.dcache.as_ref().unwrap().stats());
+            // fetch lines 0x00 and 0x04 miss; STORE allocates 0x80, LOAD hits
+            assert_eq!((i.reads, i.read_misses), (7, 2));
+            assert_eq!((d.write_misses, d.read_misses), (1, 0));
+            assert_eq!(cpu.regs[1], 5);
+            cycles.push(cpu.cycles);
+        }
+        assert_eq!(cycles, vec![11 + 3 * 5, 11 + 3 * 5]);
+    }
+
+    #[test]
+    fn test_region_wait_states_and_bus_contention() {
+        use crate::memory::Region;
+
+        // holds the bus for cycles 1..=3 (DMA-style)
+        struct BusHog;
+        impl Device for BusHog {
+            fn tick(&mut self, _current_cycle: u64) {}
+            fn bus_request(&mut self, current_cycle: u64) -> bool {
+                current_cycle <= 3
+            }
+        }
+
+        let program: &[u8] = &[
+            0x34, 0xF0, // STORE R0,0xF0  (ROM: dropped, 1 write wait)
+            0x31, 0x80, // LOAD R1,0x80   (slow RAM: 3 read waits)
+            0xFF,       // HLT
+        ];
+        for mode in [ExecMode::Instruction, ExecMode::MicroCycle] {
+            let mut cpu = CPU::new();
+            cpu.mode = mode;
+            cpu.regs[0] = 7;
+            cpu.mem.add_region(Region::new("slow", 0x80, 0xC0, 3, 3));
+            cpu.mem.add_region(Region { write_wait: 1, ..Region::rom("rom", 0xF0, 0x100, 0) });
+            cpu.load(program, 0);
+            cpu.run();
+            assert_eq!(cpu.mem.read(0xF0), 0);
+            assert_eq!(cpu.bus_stats.wait_state_cycles, 4);
+            assert_eq!(cpu.cycles, 9 + 4);
+
+            let mut cpu2 = CPU::new();
+            cpu2.mode = mode;
+            cpu2.attach_device(Box::new(BusHog));
+            cpu2.load(program, 0);
+            cpu2.run();
+            assert!(cpu2.bus_stats.contention_cycles > 0, "{:?}", mode);
+            assert_eq!(cpu2.cycles, 9 + cpu2.bus_stats.contention_cycles);
+        }
+    }
+
+    #[test]
+    fn devices_are_found_by_id_replaced_and_detached() {
+        use crate::device::{TimerDevice, TIMER_BASE, TIMER_CTRL};
+        use crate::dma::DmaController;
+
+        let mut cpu = CPU::new();
+        let timer = cpu.attach_mapped_device(Box::new(TimerDevice::periodic(0, 0xFB)), TIMER_BASE).unwrap();
+        let free = cpu.attach_device(Box::new(TimerDevice::periodic(0, 0)));
+        assert!(cpu.attach_mapped_device(Box::new(TimerDevice::new()), TIMER_BASE + 2).is_err());
+        assert!(cpu.attach_mapped_device(Box::new(TimerDevice::new()), usize::MAX).is_err());
+        cpu.load(&[0x00; 20], 0);
+        cpu.step_n_instructions(20);
+
+        assert_eq!(cpu.device::<TimerDevice>(timer).unwrap().overflows(), 4);
+        assert!(cpu.device::<DmaController>(timer).is_none());
+        cpu.device_mut::<TimerDevice>(free).unwrap().mmio_write(TIMER_CTRL, 0);
+        assert!(cpu.device_info()[0].description.starts_with("timer ctrl=03 prescale=0 reload=FB"));
+
+        // a replacement keeps the ID and window; a detached ID is never handed out again
+        assert!(cpu.replace_device(timer, Box::new(DmaController::new())).is_ok());
+        assert!(cpu.device::<DmaController>(timer).is_some());
+        assert!(cpu.detach_device(free).unwrap().describe().starts_with("timer ctrl=00"));
+        assert!(cpu.detach_device(free).is_none());
+        let next = cpu.attach_device(Box::new(TimerDevice::new()));
+        assert!(next != free && next != timer);
+        assert_eq!(cpu.device_info().iter().map(|d| (d.id, d.base)).collect::<Vec<_>>(), vec![(timer, Some(TIMER_BASE)), (next, None)]);
+    }
+
+    #[test]
+    fn event_driven_scheduling_matches_per_cycle_ticking() {
+        use crate::assembler::assemble;
+        use crate::device::{TimerDevice, TIMER_BASE};
+        use crate::dma::{DmaController, DMA_BASE};
+        use crate::gpio::{Gpio, SwitchScript, GPIO_BASE};
+        use crate::rtc::{Rtc, RtcConfig, RTC_BASE};
+        use crate::watchdog::{Watchdog, WatchdogAction, WatchdogConfig, WATCHDOG_BASE};
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        /// Counts its ticks and never asks for one.
+        #[derive(Debug)]
+        struct Idle(Rc<Cell<u64>>);
+        impl Device for Idle {
+            fn tick(&mut self, _current_cycle: u64) {
+                self.0.set(self.0.get() + 1);
+            }
+            fn next_event(&self, _now: u64) -> Option<u64> {
+                None
+            }
+        }
+
+        // timer overflow, switch edge and RTC alarm interrupts while kicking the watchdog,
+        // then a spin that its NMI ends
+        let main = r#"
+            LDI R1, 1
+            LDI R0, 3
+            STORE R0, 0xE9      ; timer PRESCALE
+            LDI R0, 0x80
+            STORE R0, 0xEA      ; RELOAD
+            LDI R0, 7
+            STORE R0, 0xE8      ; CTRL = enable | periodic | overflow irq
+            STORE R1, 0xB9      ; GPIO RISE = pin 0
+            LDI R0, 2
+            STORE R0, 0xC3      ; RTC alarm at 00:00:02
+            LDI R0, 3
+            STORE R0, 0xC6      ; alarm + irq
+            LDI R0, 0x5A
+            LDI R3, 0
+            EI
+            loop:
+            STORE R0, 0xB0
+            SUB R3, R1
+            JZ R3, spin
+            JMP loop
+            spin:
+            JMP spin
+        "#;
+        let handler = "LDI R2, 1\nSTORE R2, 0xED\nLOAD R2, 0xBB\nLOAD R2, 0xC7\nLOAD R2, 0x80\nADD R2, R1\nSTORE R2, 0x80\nRETI";
+        let run = |mode: ExecMode, scheduling: Scheduling| {
+            let mut cpu = CPU::new();
+            cpu.mode = mode;
+            cpu.scheduling = scheduling;
+            let idle_ticks = Rc::new(Cell::new(0));
+            let gpio = Gpio::new(SwitchScript::parse("150 0 1\n400 0 0\n700 0 1\n2500 0 0").unwrap());
+            let log = gpio.log();
+            let wd = WatchdogConfig { action: WatchdogAction::Nmi, cycles_per_unit: 10, timeout: 60, enabled: true };
+            cpu.attach_mapped_device(Box::new(TimerDevice::new()), TIMER_BASE).unwrap();
+            cpu.attach_mapped_device(Box::new(gpio), GPIO_BASE).unwrap();
+            cpu.attach_mapped_device(Box::new(Rtc::new(RtcConfig { clock_hz: 1000, ..RtcConfig::default() })), RTC_BASE).unwrap();
+            cpu.attach_mapped_device(Box::new(Watchdog::new(wd)), WATCHDOG_BASE).unwrap();
+            cpu.attach_mapped_device(Box::new(DmaController::new()), DMA_BASE).unwrap();
+            cpu.attach_device(Box::new(Idle(idle_ticks.clone())));
+            cpu.mem.write_bytes(IRQ_VECTOR, &assemble(handler).unwrap());
+            cpu.mem.write_bytes(NMI_VECTOR, &assemble("JMP 0x70").unwrap());
+            cpu.mem.write_bytes(0x70, &assemble("HLT").unwrap());
+            cpu.load(&assemble(main).unwrap(), 0);
+            cpu.run();
+            let devices: Vec<String> = cpu.device_info().into_iter().map(|d| d.description).collect();
+            let log = log.borrow().clone();
+            let mem: Vec<u8> = (0..cpu.mem.size()).map(|a| cpu.mem.read(a)).collect();
+            ((cpu.regs, cpu.pc, cpu.cycles, mem, devices, log), idle_ticks.get())
+        };
+
+        for mode in [ExecMode::Instruction, ExecMode::MicroCycle] {
+            let (per_cycle, ticks) = run(mode, Scheduling::PerCycle);
+            let (event_driven, skipped) = run(mode, Scheduling::EventDriven);
+            assert_eq!(per_cycle, event_driven, "{:?}", mode);
+            let (_, _, cycles, mem, _, log) = per_cycle;
+            assert!(mem[0x80] > 8 && log.len() == 4 && cycles > 2500, "{} {} {}", mem[0x80], log.len(), cycles);
+            assert_eq!(ticks, cycles);
+            assert!(skipped <= 2, "{}", skipped);
+        }
+    }
+
+    #[test]
+    fn bounded_runs_report_why_they_stopped() {
+        use crate::assembler::assemble;
+        use crate::device::DeviceContext;
+
+        /// Raises `request` at a fixed cycle.
+        #[derive(Debug)]
+        struct Requester(u64, CpuRequest);
+        impl Device for Requester {
+            fn tick_with(&mut self, ctx: &mut DeviceContext<'_>) {
+                if ctx.cycle() == self.0 {
+                    ctx.request(self.1.clone());
+                }
+            }
+            fn next_event(&self, now: u64) -> Option<u64> {
+                (now < self.0).then_some(self.0)
+            }
+        }
+
+        // counts R0 up forever: LDI takes 2 cycles, ADD and JMP 3 each
+        let program = assemble("LDI R1, 1\nloop:\nADD R0, R1\nJMP loop").unwrap();
+        let mut cpu = CPU::new();
+        cpu.load(&program, 0);
+        assert_eq!(cpu.run_for_cycles(100), StopReason::BudgetExhausted);
+        assert_eq!(cpu.cycles, 101);
+        assert_eq!(cpu.run_for_instructions(10), StopReason::BudgetExhausted);
+        assert_eq!(cpu.cycles, 131);
+
+        // a run does not stop at the breakpoint it starts from
+        cpu.breakpoints.insert(0x04);
+        assert_eq!(cpu.run(), StopReason::Breakpoint(0x04));
+        let r0 = cpu.regs[0];
+        assert_eq!(cpu.run(), StopReason::Breakpoint(0x04));
+        assert_eq!(cpu.regs[0], r0 + 1);
+        cpu.breakpoints.clear();
+        assert_eq!(cpu.run_until(|c| c.regs[0] == 200), StopReason::Breakpoint(0x04));
+        assert_eq!(cpu.step_n_instructions(3), (3, 9));
+
+        // budgets and device requests end runs at the same point with the JIT
+        let run = |jit: Option<Jit>| {
+            let mut cpu = CPU::new();
+            cpu.jit = jit;
+            cpu.load(&program, 0);
+            cpu.attach_device(Box::new(Requester(5000, CpuRequest::Reset)));
+            cpu.attach_device(Box::new(Requester(9000, CpuRequest::Halt)));
+            cpu.attach_device(Box::new(Requester(9500, CpuRequest::Fault("stop".to_string()))));
+            let mut stops = vec![(cpu.run_for_instructions(1001), cpu.cycles)];
+            stops.push((cpu.run_for_cycles(10_000), cpu.cycles));
+            stops.push((cpu.run(), cpu.cycles));
+            cpu.halted = false;
+            stops.push((cpu.run(), cpu.cycles));
+            stops.push((cpu.run(), cpu.cycles));
+            (stops, cpu.regs, cpu.jit.map(|j| j.stats.native_instructions))
+        };
+        let (stops, regs, _) = run(None);
+        assert_eq!(stops[0], (StopReason::BudgetExhausted, 3002));
+        assert_eq!(stops[1].0, StopReason::DeviceRequest(CpuRequest::Reset));
+        assert_eq!(stops[2].0, StopReason::DeviceRequest(CpuRequest::Halt));
+        assert_eq!(stops[3].0, StopReason::Fault("stop".to_string()));
+        assert_eq!(stops[4], (StopReason::Halted, stops[3].1));
+        if let Ok(jit) = Jit::new() {
+            let (native_stops, native_regs, native) = run(Some(jit));
+            assert_eq!((native_stops, native_regs), (stops, regs));
+            assert!(native.unwrap() > 1000);
+        }
+    }
 }
@@ -1,9 +1,233 @@
+use crate::memory::{MemAccess, Memory};
+use std::any::Any;
 use std::fmt::{Debug, Formatter};
 
-/// Device trait for per-cycle devices. The CPU calls `tick(current_cycle)` once per cycle.
-/// Devices are free to do work or produce side effects when tick() is called.
-pub trait Device {
-    fn tick(&mut self, current_cycle: u64);
+/// Something a device asks the CPU to do (see `DeviceContext`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CpuRequest {
+    /// Non-maskable interrupt: taken even with interrupts disabled, at `CPU::nmi_vector`.
+    Nmi,
+    /// Warm reset (see `CPU::reset`).
+    Reset,
+    /// Stop the CPU as if it had executed `HLT`.
+    Halt,
+    /// Stop the CPU with a fault (see `CPU::fault`).
+    Fault(String),
+}
+
+impl CpuRequest {
+    /// When several requests arrive before an instruction boundary the highest ranked wins.
+    fn rank(&self) -> u8 {
+        match self {
+            CpuRequest::Nmi => 0,
+            CpuRequest::Reset => 1,
+            CpuRequest::Halt => 2,
+            CpuRequest::Fault(_) => 3,
+        }
+    }
+}
+
+/// What a device can reach while it is ticked: the current cycle, memory, its own
+/// interrupt line, requests to the CPU and a wakeup timer.
+#[derive(Debug)]
+pub struct DeviceContext<'a> {
+    cycle: u64,
+    mem: &'a mut Memory,
+    irq: &'a mut bool,
+    request: &'a mut Option<CpuRequest>,
+    wakeup: &'a mut Option<u64>,
+}
+
+impl<'a> DeviceContext<'a> {
+    pub fn new(
+        cycle: u64,
+        mem: &'a mut Memory,
+        irq: &'a mut bool,
+        request: &'a mut Option<CpuRequest>,
+        wakeup: &'a mut Option<u64>,
+    ) -> Self {
+        DeviceContext { cycle, mem, irq, request, wakeup }
+    }
+
+    pub fn cycle(&self) -> u64 {
+        self.cycle
+    }
+
+    /// Read memory directly. This bypasses bus arbitration and timing (use
+    /// `Device::bus_request` for bus-mastered transfers) and does not reach other
+    /// devices' registers.
+    pub fn read(&self, addr: usize) -> u8 {
+        self.mem.read(addr)
+    }
+
+    /// Write memory directly (see `read`). Writes into read-only regions are dropped.
+    pub fn write(&mut self, addr: usize, value: u8) {
+        if !self.mem.is_read_only(addr) {
+            self.mem.write(addr, value);
+        }
+    }
+
+    pub fn memory(&mut self) -> &mut Memory {
+        self.mem
+    }
+
+    /// Raise this device's interrupt line until `deassert_irq`. It is ORed with
+    /// `Device::irq_pending`.
+    pub fn assert_irq(&mut self) {
+        *self.irq = true;
+    }
+
+    pub fn deassert_irq(&mut self) {
+        *self.irq = false;
+    }
+
+    pub fn irq_asserted(&self) -> bool {
+        *self.irq
+    }
+
+    /// Ask the CPU for something at its next instruction boundary.
+    pub fn request(&mut self, req: CpuRequest) {
+        if self.request.as_ref().is_none_or(|cur| req.rank() > cur.rank()) {
+            *self.request = Some(req);
+        }
+    }
+
+    pub fn request_nmi(&mut self) {
+        self.request(CpuRequest::Nmi);
+    }
+
+    pub fn request_reset(&mut self) {
+        self.request(CpuRequest::Reset);
+    }
+
+    /// Stop the simulation (`CPU::halted`), e.g. when a test device has seen enough.
+    pub fn request_halt(&mut self) {
+        self.request(CpuRequest::Halt);
+    }
+
+    pub fn fault(&mut self, reason: &str) {
+        self.request(CpuRequest::Fault(reason.to_string()));
+    }
+
+    /// Have `Device::wakeup` called at `cycle` (replacing any earlier schedule).
+    pub fn wake_at(&mut self, cycle: u64) {
+        *self.wakeup = Some(cycle.max(self.cycle + 1));
+    }
+
+    /// Have `Device::wakeup` called `cycles` from now.
+    pub fn wake_in(&mut self, cycles: u64) {
+        self.wake_at(self.cycle + cycles.max(1));
+    }
+
+    pub fn cancel_wakeup(&mut self) {
+        *self.wakeup = None;
+    }
+
+    pub fn next_wakeup(&self) -> Option<u64> {
+        *self.wakeup
+    }
+}
+
+/// Identifies an attached device (see `CPU::attach_device`). IDs are not reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct DeviceId(pub u32);
+
+impl std::fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// `Any` access for `CPU::device`; implemented for every device type.
+pub trait AsAny {
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<T: Any> AsAny for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Device trait for per-cycle devices. The CPU calls `tick_with` once per cycle with a
+/// `DeviceContext`; its default forwards to `tick(current_cycle)`, so devices that only
+/// need the cycle count implement `tick` and nothing else.
+pub trait Device: AsAny {
+    /// One-line state dump (for the REPL `devices` command). Defaults to the type name.
+    fn describe(&self) -> String {
+        let name = std::any::type_name::<Self>();
+        name.rsplit("::").next().unwrap_or(name).to_string()
+    }
+
+    /// Per-cycle work for devices that only need the cycle count.
+    fn tick(&mut self, _current_cycle: u64) {}
+
+    /// Per-cycle work with access to memory, the interrupt line, CPU requests and
+    /// wakeups. Defaults to `tick(ctx.cycle())`.
+    fn tick_with(&mut self, ctx: &mut DeviceContext<'_>) {
+        self.tick(ctx.cycle());
+    }
+
+    /// Called before `tick_with` in the cycle scheduled with `DeviceContext::wake_at`.
+    fn wakeup(&mut self, _ctx: &mut DeviceContext<'_>) {}
+
+    /// Under `Scheduling::EventDriven`, the next cycle this device must be ticked after
+    /// being ticked or accessed at `now`: the cycle of its next change the CPU could notice
+    /// without reading a register (interrupt line, CPU request, memory write, bus request).
+    /// `None` waits for a register access or wakeup. Skipped cycles must be caught up in
+    /// the next `tick_with`, which the CPU also calls before every register access, and
+    /// bus activity is only reported on ticked cycles. Defaults to every cycle.
+    fn next_event(&self, now: u64) -> Option<u64> {
+        Some(now + 1)
+    }
+
+    /// Called for each CPU bus access (fetches included) just before `tick` of the cycle
+    /// the access happens in. In micro-cycle mode that is the exact micro-step; in
+    /// instruction mode all of an instruction's accesses are reported on its first cycle.
+    fn bus_activity(&mut self, _current_cycle: u64, _access: &MemAccess) {}
+
+    /// Whether `bus_activity` needs instruction fetches. Devices that ignore bus activity
+    /// return false (all built-in ones do): when no attached device wants them, fetches
+    /// from predecoded blocks (see `blocks::BlockCache`) are only counted for bus
+    /// contention, not recorded. Read once when the device is attached.
+    fn watches_fetches(&self) -> bool {
+        true
+    }
+
+    /// Whether the device masters the bus in `current_cycle` (e.g. for DMA). Asked once
+    /// per cycle before `tick`. Devices win arbitration: a CPU access that needs the bus
+    /// in a cycle owned by a device waits until the bus is free.
+    fn bus_request(&mut self, _current_cycle: u64) -> bool {
+        false
+    }
+
+    /// Called in a cycle the device won through `bus_request` (before `tick`). The device
+    /// may access memory directly. When several devices ask, the first attached wins.
+    fn bus_grant(&mut self, _current_cycle: u64, _mem: &mut Memory) {}
+
+    /// Size in bytes of the register window when attached with `CPU::attach_mapped_device`.
+    fn mmio_size(&self) -> usize {
+        0
+    }
+
+    /// CPU read of register `offset` within the device's window.
+    fn mmio_read(&mut self, _offset: usize) -> u8 {
+        0
+    }
+
+    /// CPU write of register `offset` within the device's window.
+    fn mmio_write(&mut self, _offset: usize, _value: u8) {}
+
+    /// Level-triggered interrupt line. The CPU polls it between instructions and takes
+    /// the interrupt if enabled (`EI`); the device deasserts it when acknowledged.
+    fn irq_pending(&self) -> bool {
+        false
+    }
 }
 
 impl Debug for dyn Device {
@@ -12,38 +236,293 @@ impl Debug for dyn Device {
     }
 }
 
-/// A very small example Timer device that prints every `period` cycles.
+/// Default base address of the timer's register window (see `CPU::attach_mapped_device`).
+pub const TIMER_BASE: usize = 0xE8;
+
+/// Register offsets within the timer's window.
+/// CTRL (see the `TIMER_*` bits). Setting `TIMER_ENABLE` (re)loads COUNT from RELOAD.
+pub const TIMER_CTRL: usize = 0;
+/// PRESCALE: COUNT advances once every PRESCALE + 1 cycles.
+pub const TIMER_PRESCALE: usize = 1;
+/// RELOAD: value COUNT starts from, and restarts from after an overflow.
+pub const TIMER_RELOAD: usize = 2;
+/// COMPARE: `TIMER_FLAG_COMPARE` is set when COUNT reaches this value.
+pub const TIMER_COMPARE: usize = 3;
+/// COUNT: the up-counter. Writable.
+pub const TIMER_COUNT: usize = 4;
+/// STATUS: `TIMER_FLAG_*` bits; writing 1 to a bit clears it.
+pub const TIMER_STATUS: usize = 5;
+/// Number of registers.
+pub const TIMER_REGS: usize = 6;
+
+pub const TIMER_ENABLE: u8 = 0x01;
+/// Restart from RELOAD after an overflow; otherwise stop (one-shot).
+pub const TIMER_PERIODIC: u8 = 0x02;
+/// Interrupt while `TIMER_FLAG_OVERFLOW` is set.
+pub const TIMER_IRQ_OVERFLOW: u8 = 0x04;
+/// Interrupt while `TIMER_FLAG_COMPARE` is set.
+pub const TIMER_IRQ_COMPARE: u8 = 0x08;
+
+pub const TIMER_FLAG_OVERFLOW: u8 = 0x01;
+pub const TIMER_FLAG_COMPARE: u8 = 0x02;
+
+/// Programmable 8-bit timer/counter. COUNT counts up from RELOAD and overflows after
+/// 0xFF, so with prescale `p` a periodic timer overflows every `(256 - reload) * (p + 1)`
+/// cycles. Everything is driven by the cycle numbers passed to `tick`, so runs are
+/// deterministic.
+#[derive(Debug, Default)]
 pub struct TimerDevice {
-    period: u64,
-    next: u64,
+    ctrl: u8,
+    prescale: u8,
+    reload: u8,
+    compare: u8,
+    count: u8,
+    status: u8,
+    /// Cycles since COUNT last advanced.
+    divider: u8,
+    overflows: u64,
+    /// Cycle of the previous `tick`.
+    last: Option<u64>,
 }
 
 impl TimerDevice {
-    pub fn new(period: u64) -> Self {
-        TimerDevice { period, next: period }
+    /// A stopped timer, to be programmed through its registers.
+    pub fn new() -> Self {
+        TimerDevice::default()
+    }
+
+    /// A running periodic timer (no interrupts) overflowing every `(256 - reload) * (prescale + 1)` cycles.
+    pub fn periodic(prescale: u8, reload: u8) -> Self {
+        let mut t = TimerDevice { prescale, reload, ..TimerDevice::default() };
+        t.mmio_write(TIMER_CTRL, TIMER_ENABLE | TIMER_PERIODIC);
+        t
+    }
+
+    /// Number of overflows so far.
+    pub fn overflows(&self) -> u64 {
+        self.overflows
+    }
+
+    pub fn count(&self) -> u8 {
+        self.count
+    }
+
+    pub fn status(&self) -> u8 {
+        self.status
+    }
+
+    /// Count for `cycles` cycles in one go: a step of COUNT every `prescale + 1` cycles.
+    fn advance(&mut self, mut cycles: u64) {
+        while cycles > 0 && self.ctrl & TIMER_ENABLE != 0 {
+            let period = self.prescale as u64 + 1;
+            let first = self.prescale.saturating_sub(self.divider) as u64 + 1;
+            if cycles < first {
+                self.divider += cycles as u8;
+                return;
+            }
+            let steps = 1 + (cycles - first) / period;
+            let to_overflow = 256 - self.count as u64;
+            if self.compare > self.count && (self.compare as u64) < self.count as u64 + steps.min(to_overflow) + 1 {
+                self.status |= TIMER_FLAG_COMPARE;
+            }
+            if steps < to_overflow {
+                self.count += steps as u8;
+                self.divider = ((cycles - first) % period) as u8;
+                return;
+            }
+            cycles -= first + (to_overflow - 1) * period;
+            self.divider = 0;
+            self.overflows += 1;
+            self.status |= TIMER_FLAG_OVERFLOW;
+            self.count = self.reload;
+            if self.ctrl & TIMER_PERIODIC == 0 {
+                self.ctrl &= !TIMER_ENABLE;
+            }
+            if self.count == self.compare {
+                self.status |= TIMER_FLAG_COMPARE;
+            }
+        }
     }
 }
 
 impl Device for TimerDevice {
+    fn describe(&self) -> String {
+        format!(
+            "timer ctrl={:02X} prescale={} reload={:02X} compare={:02X} count={:02X} status={:02X} overflows={}",
+            self.ctrl, self.prescale, self.reload, self.compare, self.count, self.status, self.overflows
+        )
+    }
+
     fn tick(&mut self, current_cycle: u64) {
-        if current_cycle >= self.next {
-            println!("[device] Timer tick at cycle {}", current_cycle);
-            self.next += self.period;
+        let elapsed = current_cycle.saturating_sub(self.last.unwrap_or(current_cycle.saturating_sub(1)));
+        self.last = Some(current_cycle);
+        self.advance(elapsed);
+    }
+
+    /// The next overflow or compare match, if it can interrupt.
+    fn next_event(&self, now: u64) -> Option<u64> {
+        if self.ctrl & TIMER_ENABLE == 0 || self.ctrl & (TIMER_IRQ_OVERFLOW | TIMER_IRQ_COMPARE) == 0 {
+            return None;
+        }
+        let mut steps = 256 - self.count as u64;
+        if self.compare > self.count {
+            steps = steps.min((self.compare - self.count) as u64);
         }
+        let first = self.prescale.saturating_sub(self.divider) as u64 + 1;
+        Some(now + first + (steps - 1) * (self.prescale as u64 + 1))
+    }
+
+    fn mmio_size(&self) -> usize {
+        TIMER_REGS
+    }
+
+    fn mmio_read(&mut self, offset: usize) -> u8 {
+        match offset {
+            TIMER_CTRL => self.ctrl,
+            TIMER_PRESCALE => self.prescale,
+            TIMER_RELOAD => self.reload,
+            TIMER_COMPARE => self.compare,
+            TIMER_COUNT => self.count,
+            TIMER_STATUS => self.status,
+            _ => 0,
+        }
+    }
+
+    fn mmio_write(&mut self, offset: usize, value: u8) {
+        match offset {
+            TIMER_CTRL => {
+                if value & TIMER_ENABLE != 0 && self.ctrl & TIMER_ENABLE == 0 {
+                    self.count = self.reload;
+                    self.divider = 0;
+                }
+                self.ctrl = value;
+            }
+            TIMER_PRESCALE => self.prescale = value,
+            TIMER_RELOAD => self.reload = value,
+            TIMER_COMPARE => self.compare = value,
+            TIMER_COUNT => self.count = value,
+            TIMER_STATUS => self.status &= !value,
+            _ => {}
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        (self.ctrl & TIMER_IRQ_OVERFLOW != 0 && self.status & TIMER_FLAG_OVERFLOW != 0)
+            || (self.ctrl & TIMER_IRQ_COMPARE != 0 && self.status & TIMER_FLAG_COMPARE != 0)
+    }
+
+    fn watches_fetches(&self) -> bool {
+        false
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::assembler::assemble;
+    use crate::cpu::CPU;
 
     #[test]
-    fn timer_runs() {
-        let mut t = TimerDevice::new(2);
-        // tick a few times; ensure no panic (behavior is printed)
-        t.tick(1);
-        t.tick(2);
-        t.tick(3);
-        t.tick(4);
+    fn prescaler_reload_compare_and_one_shot() {
+        // overflow every (256 - 0xFC) * (1 + 1) = 8 cycles
+        let mut t = TimerDevice::periodic(1, 0xFC);
+        t.mmio_write(TIMER_COMPARE, 0xFE);
+        for c in 1..=8 {
+            t.tick(c);
+            if c == 4 {
+                assert_eq!(t.status(), TIMER_FLAG_COMPARE);
+            }
+        }
+        assert_eq!((t.overflows(), t.count()), (1, 0xFC));
+        t.mmio_write(TIMER_STATUS, TIMER_FLAG_OVERFLOW | TIMER_FLAG_COMPARE);
+        for c in 9..=40 {
+            t.tick(c);
+        }
+        assert_eq!(t.overflows(), 5);
+
+        let mut one_shot = TimerDevice::new();
+        one_shot.mmio_write(TIMER_RELOAD, 0xFE);
+        one_shot.mmio_write(TIMER_CTRL, TIMER_ENABLE | TIMER_IRQ_OVERFLOW);
+        for c in 1..=10 {
+            one_shot.tick(c);
+        }
+        assert_eq!(one_shot.overflows(), 1);
+        assert_eq!(one_shot.mmio_read(TIMER_CTRL) & TIMER_ENABLE, 0);
+        assert!(one_shot.irq_pending());
+        one_shot.mmio_write(TIMER_STATUS, TIMER_FLAG_OVERFLOW);
+        assert!(!one_shot.irq_pending());
+    }
+
+    #[test]
+    fn overflow_interrupts_are_deterministic() {
+        // count at least 3 timer interrupts in R2, then halt
+        let main = r#"
+            LDI R0, 0xF0
+            STORE R0, 0xEA      ; RELOAD: overflow every 16 cycles
+            LDI R1, 3
+            EI
+            LDI R0, 7           ; ENABLE | PERIODIC | IRQ_OVERFLOW
+            STORE R0, 0xE8
+            wait:
+            SUB R1, R2
+            JZ R1, done
+            LDI R1, 3
+            JMP wait
+            done:
+            HLT
+        "#;
+        let handler = "LDI R3, 1\nSTORE R3, 0xED\nADD R2, R3\nRETI";
+        let mut runs = Vec::new();
+        for _ in 0..2 {
+            let mut cpu = CPU::new();
+            cpu.attach_mapped_device(Box::new(TimerDevice::new()), TIMER_BASE).unwrap();
+            cpu.mem.write_bytes(0xD0, &assemble(handler).unwrap());
+            cpu.load(&assemble(main).unwrap(), 0);
+            cpu.run();
+            // the fourth overflow is taken between the SUB that reaches zero and HLT
+            assert_eq!(cpu.regs[2], 4);
+            runs.push((cpu.regs, cpu.cycles));
+        }
+        assert_eq!(runs[0], runs[1]);
+    }
+
+    #[test]
+    fn context_devices_use_memory_irqs_wakeups_and_requests() {
+        /// Every 10 cycles copies 0x80 to 0x81 and raises its interrupt line while 0x80
+        /// is odd; halts the CPU once 0x80 reaches 5.
+        #[derive(Debug)]
+        struct Mirror;
+        impl Device for Mirror {
+            fn tick_with(&mut self, ctx: &mut DeviceContext<'_>) {
+                if ctx.next_wakeup().is_none() {
+                    ctx.wake_in(10);
+                }
+            }
+            fn wakeup(&mut self, ctx: &mut DeviceContext<'_>) {
+                let v = ctx.read(0x80);
+                ctx.write(0x81, v);
+                if v % 2 == 1 {
+                    ctx.assert_irq();
+                } else {
+                    ctx.deassert_irq();
+                }
+                if v >= 5 {
+                    ctx.request_halt();
+                }
+            }
+        }
+        // count up at 0x80 forever; the handler counts interrupts in R3
+        let main = "EI\nLDI R1, 1\nloop:\nLOAD R0, 0x80\nADD R0, R1\nSTORE R0, 0x80\nJMP loop";
+        let mut cpu = CPU::new();
+        cpu.attach_device(Box::new(Mirror));
+        cpu.mem.write_bytes(0xD0, &assemble("ADD R3, R1\nLOAD R2, 0x80\nADD R2, R1\nSTORE R2, 0x80\nRETI").unwrap());
+        cpu.load(&assemble(main).unwrap(), 0);
+        cpu.run();
+        // stopped by the device, not by HLT
+        assert!(cpu.halted && cpu.fault.is_none());
+        assert_eq!(cpu.mem.read(0x81), 5);
+        // interrupts taken while the line was asserted
+        assert_eq!(cpu.regs[3], 3);
+        assert!(cpu.pc < 0x10);
     }
 }
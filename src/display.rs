@@ -0,0 +1,531 @@
+// src/display.rs
+use crate::device::Device;
+use std::cell::RefCell;
+use std::fmt::{Debug, Formatter};
+use std::fs::File;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+/// Default base address of the display's register window (see `CPU::attach_mapped_device`).
+pub const DISPLAY_BASE: usize = 0xE0;
+
+/// Register offsets within the display's window.
+/// CTRL (see the `CTRL_*` bits).
+pub const DISPLAY_CTRL: usize = 0;
+/// STATUS: `STATUS_VSYNC` is set at every vsync; reading STATUS clears it.
+pub const DISPLAY_STATUS: usize = 1;
+/// ADDR: VRAM index used by DATA.
+pub const DISPLAY_ADDR: usize = 2;
+/// DATA: reads or writes VRAM[ADDR], then increments ADDR.
+pub const DISPLAY_DATA: usize = 3;
+/// PAL: palette entry (0-15) accessed through PAL_R/PAL_G/PAL_B.
+pub const DISPLAY_PAL: usize = 4;
+pub const DISPLAY_PAL_R: usize = 5;
+pub const DISPLAY_PAL_G: usize = 6;
+pub const DISPLAY_PAL_B: usize = 7;
+/// Number of registers.
+pub const DISPLAY_REGS: usize = 8;
+
+/// Bitmap mode instead of text mode.
+pub const CTRL_BITMAP: u8 = 0x01;
+/// Interrupt while `STATUS_VSYNC` is set.
+pub const CTRL_VSYNC_IRQ: u8 = 0x02;
+pub const STATUS_VSYNC: u8 = 0x01;
+
+/// Text mode: `TEXT_COLS` x `TEXT_ROWS` cells. VRAM[0..128] holds characters and
+/// VRAM[128..256] their attributes (low nibble foreground, high nibble background).
+pub const TEXT_COLS: usize = 16;
+pub const TEXT_ROWS: usize = 8;
+/// Bitmap mode: `BITMAP_SIZE` x `BITMAP_SIZE` pixels, one palette index per VRAM byte.
+pub const BITMAP_SIZE: usize = 16;
+pub const VRAM_SIZE: usize = 256;
+
+/// Text cells are drawn as 3x5 glyphs in 4x6 pixel cells.
+const CELL_W: usize = 4;
+const CELL_H: usize = 6;
+
+/// CGA-style default palette.
+const DEFAULT_PALETTE: [[u8; 3]; 16] = [
+    [0x00, 0x00, 0x00],
+    [0x00, 0x00, 0xAA],
+    [0x00, 0xAA, 0x00],
+    [0x00, 0xAA, 0xAA],
+    [0xAA, 0x00, 0x00],
+    [0xAA, 0x00, 0xAA],
+    [0xAA, 0x55, 0x00],
+    [0xAA, 0xAA, 0xAA],
+    [0x55, 0x55, 0x55],
+    [0x55, 0x55, 0xFF],
+    [0x55, 0xFF, 0x55],
+    [0x55, 0xFF, 0xFF],
+    [0xFF, 0x55, 0x55],
+    [0xFF, 0x55, 0xFF],
+    [0xFF, 0xFF, 0x55],
+    [0xFF, 0xFF, 0xFF],
+];
+
+/// 3x5 font, one row per byte (bit 2 = left column). Lower case renders as upper case.
+const FONT: &[(char, [u8; 5])] = &[
+    ('0', [0b111, 0b101, 0b101, 0b101, 0b111]),
+    ('1', [0b010, 0b110, 0b010, 0b010, 0b111]),
+    ('2', [0b111, 0b001, 0b111, 0b100, 0b111]),
+    ('3', [0b111, 0b001, 0b111, 0b001, 0b111]),
+    ('4', [0b101, 0b101, 0b111, 0b001, 0b001]),
+    ('5', [0b111, 0b100, 0b111, 0b001, 0b111]),
+    ('6', [0b111, 0b100, 0b111, 0b101, 0b111]),
+    ('7', [0b111, 0b001, 0b001, 0b001, 0b001]),
+    ('8', [0b111, 0b101, 0b111, 0b101, 0b111]),
+    ('9', [0b111, 0b101, 0b111, 0b001, 0b111]),
+    ('A', [0b010, 0b101, 0b111, 0b101, 0b101]),
+    ('B', [0b110, 0b101, 0b110, 0b101, 0b110]),
+    ('C', [0b011, 0b100, 0b100, 0b100, 0b011]),
+    ('D', [0b110, 0b101, 0b101, 0b101, 0b110]),
+    ('E', [0b111, 0b100, 0b110, 0b100, 0b111]),
+    ('F', [0b111, 0b100, 0b110, 0b100, 0b100]),
+    ('G', [0b011, 0b100, 0b101, 0b101, 0b011]),
+    ('H', [0b101, 0b101, 0b111, 0b101, 0b101]),
+    ('I', [0b111, 0b010, 0b010, 0b010, 0b111]),
+    ('J', [0b001, 0b001, 0b001, 0b101, 0b010]),
+    ('K', [0b101, 0b101, 0b110, 0b101, 0b101]),
+    ('L', [0b100, 0b100, 0b100, 0b100, 0b111]),
+    ('M', [0b101, 0b111, 0b111, 0b101, 0b101]),
+    ('N', [0b110, 0b101, 0b101, 0b101, 0b101]),
+    ('O', [0b010, 0b101, 0b101, 0b101, 0b010]),
+    ('P', [0b110, 0b101, 0b110, 0b100, 0b100]),
+    ('Q', [0b010, 0b101, 0b101, 0b110, 0b011]),
+    ('R', [0b110, 0b101, 0b110, 0b101, 0b101]),
+    ('S', [0b011, 0b100, 0b010, 0b001, 0b110]),
+    ('T', [0b111, 0b010, 0b010, 0b010, 0b010]),
+    ('U', [0b101, 0b101, 0b101, 0b101, 0b111]),
+    ('V', [0b101, 0b101, 0b101, 0b101, 0b010]),
+    ('W', [0b101, 0b101, 0b111, 0b111, 0b101]),
+    ('X', [0b101, 0b101, 0b010, 0b101, 0b101]),
+    ('Y', [0b101, 0b101, 0b010, 0b010, 0b010]),
+    ('Z', [0b111, 0b001, 0b010, 0b100, 0b111]),
+    ('.', [0b000, 0b000, 0b000, 0b000, 0b010]),
+    (',', [0b000, 0b000, 0b000, 0b010, 0b100]),
+    (':', [0b000, 0b010, 0b000, 0b010, 0b000]),
+    ('!', [0b010, 0b010, 0b010, 0b000, 0b010]),
+    ('?', [0b111, 0b001, 0b010, 0b000, 0b010]),
+    ('-', [0b000, 0b000, 0b111, 0b000, 0b000]),
+    ('+', [0b000, 0b010, 0b111, 0b010, 0b000]),
+    ('=', [0b000, 0b111, 0b000, 0b111, 0b000]),
+    ('/', [0b001, 0b001, 0b010, 0b100, 0b100]),
+    ('*', [0b101, 0b010, 0b101, 0b000, 0b000]),
+    ('#', [0b101, 0b111, 0b101, 0b111, 0b101]),
+    ('(', [0b001, 0b010, 0b010, 0b010, 0b001]),
+    (')', [0b100, 0b010, 0b010, 0b010, 0b100]),
+    ('<', [0b001, 0b010, 0b100, 0b010, 0b001]),
+    ('>', [0b100, 0b010, 0b001, 0b010, 0b100]),
+    ('_', [0b000, 0b000, 0b000, 0b000, 0b111]),
+    ('\'', [0b010, 0b010, 0b000, 0b000, 0b000]),
+];
+
+fn glyph(c: u8) -> [u8; 5] {
+    let c = (c as char).to_ascii_uppercase();
+    if c == ' ' || !c.is_ascii_graphic() {
+        return [0; 5];
+    }
+    FONT.iter().find(|(g, _)| *g == c).map(|(_, rows)| *rows).unwrap_or_else(|| glyph(b'?'))
+}
+
+/// An RGB image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    /// `width * height` RGB triples, row by row.
+    pub rgb: Vec<u8>,
+}
+
+impl Image {
+    pub fn pixel(&self, x: usize, y: usize) -> [u8; 3] {
+        let i = 3 * (y * self.width + x);
+        [self.rgb[i], self.rgb[i + 1], self.rgb[i + 2]]
+    }
+
+    /// Binary PPM (P6).
+    pub fn write_ppm<W: Write>(&self, mut out: W) -> io::Result<()> {
+        write!(out, "P6\n{} {}\n255\n", self.width, self.height)?;
+        out.write_all(&self.rgb)
+    }
+}
+
+/// One frame captured at vsync.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    /// Vsync count, starting at 1.
+    pub number: u64,
+    pub cycle: u64,
+    pub image: Image,
+    /// The screen as ANSI-coloured terminal text.
+    pub ansi: String,
+}
+
+/// Receives captured frames. A failed write is kept by the display (see `Display::sink_error`).
+pub trait FrameSink {
+    fn frame(&mut self, frame: &Frame) -> io::Result<()>;
+}
+
+impl Debug for dyn FrameSink {
+    fn fmt(&self, _: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        Ok(())
+    }
+}
+
+impl<T: FrameSink + ?Sized> FrameSink for Rc<RefCell<T>> {
+    fn frame(&mut self, frame: &Frame) -> io::Result<()> {
+        self.borrow_mut().frame(frame)
+    }
+}
+
+/// Keeps every frame in memory.
+impl FrameSink for Vec<Frame> {
+    fn frame(&mut self, frame: &Frame) -> io::Result<()> {
+        self.push(frame.clone());
+        Ok(())
+    }
+}
+
+/// Writes each frame to `<prefix><number>.ppm` (number zero-padded to 4 digits).
+#[derive(Debug)]
+pub struct PpmSink {
+    prefix: String,
+}
+
+impl PpmSink {
+    pub fn new(prefix: &str) -> Self {
+        PpmSink { prefix: prefix.to_string() }
+    }
+}
+
+impl FrameSink for PpmSink {
+    fn frame(&mut self, frame: &Frame) -> io::Result<()> {
+        let path = format!("{}{:04}.ppm", self.prefix, frame.number);
+        File::create(&path)
+            .and_then(|f| {
+                let mut out = io::BufWriter::new(f);
+                frame.image.write_ppm(&mut out)?;
+                out.flush()
+            })
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))
+    }
+}
+
+/// Writes frames as ANSI text. With `live`, each frame first moves the cursor home so
+/// a terminal shows an updating screen.
+pub struct AnsiSink<W: Write> {
+    out: W,
+    live: bool,
+}
+
+impl<W: Write> AnsiSink<W> {
+    pub fn new(out: W, live: bool) -> Self {
+        AnsiSink { out, live }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W: Write> FrameSink for AnsiSink<W> {
+    fn frame(&mut self, frame: &Frame) -> io::Result<()> {
+        if self.live {
+            write!(self.out, "\x1b[H")?;
+        }
+        writeln!(self.out, "[display] frame {} at cycle {}", frame.number, frame.cycle)?;
+        self.out.write_all(frame.ansi.as_bytes())?;
+        self.out.flush()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisplayConfig {
+    /// Cycles between vsyncs.
+    pub vsync_cycles: u64,
+    /// Hand every Nth frame to the sink (1 = every frame).
+    pub dump_every: u64,
+}
+
+impl Default for DisplayConfig {
+    fn default() -> Self {
+        DisplayConfig { vsync_cycles: 1000, dump_every: 1 }
+    }
+}
+
+/// Headless display with a 16x8 text mode and a 16x16 bitmap mode, both using a
+/// 16-entry palette. Programs fill VRAM through the ADDR/DATA registers; at every
+/// vsync (`vsync_cycles` apart) the screen is rendered and passed to the frame sink.
+///
+/// Attributes start as 0x0F (white on black), so plain text is visible without setup.
+#[derive(Debug)]
+pub struct Display {
+    config: DisplayConfig,
+    sink: Option<Box<dyn FrameSink>>,
+    sink_error: Option<String>,
+    vram: [u8; VRAM_SIZE],
+    palette: [[u8; 3]; 16],
+    ctrl: u8,
+    status: u8,
+    addr: u8,
+    pal_index: usize,
+    frames: u64,
+}
+
+impl Display {
+    pub fn new(config: DisplayConfig) -> Self {
+        let mut vram = [0; VRAM_SIZE];
+        vram[TEXT_COLS * TEXT_ROWS..].fill(0x0F);
+        Display {
+            config: DisplayConfig { vsync_cycles: config.vsync_cycles.max(1), dump_every: config.dump_every.max(1) },
+            sink: None,
+            sink_error: None,
+            vram,
+            palette: DEFAULT_PALETTE,
+            ctrl: 0,
+            status: 0,
+            addr: 0,
+            pal_index: 0,
+            frames: 0,
+        }
+    }
+
+    pub fn with_sink(sink: Box<dyn FrameSink>, config: DisplayConfig) -> Self {
+        Display { sink: Some(sink), ..Display::new(config) }
+    }
+
+    /// The last error the frame sink reported, if any.
+    pub fn sink_error(&self) -> Option<&str> {
+        self.sink_error.as_deref()
+    }
+
+    pub fn vram(&self) -> &[u8; VRAM_SIZE] {
+        &self.vram
+    }
+
+    /// Vsyncs so far.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    pub fn bitmap_mode(&self) -> bool {
+        self.ctrl & CTRL_BITMAP != 0
+    }
+
+    fn color(&self, index: u8) -> [u8; 3] {
+        self.palette[(index & 0x0F) as usize]
+    }
+
+    /// Render the current screen: 64x48 pixels in text mode, 16x16 in bitmap mode.
+    pub fn render(&self) -> Image {
+        if self.bitmap_mode() {
+            let rgb = self.vram.iter().flat_map(|p| self.color(*p)).collect();
+            return Image { width: BITMAP_SIZE, height: BITMAP_SIZE, rgb };
+        }
+        let (width, height) = (TEXT_COLS * CELL_W, TEXT_ROWS * CELL_H);
+        let mut rgb = vec![0; width * height * 3];
+        for y in 0..height {
+            for x in 0..width {
+                let cell = (y / CELL_H) * TEXT_COLS + x / CELL_W;
+                let attr = self.vram[TEXT_COLS * TEXT_ROWS + cell];
+                let (gx, gy) = (x % CELL_W, y % CELL_H);
+                let on = gx < 3 && gy < 5 && glyph(self.vram[cell])[gy] & (0b100 >> gx) != 0;
+                let c = self.color(if on { attr } else { attr >> 4 });
+                rgb[3 * (y * width + x)..3 * (y * width + x) + 3].copy_from_slice(&c);
+            }
+        }
+        Image { width, height, rgb }
+    }
+
+    /// Plain text of the text-mode screen, one line per row (trailing blanks trimmed).
+    pub fn text(&self) -> String {
+        let mut s = String::new();
+        for row in self.vram[..TEXT_COLS * TEXT_ROWS].chunks(TEXT_COLS) {
+            let line: String = row.iter().map(|&c| if c.is_ascii_graphic() { c as char } else { ' ' }).collect();
+            s.push_str(line.trim_end());
+            s.push('\n');
+        }
+        s
+    }
+
+    /// The screen with 24-bit ANSI colours: text cells as characters, bitmap pixels as
+    /// two coloured spaces.
+    pub fn to_ansi(&self) -> String {
+        let mut s = String::new();
+        let fg = |c: [u8; 3]| format!("\x1b[38;2;{};{};{}m", c[0], c[1], c[2]);
+        let bg = |c: [u8; 3]| format!("\x1b[48;2;{};{};{}m", c[0], c[1], c[2]);
+        if self.bitmap_mode() {
+            for row in self.vram.chunks(BITMAP_SIZE) {
+                for p in row {
+                    s.push_str(&bg(self.color(*p)));
+                    s.push_str("  ");
+                }
+                s.push_str("\x1b[0m\n");
+            }
+        } else {
+            for (r, row) in self.vram[..TEXT_COLS * TEXT_ROWS].chunks(TEXT_COLS).enumerate() {
+                for (col, &c) in row.iter().enumerate() {
+                    let attr = self.vram[TEXT_COLS * TEXT_ROWS + r * TEXT_COLS + col];
+                    s.push_str(&fg(self.color(attr)));
+                    s.push_str(&bg(self.color(attr >> 4)));
+                    s.push(if c.is_ascii_graphic() { c as char } else { ' ' });
+                }
+                s.push_str("\x1b[0m\n");
+            }
+        }
+        s
+    }
+}
+
+impl Device for Display {
+    fn describe(&self) -> String {
+        let mut s = format!("display ctrl={:02X} status={:02X} addr={:02X} frames={}", self.ctrl, self.status, self.addr, self.frames);
+        if let Some(e) = &self.sink_error {
+            s.push_str(&format!(" sink error: {}", e));
+        }
+        s
+    }
+
+    fn tick(&mut self, current_cycle: u64) {
+        if !current_cycle.is_multiple_of(self.config.vsync_cycles) {
+            return;
+        }
+        self.frames += 1;
+        self.status |= STATUS_VSYNC;
+        if self.frames.is_multiple_of(self.config.dump_every) && self.sink.is_some() {
+            let frame = Frame { number: self.frames, cycle: current_cycle, image: self.render(), ansi: self.to_ansi() };
+            if let Some(sink) = self.sink.as_mut() {
+                if let Err(e) = sink.frame(&frame) {
+                    self.sink_error = Some(e.to_string());
+                }
+            }
+        }
+    }
+
+    fn mmio_size(&self) -> usize {
+        DISPLAY_REGS
+    }
+
+    fn mmio_read(&mut self, offset: usize) -> u8 {
+        match offset {
+            DISPLAY_CTRL => self.ctrl,
+            DISPLAY_STATUS => std::mem::take(&mut self.status),
+            DISPLAY_ADDR => self.addr,
+            DISPLAY_DATA => {
+                let v = self.vram[self.addr as usize];
+                self.addr = self.addr.wrapping_add(1);
+                v
+            }
+            DISPLAY_PAL => self.pal_index as u8,
+            DISPLAY_PAL_R | DISPLAY_PAL_G | DISPLAY_PAL_B => self.palette[self.pal_index][offset - DISPLAY_PAL_R],
+            _ => 0,
+        }
+    }
+
+    fn mmio_write(&mut self, offset: usize, value: u8) {
+        match offset {
+            DISPLAY_CTRL => self.ctrl = value,
+            DISPLAY_ADDR => self.addr = value,
+            DISPLAY_DATA => {
+                self.vram[self.addr as usize] = value;
+                self.addr = self.addr.wrapping_add(1);
+            }
+            DISPLAY_PAL => self.pal_index = (value & 0x0F) as usize,
+            DISPLAY_PAL_R | DISPLAY_PAL_G | DISPLAY_PAL_B => self.palette[self.pal_index][offset - DISPLAY_PAL_R] = value,
+            _ => {}
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.ctrl & CTRL_VSYNC_IRQ != 0 && self.status & STATUS_VSYNC != 0
+    }
+
+    fn watches_fetches(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::cpu::CPU;
+
+    #[test]
+    fn text_mode_program_is_captured_at_vsync() {
+        // write "HI" at row 1, column 0 in yellow on blue, then spin until the first vsync
+        let src = r#"
+            LDI R0, 16
+            STORE R0, 0xE2      ; ADDR
+            LDI R0, 0x48        ; 'H'
+            STORE R0, 0xE3
+            LDI R0, 0x49        ; 'I'
+            STORE R0, 0xE3
+            LDI R0, 144         ; attribute of cell 16
+            STORE R0, 0xE2
+            LDI R0, 0x1E
+            STORE R0, 0xE3
+            wait:
+            LOAD R1, 0xE1       ; STATUS
+            JZ R1, wait
+            HLT
+        "#;
+        let frames = Rc::new(RefCell::new(Vec::new()));
+        let mut cpu = CPU::new();
+        let display = Display::with_sink(Box::new(frames.clone()), DisplayConfig { vsync_cycles: 100, dump_every: 1 });
+        cpu.attach_mapped_device(Box::new(display), DISPLAY_BASE).unwrap();
+        cpu.load(&assemble(src).unwrap(), 0);
+        cpu.run();
+
+        let frames = frames.borrow();
+        assert_eq!(frames.len(), 1);
+        assert_eq!((frames[0].number, frames[0].cycle), (1, 100));
+        let img = &frames[0].image;
+        assert_eq!((img.width, img.height), (64, 48));
+        // top-left pixel of 'H' is set (yellow), the gap column is background (blue)
+        assert_eq!(img.pixel(0, 6), [0xFF, 0xFF, 0x55]);
+        assert_eq!(img.pixel(1, 6), [0x00, 0x00, 0xAA]);
+        assert!(frames[0].ansi.contains("\x1b[38;2;255;255;85m\x1b[48;2;0;0;170mH"));
+
+        let mut ppm = Vec::new();
+        img.write_ppm(&mut ppm).unwrap();
+        assert!(ppm.starts_with(b"P6\n64 48\n255\n"));
+        assert_eq!(ppm.len(), 13 + 64 * 48 * 3);
+    }
+
+    #[test]
+    fn bitmap_mode_uses_palette() {
+        let mut d = Display::new(DisplayConfig::default());
+        d.mmio_write(DISPLAY_CTRL, CTRL_BITMAP);
+        d.mmio_write(DISPLAY_PAL, 3);
+        d.mmio_write(DISPLAY_PAL_R, 0x12);
+        d.mmio_write(DISPLAY_PAL_G, 0x34);
+        d.mmio_write(DISPLAY_PAL_B, 0x56);
+        d.mmio_write(DISPLAY_ADDR, 17);
+        d.mmio_write(DISPLAY_DATA, 3);
+        let img = d.render();
+        assert_eq!((img.width, img.height), (16, 16));
+        assert_eq!(img.pixel(1, 1), [0x12, 0x34, 0x56]);
+        assert_eq!(img.pixel(0, 0), [0, 0, 0]);
+        assert_eq!(d.mmio_read(DISPLAY_ADDR), 18);
+
+        d.tick(1000);
+        assert!(!d.irq_pending());
+        d.mmio_write(DISPLAY_CTRL, CTRL_BITMAP | CTRL_VSYNC_IRQ);
+        assert!(d.irq_pending());
+        assert_eq!(d.mmio_read(DISPLAY_STATUS), STATUS_VSYNC);
+        assert!(!d.irq_pending());
+    }
+
+    #[test]
+    fn sink_errors_are_kept_for_describe() {
+        let prefix = std::env::temp_dir().join("toy_cpu_missing_dir").join("frame");
+        let mut d = Display::with_sink(Box::new(PpmSink::new(prefix.to_str().unwrap())), DisplayConfig { vsync_cycles: 10, dump_every: 1 });
+        assert_eq!(d.sink_error(), None);
+        d.tick(10);
+        let err = d.sink_error().unwrap().to_string();
+        assert!(err.contains("frame0001.ppm"));
+        assert!(d.describe().ends_with(&format!("sink error: {}", err)));
+    }
+}
@@ -0,0 +1,228 @@
+// src/dma.rs
+use crate::device::Device;
+use crate::memory::Memory;
+
+/// Default base address of the DMA controller's register window (see `CPU::attach_mapped_device`).
+pub const DMA_BASE: usize = 0xF0;
+
+/// Register offsets within the DMA controller's window.
+pub const DMA_SRC: usize = 0;
+pub const DMA_DST: usize = 1;
+pub const DMA_LEN: usize = 2;
+/// Control / mode register (see the `CTRL_*` bits).
+pub const DMA_CTRL: usize = 3;
+/// Status register (see the `STATUS_*` bits). Reading it acknowledges completion.
+pub const DMA_STATUS: usize = 4;
+/// Number of registers.
+pub const DMA_REGS: usize = 5;
+
+/// Writing CTRL with this bit set starts a transfer (ignored while busy).
+pub const CTRL_START: u8 = 0x01;
+/// Raise the interrupt line on completion.
+pub const CTRL_IRQ: u8 = 0x02;
+/// Burst mode: take the bus every cycle until done. Without it the controller
+/// steals every other cycle so the CPU keeps making progress.
+pub const CTRL_BURST: u8 = 0x04;
+
+pub const STATUS_BUSY: u8 = 0x01;
+pub const STATUS_DONE: u8 = 0x02;
+
+/// Memory-to-memory DMA controller. Attach it with `CPU::attach_mapped_device`,
+/// program SRC/DST/LEN and write CTRL with `CTRL_START`. Each byte is copied in one
+/// bus cycle stolen from the CPU through `Device::bus_request`, so CPU accesses
+/// during a transfer wait (see `CPU::bus_stats` and `TraceRecord::stolen`).
+///
+/// A LEN of 0 completes immediately. Writes into read-only regions are dropped.
+#[derive(Debug, Default)]
+pub struct DmaController {
+    src: u8,
+    dst: u8,
+    len: u8,
+    ctrl: u8,
+    busy: bool,
+    done: bool,
+    irq: bool,
+    copied: usize,
+    last_grant: Option<u64>,
+    stolen: u64,
+    transfers: u64,
+}
+
+impl DmaController {
+    pub fn new() -> Self {
+        DmaController::default()
+    }
+
+    pub fn busy(&self) -> bool {
+        self.busy
+    }
+
+    /// Total bus cycles taken from the CPU.
+    pub fn stolen_cycles(&self) -> u64 {
+        self.stolen
+    }
+
+    /// Number of completed transfers.
+    pub fn transfers(&self) -> u64 {
+        self.transfers
+    }
+
+    fn status(&self) -> u8 {
+        (self.busy as u8 * STATUS_BUSY) | (self.done as u8 * STATUS_DONE)
+    }
+
+    fn complete(&mut self) {
+        self.busy = false;
+        self.done = true;
+        self.irq = self.ctrl & CTRL_IRQ != 0;
+        self.transfers += 1;
+    }
+}
+
+impl Device for DmaController {
+    fn describe(&self) -> String {
+        format!(
+            "dma src={:02X} dst={:02X} len={} ctrl={:02X} busy={} copied={} transfers={} stolen={}",
+            self.src, self.dst, self.len, self.ctrl, self.busy, self.copied, self.transfers, self.stolen
+        )
+    }
+
+    fn tick(&mut self, _current_cycle: u64) {}
+
+    /// Every cycle of a transfer, since each may request the bus.
+    fn next_event(&self, now: u64) -> Option<u64> {
+        self.busy.then_some(now + 1)
+    }
+
+    fn bus_request(&mut self, current_cycle: u64) -> bool {
+        self.busy && (self.ctrl & CTRL_BURST != 0 || self.last_grant != Some(current_cycle.saturating_sub(1)))
+    }
+
+    fn bus_grant(&mut self, current_cycle: u64, mem: &mut Memory) {
+        let src = self.src as usize + self.copied;
+        let dst = self.dst as usize + self.copied;
+        if !mem.is_read_only(dst) {
+            mem.write(dst, mem.read(src));
+        }
+        self.copied += 1;
+        self.stolen += 1;
+        self.last_grant = Some(current_cycle);
+        if self.copied == self.len as usize {
+            self.complete();
+        }
+    }
+
+    fn mmio_size(&self) -> usize {
+        DMA_REGS
+    }
+
+    fn mmio_read(&mut self, offset: usize) -> u8 {
+        match offset {
+            DMA_SRC => self.src,
+            DMA_DST => self.dst,
+            DMA_LEN => self.len,
+            DMA_CTRL => self.ctrl,
+            DMA_STATUS => {
+                let status = self.status();
+                self.done = false;
+                self.irq = false;
+                status
+            }
+            _ => 0,
+        }
+    }
+
+    fn mmio_write(&mut self, offset: usize, value: u8) {
+        if self.busy && offset != DMA_STATUS {
+            return;
+        }
+        match offset {
+            DMA_SRC => self.src = value,
+            DMA_DST => self.dst = value,
+            DMA_LEN => self.len = value,
+            DMA_CTRL => {
+                self.ctrl = value & !CTRL_START;
+                if value & CTRL_START != 0 {
+                    self.busy = true;
+                    self.done = false;
+                    self.irq = false;
+                    self.copied = 0;
+                    self.last_grant = None;
+                    if self.len == 0 {
+                        self.complete();
+                    }
+                }
+            }
+            // any write acknowledges completion
+            DMA_STATUS => {
+                self.done = false;
+                self.irq = false;
+            }
+            _ => {}
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq
+    }
+
+    fn watches_fetches(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::cpu::{ExecMode, CPU};
+    use crate::trace::TextTracer;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Copy 0x80..0x88 to 0x90 with the controller at 0xF0, then wait for the
+    /// interrupt handler at 0xD0 to acknowledge it and set R3.
+    const COPY: &str = r#"
+        LDI R0, 0x80
+        STORE R0, 0xF0
+        LDI R0, 0x90
+        STORE R0, 0xF1
+        LDI R0, 8
+        STORE R0, 0xF2
+        EI
+        LDI R0, 3       ; START | IRQ
+        STORE R0, 0xF3
+        wait:
+        JZ R3, wait
+        HLT
+    "#;
+
+    const HANDLER: &str = "LOAD R2, 0xF4\nLDI R3, 1\nRETI";
+
+    #[test]
+    fn copies_with_stolen_cycles_and_interrupt() {
+        for mode in [ExecMode::Instruction, ExecMode::MicroCycle] {
+            let text = Rc::new(RefCell::new(TextTracer::new(Vec::new())));
+            let mut cpu = CPU::new();
+            cpu.mode = mode;
+            cpu.attach_mapped_device(Box::new(DmaController::new()), 0xF0).unwrap();
+            cpu.attach_tracer(Box::new(text.clone()));
+            for i in 0..8 {
+                cpu.mem.write(0x80 + i, i as u8 + 1);
+            }
+            cpu.mem.write_bytes(0xD0, &assemble(HANDLER).unwrap());
+            cpu.load(&assemble(COPY).unwrap(), 0);
+            cpu.run();
+
+            assert_eq!((0..8).map(|i| cpu.mem.read(0x90 + i)).collect::<Vec<u8>>(), vec![1, 2, 3, 4, 5, 6, 7, 8]);
+            assert_eq!(cpu.regs[2], STATUS_DONE);
+            assert_eq!(cpu.bus_stats.device_cycles, 8);
+            drop(cpu.take_tracers());
+            let text = Rc::try_unwrap(text).ok().unwrap().into_inner().into_inner();
+            let text = String::from_utf8(text).unwrap();
+            assert!(text.contains("STOLEN="), "{}", text);
+            assert!(text.contains("D0: 32 F4 LOAD R2, 0xF4"), "{}", text);
+        }
+        assert!(CPU::new().attach_mapped_device(Box::new(DmaController::new()), 0xFE).is_err());
+    }
+}
@@ -0,0 +1,323 @@
+// src/gpio.rs
+use crate::device::Device;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt::{Debug, Formatter};
+use std::io::Write;
+use std::rc::Rc;
+
+/// Default base address of the GPIO's register window (see `CPU::attach_mapped_device`).
+pub const GPIO_BASE: usize = 0xB6;
+
+/// Register offsets within the GPIO's window. Bit n of each register is pin n.
+/// DIR: 1 = output (drives an LED), 0 = input (reads a switch).
+pub const GPIO_DIR: usize = 0;
+/// OUT: levels driven on output pins.
+pub const GPIO_OUT: usize = 1;
+/// IN: current level of every pin (read-only).
+pub const GPIO_IN: usize = 2;
+/// RISE / FALL: pins whose rising / falling edges latch a flag and interrupt.
+pub const GPIO_RISE: usize = 3;
+pub const GPIO_FALL: usize = 4;
+/// FLAGS: latched edges. Reading returns and clears them.
+pub const GPIO_FLAGS: usize = 5;
+/// Number of registers.
+pub const GPIO_REGS: usize = 6;
+
+pub const GPIO_PINS: usize = 8;
+
+/// A pin changing level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PinChange {
+    pub cycle: u64,
+    pub pin: u8,
+    pub level: bool,
+    /// Driven by the program (an output) rather than a switch.
+    pub output: bool,
+}
+
+/// Shared pin-change log; keep a clone to inspect it after the GPIO is attached.
+pub type PinLog = Rc<RefCell<Vec<PinChange>>>;
+
+/// Scripted switch flips: `<cycle> <pin> <0|1>` per line, `#` starts a comment.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SwitchScript {
+    /// (cycle, pin, level) in cycle order.
+    events: VecDeque<(u64, u8, bool)>,
+}
+
+impl SwitchScript {
+    pub fn new(mut events: Vec<(u64, u8, bool)>) -> Self {
+        events.sort_by_key(|(cycle, _, _)| *cycle);
+        SwitchScript { events: events.into() }
+    }
+
+    pub fn parse(src: &str) -> Result<Self, String> {
+        let mut events = Vec::new();
+        for (lineno, raw) in src.lines().enumerate() {
+            let line = raw.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let err = |msg: &str| format!("line {}: {}", lineno + 1, msg);
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [cycle, pin, level] = fields[..] else {
+                return Err(err("expected <cycle> <pin> <0|1>"));
+            };
+            let cycle: u64 = cycle.parse().map_err(|_| err("bad cycle"))?;
+            let pin: u8 = pin.parse().ok().filter(|&p| (p as usize) < GPIO_PINS).ok_or_else(|| err("bad pin"))?;
+            let level = match level {
+                "0" => false,
+                "1" => true,
+                _ => return Err(err("level must be 0 or 1")),
+            };
+            events.push((cycle, pin, level));
+        }
+        Ok(SwitchScript::new(events))
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let src = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        SwitchScript::parse(&src)
+    }
+}
+
+/// Eight general-purpose I/O pins wired to a virtual panel: output pins light LEDs,
+/// input pins read switches. Every level change is logged with its cycle, and with a
+/// panel writer attached the LED row is redrawn in place whenever it changes.
+pub struct Gpio {
+    dir: u8,
+    out: u8,
+    switches: u8,
+    rise: u8,
+    fall: u8,
+    flags: u8,
+    levels: u8,
+    script: SwitchScript,
+    log: PinLog,
+    panel: Option<Box<dyn Write>>,
+    cycle: u64,
+}
+
+impl Debug for Gpio {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "Gpio {{ dir: {:02X}, levels: {:02X}, flags: {:02X} }}", self.dir, self.levels, self.flags)
+    }
+}
+
+impl Gpio {
+    pub fn new(script: SwitchScript) -> Self {
+        Gpio {
+            dir: 0,
+            out: 0,
+            switches: 0,
+            rise: 0,
+            fall: 0,
+            flags: 0,
+            levels: 0,
+            script,
+            log: PinLog::default(),
+            panel: None,
+            cycle: 0,
+        }
+    }
+
+    /// Also draw the panel to `out` (e.g. stdout) on every change.
+    pub fn with_panel(script: SwitchScript, out: Box<dyn Write>) -> Self {
+        Gpio { panel: Some(out), ..Gpio::new(script) }
+    }
+
+    pub fn log(&self) -> PinLog {
+        self.log.clone()
+    }
+
+    pub fn levels(&self) -> u8 {
+        self.levels
+    }
+
+    /// Flip a switch now (it only shows on IN while the pin is an input).
+    pub fn set_switch(&mut self, pin: u8, level: bool) {
+        let bit = 1 << (pin as usize % GPIO_PINS);
+        self.switches = if level { self.switches | bit } else { self.switches & !bit };
+        self.update();
+    }
+
+    /// The panel, pin 7 first: `*`/`.` for a lit/dark LED, `1`/`0` for a switch.
+    pub fn panel_text(&self) -> String {
+        self.row(["*", ".", "1", "0"])
+    }
+
+    /// One symbol per pin, pin 7 first: lit LED, dark LED, switch on, switch off.
+    fn row(&self, symbols: [&str; 4]) -> String {
+        (0..GPIO_PINS)
+            .rev()
+            .map(|pin| match (self.dir >> pin & 1 != 0, self.levels >> pin & 1 != 0) {
+                (true, true) => symbols[0],
+                (true, false) => symbols[1],
+                (false, true) => symbols[2],
+                (false, false) => symbols[3],
+            })
+            .collect()
+    }
+
+    /// Recompute pin levels, logging changes and latching edges.
+    fn update(&mut self) {
+        let levels = (self.out & self.dir) | (self.switches & !self.dir);
+        let changed = levels ^ self.levels;
+        if changed == 0 {
+            return;
+        }
+        self.flags |= (changed & levels & self.rise) | (changed & !levels & self.fall);
+        for pin in (0..GPIO_PINS).filter(|p| changed >> p & 1 != 0) {
+            self.log.borrow_mut().push(PinChange {
+                cycle: self.cycle,
+                pin: pin as u8,
+                level: levels >> pin & 1 != 0,
+                output: self.dir >> pin & 1 != 0,
+            });
+        }
+        self.levels = levels;
+        if self.panel.is_none() || changed & self.dir == 0 {
+            return;
+        }
+        let row = self.row(["\x1b[1;31m\u{25CF}\x1b[0m", "\u{25CB}", "\u{25B2}", "\u{25BD}"]);
+        if let Some(out) = self.panel.as_mut() {
+            let _ = write!(out, "\r[gpio] {} @{:<10}", row, self.cycle);
+            let _ = out.flush();
+        }
+    }
+}
+
+impl Device for Gpio {
+    fn describe(&self) -> String {
+        format!(
+            "gpio [{}] dir={:02X} out={:02X} rise={:02X} fall={:02X} flags={:02X}",
+            self.panel_text(),
+            self.dir,
+            self.out,
+            self.rise,
+            self.fall,
+            self.flags
+        )
+    }
+
+    fn tick(&mut self, current_cycle: u64) {
+        self.cycle = current_cycle;
+        while let Some(&(at, pin, level)) = self.script.events.front() {
+            if at > current_cycle {
+                break;
+            }
+            self.script.events.pop_front();
+            self.set_switch(pin, level);
+        }
+    }
+
+    /// The next scripted switch flip.
+    fn next_event(&self, now: u64) -> Option<u64> {
+        self.script.events.front().map(|&(at, _, _)| at.max(now + 1))
+    }
+
+    fn mmio_size(&self) -> usize {
+        GPIO_REGS
+    }
+
+    fn mmio_read(&mut self, offset: usize) -> u8 {
+        match offset {
+            GPIO_DIR => self.dir,
+            GPIO_OUT => self.out,
+            GPIO_IN => self.levels,
+            GPIO_RISE => self.rise,
+            GPIO_FALL => self.fall,
+            GPIO_FLAGS => std::mem::take(&mut self.flags),
+            _ => 0,
+        }
+    }
+
+    fn mmio_write(&mut self, offset: usize, value: u8) {
+        match offset {
+            GPIO_DIR => self.dir = value,
+            GPIO_OUT => self.out = value,
+            GPIO_RISE => self.rise = value,
+            GPIO_FALL => self.fall = value,
+            _ => return,
+        }
+        self.update();
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.flags != 0
+    }
+
+    fn watches_fetches(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::cpu::CPU;
+
+    #[test]
+    fn switch_edges_interrupt_and_drive_leds() {
+        // pins 4-7 are LEDs; each rising edge on switch 0 adds 16 to OUT in the handler
+        let main = r#"
+            LDI R0, 0xF0
+            STORE R0, 0xB6      ; DIR
+            LDI R0, 1
+            STORE R0, 0xB9      ; RISE = pin 0
+            LDI R1, 16
+            EI
+            wait:
+            LOAD R0, 0xB7       ; OUT
+            SUB R0, R3
+            JZ R0, done
+            JMP wait
+            done:
+            HLT
+        "#;
+        let handler = "LOAD R2, 0xBB\nLOAD R0, 0xB7\nADD R0, R1\nSTORE R0, 0xB7\nRETI";
+        let script = SwitchScript::parse("# bounce\n100 0 1\n150 0 0\n200 0 1\n250 1 1\n").unwrap();
+        let gpio = Gpio::new(script);
+        let log = gpio.log();
+        let mut cpu = CPU::new();
+        cpu.attach_mapped_device(Box::new(gpio), GPIO_BASE).unwrap();
+        cpu.mem.write_bytes(0xD0, &assemble(handler).unwrap());
+        cpu.load(&assemble(main).unwrap(), 0);
+        cpu.regs[3] = 0x20;
+        cpu.run();
+
+        assert_eq!(cpu.regs[2], 1);
+        let log = log.borrow();
+        let switches: Vec<(u64, u8, bool)> = log.iter().filter(|c| !c.output).map(|c| (c.cycle, c.pin, c.level)).collect();
+        assert_eq!(switches[..3], [(100, 0, true), (150, 0, false), (200, 0, true)]);
+        let leds: Vec<&PinChange> = log.iter().filter(|c| c.output).collect();
+        assert_eq!(leds.iter().map(|c| (c.pin, c.level)).collect::<Vec<_>>(), vec![(4, true), (4, false), (5, true)]);
+        assert!(leds[0].cycle > 100 && leds[2].cycle > 200);
+        assert!(SwitchScript::parse("10 8 1").is_err());
+    }
+
+    #[test]
+    fn panel_shows_leds_and_switches() {
+        let out: Rc<RefCell<Vec<u8>>> = Rc::default();
+        struct Shared(Rc<RefCell<Vec<u8>>>);
+        impl Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.borrow_mut().write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        let mut gpio = Gpio::with_panel(SwitchScript::default(), Box::new(Shared(out.clone())));
+        gpio.mmio_write(GPIO_DIR, 0x0F);
+        gpio.mmio_write(GPIO_OUT, 0x05);
+        gpio.set_switch(7, true);
+        assert_eq!(gpio.panel_text(), "1000.*.*");
+        assert_eq!(gpio.mmio_read(GPIO_IN), 0x85);
+        assert_eq!(gpio.mmio_read(GPIO_FLAGS), 0);
+        let drawn = String::from_utf8(out.borrow().clone()).unwrap();
+        assert_eq!(drawn.matches("\r[gpio]").count(), 1);
+    }
+}
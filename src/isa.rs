@@ -0,0 +1,238 @@
+// src/isa.rs
+use std::fmt;
+
+/// A decoded toy-ISA instruction.
+///
+/// Encoding (first byte is the opcode, register numbers live in the low two bits):
+/// - `0x10|r imm`  LDI r, imm
+/// - `0x20|d s`    ADD d, s
+/// - `0x24|d s`    SUB d, s
+/// - `0x30|d addr` LOAD d, addr
+/// - `0x34|s addr` STORE s, addr
+/// - `0x40 addr`   JMP addr
+/// - `0x44|r addr` JZ r, addr
+/// - `0x50|r`      OUT r
+/// - `0xFC`        EI    (enable interrupts)
+/// - `0xFD`        DI    (disable interrupts)
+/// - `0xFE`        RETI  (return from interrupt)
+/// - `0xFF`        HLT
+/// - `0x00`        NOP
+///
+/// Any other opcode decodes to `Unknown` and executes as a 1-cycle NOP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    Ldi { reg: usize, imm: u8 },
+    Add { dest: usize, src: usize },
+    Sub { dest: usize, src: usize },
+    Load { dest: usize, addr: u8 },
+    Store { src: usize, addr: u8 },
+    Jmp { addr: u8 },
+    Jz { reg: usize, addr: u8 },
+    Out { reg: usize },
+    Ei,
+    Di,
+    Reti,
+    Hlt,
+    Nop,
+    Unknown(u8),
+}
+
+/// Pipeline stage performed by one micro-step (one cycle) in micro-cycle mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MicroStage {
+    /// Read the opcode byte at PC.
+    Fetch,
+    /// Read the operand byte (if any) and decode.
+    Decode,
+    /// ALU operation, branch resolution or address calculation.
+    Execute,
+    /// Data memory read or write.
+    Memory,
+    /// Commit a loaded value to a register, or emit output.
+    Writeback,
+}
+
+/// Whether an opcode is followed by an operand byte.
+pub fn has_operand(opcode: u8) -> bool {
+    matches!(opcode & 0xFC, 0x10 | 0x20 | 0x24 | 0x30 | 0x34 | 0x44) || opcode == 0x40
+}
+
+/// Decode an opcode and its operand byte. `operand` is ignored for 1-byte instructions.
+pub fn decode(opcode: u8, operand: u8) -> Instruction {
+    let r = (opcode & 0x03) as usize;
+    match opcode & 0xFC {
+        0x10 => Instruction::Ldi { reg: r, imm: operand },
+        0x20 => Instruction::Add { dest: r, src: (operand & 0x03) as usize },
+        0x24 => Instruction::Sub { dest: r, src: (operand & 0x03) as usize },
+        0x30 => Instruction::Load { dest: r, addr: operand },
+        0x34 => Instruction::Store { src: r, addr: operand },
+        0x44 => Instruction::Jz { reg: r, addr: operand },
+        0x50 => Instruction::Out { reg: r },
+        _ => match opcode {
+            0x40 => Instruction::Jmp { addr: operand },
+            0xFC => Instruction::Ei,
+            0xFD => Instruction::Di,
+            0xFE => Instruction::Reti,
+            0xFF => Instruction::Hlt,
+            0x00 => Instruction::Nop,
+            op => Instruction::Unknown(op),
+        },
+    }
+}
+
+impl Instruction {
+    /// Encoded size in bytes.
+    pub fn size(&self) -> usize {
+        match self {
+            Instruction::Out { .. }
+            | Instruction::Ei
+            | Instruction::Di
+            | Instruction::Reti
+            | Instruction::Hlt
+            | Instruction::Nop
+            | Instruction::Unknown(_) => 1,
+            _ => 2,
+        }
+    }
+
+    /// Base cycle cost of the instruction.
+    pub fn cycles(&self) -> u64 {
+        match self {
+            Instruction::Ldi { .. } => 2,
+            Instruction::Add { .. } | Instruction::Sub { .. } => 3,
+            Instruction::Load { .. } | Instruction::Store { .. } => 4,
+            Instruction::Jmp { .. } | Instruction::Jz { .. } => 3,
+            Instruction::Out { .. } => 4,
+            Instruction::Reti => 2,
+            Instruction::Ei | Instruction::Di | Instruction::Hlt | Instruction::Nop | Instruction::Unknown(_) => 1,
+        }
+    }
+
+    /// Micro-steps executed for this instruction in micro-cycle mode, one per cycle.
+    /// The schedule length always equals `cycles()` so both execution modes agree
+    /// on timing. 1-cycle instructions decode and complete in their fetch cycle.
+    pub fn micro_stages(&self) -> &'static [MicroStage] {
+        use MicroStage::*;
+        match self {
+            Instruction::Ldi { .. } => &[Fetch, Decode],
+            Instruction::Add { .. } | Instruction::Sub { .. } => &[Fetch, Decode, Execute],
+            Instruction::Load { .. } => &[Fetch, Decode, Memory, Writeback],
+            Instruction::Store { .. } => &[Fetch, Decode, Execute, Memory],
+            Instruction::Jmp { .. } | Instruction::Jz { .. } => &[Fetch, Decode, Execute],
+            Instruction::Out { .. } => &[Fetch, Decode, Execute, Writeback],
+            Instruction::Reti => &[Fetch, Execute],
+            Instruction::Ei | Instruction::Di | Instruction::Hlt | Instruction::Nop | Instruction::Unknown(_) => &[Fetch],
+        }
+    }
+
+    /// Upper-case mnemonic, e.g. `"LDI"`.
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Instruction::Ldi { .. } => "LDI",
+            Instruction::Add { .. } => "ADD",
+            Instruction::Sub { .. } => "SUB",
+            Instruction::Load { .. } => "LOAD",
+            Instruction::Store { .. } => "STORE",
+            Instruction::Jmp { .. } => "JMP",
+            Instruction::Jz { .. } => "JZ",
+            Instruction::Out { .. } => "OUT",
+            Instruction::Ei => "EI",
+            Instruction::Di => "DI",
+            Instruction::Reti => "RETI",
+            Instruction::Hlt => "HLT",
+            Instruction::Nop => "NOP",
+            Instruction::Unknown(_) => "???",
+        }
+    }
+
+    /// Whether the instruction can redirect control flow (JMP, JZ, RETI).
+    pub fn is_branch(&self) -> bool {
+        matches!(self, Instruction::Jmp { .. } | Instruction::Jz { .. } | Instruction::Reti)
+    }
+
+    /// Encode back into bytes.
+    pub fn encode(&self) -> Vec<u8> {
+        match *self {
+            Instruction::Ldi { reg, imm } => vec![0x10 | reg as u8, imm],
+            Instruction::Add { dest, src } => vec![0x20 | dest as u8, src as u8],
+            Instruction::Sub { dest, src } => vec![0x24 | dest as u8, src as u8],
+            Instruction::Load { dest, addr } => vec![0x30 | dest as u8, addr],
+            Instruction::Store { src, addr } => vec![0x34 | src as u8, addr],
+            Instruction::Jmp { addr } => vec![0x40, addr],
+            Instruction::Jz { reg, addr } => vec![0x44 | reg as u8, addr],
+            Instruction::Out { reg } => vec![0x50 | reg as u8],
+            Instruction::Ei => vec![0xFC],
+            Instruction::Di => vec![0xFD],
+            Instruction::Reti => vec![0xFE],
+            Instruction::Hlt => vec![0xFF],
+            Instruction::Nop => vec![0x00],
+            Instruction::Unknown(op) => vec![op],
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Instruction::Ldi { reg, imm } => write!(f, "LDI R{}, {}", reg, imm),
+            Instruction::Add { dest, src } => write!(f, "ADD R{}, R{}", dest, src),
+            Instruction::Sub { dest, src } => write!(f, "SUB R{}, R{}", dest, src),
+            Instruction::Load { dest, addr } => write!(f, "LOAD R{}, 0x{:02X}", dest, addr),
+            Instruction::Store { src, addr } => write!(f, "STORE R{}, 0x{:02X}", src, addr),
+            Instruction::Jmp { addr } => write!(f, "JMP 0x{:02X}", addr),
+            Instruction::Jz { reg, addr } => write!(f, "JZ R{}, 0x{:02X}", reg, addr),
+            Instruction::Out { reg } => write!(f, "OUT R{}", reg),
+            Instruction::Ei => write!(f, "EI"),
+            Instruction::Di => write!(f, "DI"),
+            Instruction::Reti => write!(f, "RETI"),
+            Instruction::Hlt => write!(f, "HLT"),
+            Instruction::Nop => write!(f, "NOP"),
+            Instruction::Unknown(op) => write!(f, "??? 0x{:02X}", op),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_encode_roundtrip() {
+        let instrs = [
+            Instruction::Ldi { reg: 2, imm: 7 },
+            Instruction::Add { dest: 1, src: 3 },
+            Instruction::Sub { dest: 0, src: 1 },
+            Instruction::Load { dest: 3, addr: 0x80 },
+            Instruction::Store { src: 1, addr: 0x81 },
+            Instruction::Jmp { addr: 0x10 },
+            Instruction::Jz { reg: 1, addr: 0x20 },
+            Instruction::Out { reg: 2 },
+            Instruction::Ei,
+            Instruction::Di,
+            Instruction::Reti,
+            Instruction::Hlt,
+            Instruction::Nop,
+        ];
+        for i in instrs {
+            let bytes = i.encode();
+            assert_eq!(bytes.len(), i.size());
+            assert_eq!(has_operand(bytes[0]), i.size() == 2);
+            assert_eq!(decode(bytes[0], *bytes.get(1).unwrap_or(&0)), i);
+        }
+    }
+
+    #[test]
+    fn micro_schedule_matches_cycles() {
+        for op in 0..=255u8 {
+            let i = decode(op, 0);
+            assert_eq!(i.micro_stages().len() as u64, i.cycles(), "{}", i);
+        }
+    }
+
+    #[test]
+    fn disassembly_text() {
+        assert_eq!(decode(0x11, 0x0A).to_string(), "LDI R1, 10");
+        assert_eq!(decode(0x45, 0x08).to_string(), "JZ R1, 0x08");
+        assert_eq!(decode(0x99, 0).to_string(), "??? 0x99");
+    }
+}
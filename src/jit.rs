@@ -0,0 +1,524 @@
+// src/jit.rs
+use crate::blocks::Decoded;
+use crate::isa::Instruction;
+use crate::memory::{AccessKind, Memory};
+
+/// Block entries before a block is translated.
+pub const DEFAULT_THRESHOLD: u32 = 8;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct JitStats {
+    /// Blocks translated to host code, including retranslations after code was overwritten.
+    pub translated: u64,
+    /// Blocks with no translatable prefix (their first instruction needs the interpreter).
+    pub rejected: u64,
+    /// Native block runs and the instructions they executed.
+    pub native_runs: u64,
+    pub native_instructions: u64,
+}
+
+/// Translation of one predecoded block.
+#[derive(Debug)]
+struct Native {
+    /// `BlockCache` serial of the block it was translated from.
+    serial: u64,
+    /// `None` when not even the first instruction could be translated.
+    code: Option<ExecBuf>,
+    instructions: u64,
+    /// Total cycles, including the `wait` cycles from region wait states.
+    cycles: u64,
+    wait: u64,
+}
+
+/// What a native block run did, for the CPU's bookkeeping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct NativeRun {
+    pub next_pc: usize,
+    pub instructions: u64,
+    pub cycles: u64,
+    pub wait: u64,
+}
+
+/// Dynamic binary translator from the toy ISA to x86-64 host code (Linux only).
+///
+/// Hot basic blocks of the `BlockCache` are translated up to the first instruction that
+/// must go through the interpreter: OUT, EI, DI, RETI, HLT, and LOAD/STORE to device
+/// registers, read-only regions or the block's own bytes. Translated code updates
+/// registers, Z and memory exactly like the interpreter and clears the code marks of
+/// the bytes it stores to, so overwritten blocks are retranslated. `CPU::run` only enters
+/// a translated block when nothing can tell it apart from interpretation: no tracers,
+/// caches or branch predictor, no pending interrupt or device request, and no device
+/// due before the block's last cycle.
+#[derive(Debug)]
+pub struct Jit {
+    natives: Vec<Option<Native>>,
+    /// Entries per block start since it was last translated.
+    heat: Vec<u32>,
+    pub threshold: u32,
+    /// Device layout the translations assume (see `CPU::device_epoch`).
+    pub(crate) device_epoch: u64,
+    /// Memory size and `BlockCache::generation` the translations were made for.
+    mem_size: usize,
+    cache_generation: u64,
+    pub stats: JitStats,
+}
+
+impl Jit {
+    /// A translator, or an error on hosts other than x86-64 Linux.
+    pub fn new() -> Result<Jit, String> {
+        if !cfg!(all(target_arch = "x86_64", target_os = "linux")) {
+            return Err("the JIT needs an x86-64 Linux host".to_string());
+        }
+        Ok(Jit {
+            natives: Vec::new(),
+            heat: Vec::new(),
+            threshold: DEFAULT_THRESHOLD,
+            device_epoch: 0,
+            mem_size: 0,
+            cache_generation: 0,
+            stats: JitStats::default(),
+        })
+    }
+
+    /// Drop every translation.
+    pub fn flush(&mut self) {
+        self.natives.clear();
+        self.heat.clear();
+    }
+
+    /// Number of blocks with host code.
+    pub fn translated_blocks(&self) -> usize {
+        self.natives.iter().flatten().filter(|n| n.code.is_some()).count()
+    }
+
+    /// Run the translation of `block` (as returned by `BlockCache::block`, with the cache's
+    /// generation and the block's serial), translating it first if it has become hot.
+    /// Translations made for another memory size or block cache are dropped first. `is_mmio` tells device register
+    /// addresses; the block may take at most `max_cycles` cycles (e.g. until a device is
+    /// due) and `max_instructions` instructions.
+    /// Returns `None` if the interpreter has to execute the next instruction instead.
+    pub(crate) fn run(
+        &mut self,
+        (generation, serial, block): (u64, u64, &[Decoded]),
+        mem: &mut Memory,
+        regs: &mut [u8; 4],
+        z: &mut bool,
+        is_mmio: impl Fn(usize) -> bool,
+        (max_cycles, max_instructions): (u64, u64),
+    ) -> Option<NativeRun> {
+        let pc = block[0].pc;
+        if pc >= mem.size() {
+            return None;
+        }
+        if self.mem_size != mem.size() || self.cache_generation != generation {
+            self.flush();
+            self.mem_size = mem.size();
+            self.cache_generation = generation;
+            self.natives.resize_with(mem.size(), || None);
+            self.heat.resize(mem.size(), 0);
+        }
+        if self.natives[pc].as_ref().is_none_or(|n| n.serial != serial) {
+            self.heat[pc] += 1;
+            if self.heat[pc] < self.threshold {
+                return None;
+            }
+            self.heat[pc] = 0;
+            let native = translate(serial, block, mem, &is_mmio);
+            match native.code {
+                Some(_) => self.stats.translated += 1,
+                None => self.stats.rejected += 1,
+            }
+            self.natives[pc] = Some(native);
+        }
+        let native = self.natives[pc].as_ref()?;
+        let code = native.code.as_ref()?;
+        if native.cycles > max_cycles || native.instructions > max_instructions {
+            return None;
+        }
+        let mut state = [regs[0], regs[1], regs[2], regs[3], *z as u8];
+        let (mem_ptr, code_ptr) = mem.raw_parts();
+        // SAFETY: `native` was translated from this very block (same cache generation and
+        // serial) for a memory of this size, so every offset it uses is in bounds.
+        let next_pc = unsafe { code.call(&mut state, mem_ptr, code_ptr) };
+        regs.copy_from_slice(&state[..4]);
+        *z = state[4] != 0;
+        self.stats.native_runs += 1;
+        self.stats.native_instructions += native.instructions;
+        Some(NativeRun { next_pc, instructions: native.instructions, cycles: native.cycles, wait: native.wait })
+    }
+}
+
+/// Translate the longest prefix of `block` that needs no interpreter.
+fn translate(serial: u64, block: &[Decoded], mem: &Memory, is_mmio: &dyn Fn(usize) -> bool) -> Native {
+    let size = mem.size();
+    let own = |addr: usize| block.iter().any(|d| d.pc == addr || (d.operand.is_some() && d.operand_addr == addr));
+    let mut asm = Emitter::default();
+    let (mut instructions, mut cycles, mut wait) = (0, 0, 0);
+    let mut next = block[0].pc;
+    let mut ended = false;
+    for d in block {
+        let mut data_wait = 0;
+        match d.instr {
+            Instruction::Ldi { reg, imm } => asm.ldi(reg, imm),
+            Instruction::Add { dest, src } => asm.arith(0x02, dest, src),
+            Instruction::Sub { dest, src } => asm.arith(0x2A, dest, src),
+            Instruction::Load { dest, addr } if !is_mmio(addr as usize) => {
+                data_wait = mem.wait_states(addr as usize, AccessKind::Read);
+                asm.load(dest, addr as usize % size);
+            }
+            Instruction::Store { src, addr } if !is_mmio(addr as usize) && !mem.is_read_only(addr as usize) && !own(addr as usize % size) => {
+                data_wait = mem.wait_states(addr as usize, AccessKind::Write);
+                asm.store(src, addr as usize % size);
+            }
+            Instruction::Jmp { addr } => {
+                asm.jmp(addr as usize % size);
+                ended = true;
+            }
+            Instruction::Jz { reg, addr } => {
+                asm.jz(reg, addr as usize % size, d.next);
+                ended = true;
+            }
+            Instruction::Nop | Instruction::Unknown(_) => {}
+            _ => break,
+        }
+        instructions += 1;
+        cycles += d.instr.cycles() + d.fetch_wait + data_wait;
+        wait += d.fetch_wait + data_wait;
+        next = d.next;
+        if ended {
+            break;
+        }
+    }
+    if !ended {
+        asm.exit(next);
+    }
+    let code = if instructions == 0 { None } else { ExecBuf::new(&asm.code).ok() };
+    Native { serial, code, instructions, cycles, wait }
+}
+
+/// x86-64 encoder for the handful of instructions translations use. Generated code is
+/// `extern "sysv64" fn(state: *mut u8, mem: *mut u8, code: *mut bool) -> u64` with R0..R3
+/// at `state[0..4]` and Z at `state[4]`; it returns the next PC.
+#[derive(Debug, Default)]
+struct Emitter {
+    code: Vec<u8>,
+}
+
+const Z: u8 = 4;
+
+impl Emitter {
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn imm32(&mut self, value: usize) {
+        self.emit(&(value as u32).to_le_bytes());
+    }
+
+    /// `sete byte [rdi + Z]`
+    fn set_z(&mut self) {
+        self.emit(&[0x0F, 0x94, 0x47, Z]);
+    }
+
+    fn ldi(&mut self, reg: usize, imm: u8) {
+        // mov byte [rdi + reg], imm; mov byte [rdi + Z], imm == 0
+        self.emit(&[0xC6, 0x47, reg as u8, imm, 0xC6, 0x47, Z, (imm == 0) as u8]);
+    }
+
+    /// ADD (`op` 0x02) or SUB (0x2A): mov al, [rdi + dest]; op al, [rdi + src];
+    /// mov [rdi + dest], al; sete [rdi + Z]
+    fn arith(&mut self, op: u8, dest: usize, src: usize) {
+        self.emit(&[0x8A, 0x47, dest as u8, op, 0x47, src as u8, 0x88, 0x47, dest as u8]);
+        self.set_z();
+    }
+
+    fn load(&mut self, dest: usize, addr: usize) {
+        // mov al, [rsi + addr]; mov [rdi + dest], al; test al, al; sete [rdi + Z]
+        self.emit(&[0x8A, 0x86]);
+        self.imm32(addr);
+        self.emit(&[0x88, 0x47, dest as u8, 0x84, 0xC0]);
+        self.set_z();
+    }
+
+    fn store(&mut self, src: usize, addr: usize) {
+        // mov al, [rdi + src]; mov [rsi + addr], al; mov byte [rdx + addr], 0 (code mark)
+        self.emit(&[0x8A, 0x47, src as u8, 0x88, 0x86]);
+        self.imm32(addr);
+        self.emit(&[0xC6, 0x82]);
+        self.imm32(addr);
+        self.emit(&[0x00]);
+    }
+
+    fn jmp(&mut self, target: usize) {
+        self.exit(target);
+    }
+
+    fn jz(&mut self, reg: usize, target: usize, fallthrough: usize) {
+        // cmp byte [rdi + reg], 0; mov eax, fallthrough; mov ecx, target; cmove eax, ecx; ret
+        self.emit(&[0x80, 0x7F, reg as u8, 0x00, 0xB8]);
+        self.imm32(fallthrough);
+        self.emit(&[0xB9]);
+        self.imm32(target);
+        self.emit(&[0x0F, 0x44, 0xC1, 0xC3]);
+    }
+
+    /// mov eax, pc; ret
+    fn exit(&mut self, pc: usize) {
+        self.emit(&[0xB8]);
+        self.imm32(pc);
+        self.emit(&[0xC3]);
+    }
+}
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+mod exec {
+    use std::ffi::c_void;
+
+    const PROT_READ: i32 = 1;
+    const PROT_WRITE: i32 = 2;
+    const PROT_EXEC: i32 = 4;
+    const MAP_PRIVATE: i32 = 2;
+    const MAP_ANONYMOUS: i32 = 0x20;
+
+    extern "C" {
+        fn mmap(addr: *mut c_void, len: usize, prot: i32, flags: i32, fd: i32, offset: i64) -> *mut c_void;
+        fn mprotect(addr: *mut c_void, len: usize, prot: i32) -> i32;
+        fn munmap(addr: *mut c_void, len: usize) -> i32;
+    }
+
+    /// Host code in its own mapping, writable while it is filled and executable after.
+    #[derive(Debug)]
+    pub struct ExecBuf {
+        ptr: *mut c_void,
+        len: usize,
+    }
+
+    type Entry = extern "sysv64" fn(*mut u8, *mut u8, *mut bool) -> u64;
+
+    impl ExecBuf {
+        pub fn new(code: &[u8]) -> Result<ExecBuf, String> {
+            let len = code.len().max(1);
+            // SAFETY: a fresh private anonymous mapping, only touched through `ptr` below.
+            unsafe {
+                let ptr = mmap(std::ptr::null_mut(), len, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+                if ptr as isize == -1 {
+                    return Err("mmap failed".to_string());
+                }
+                std::ptr::copy_nonoverlapping(code.as_ptr(), ptr as *mut u8, code.len());
+                if mprotect(ptr, len, PROT_READ | PROT_EXEC) != 0 {
+                    munmap(ptr, len);
+                    return Err("mprotect failed".to_string());
+                }
+                Ok(ExecBuf { ptr, len })
+            }
+        }
+
+        /// Run the code on `state` (R0..R3, Z) with `mem` and its code marks.
+        ///
+        /// # Safety
+        ///
+        /// `mem` and `code` must point to at least as many bytes as the memory the code
+        /// was translated for; the code reads and writes them at fixed offsets unchecked.
+        pub unsafe fn call(&self, state: &mut [u8; 5], mem: *mut u8, code: *mut bool) -> usize {
+            // SAFETY: the buffer holds a complete function emitted by `Emitter` that only
+            // accesses `state[..5]` and offsets of `mem` and `code` the caller vouches for.
+            unsafe {
+                let entry: Entry = std::mem::transmute::<*mut c_void, Entry>(self.ptr);
+                entry(state.as_mut_ptr(), mem, code) as usize
+            }
+        }
+    }
+
+    impl Drop for ExecBuf {
+        fn drop(&mut self) {
+            // SAFETY: `ptr`/`len` came from `mmap` and are unmapped once.
+            unsafe {
+                munmap(self.ptr, self.len);
+            }
+        }
+    }
+}
+
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+mod exec {
+    /// Stand-in on hosts without a JIT; `Jit::new` fails there, so this is never built.
+    #[derive(Debug)]
+    pub struct ExecBuf;
+
+    impl ExecBuf {
+        pub fn new(_code: &[u8]) -> Result<ExecBuf, String> {
+            Err("the JIT needs an x86-64 Linux host".to_string())
+        }
+
+        /// # Safety
+        ///
+        /// Never callable: there is no `ExecBuf` on this platform.
+        pub unsafe fn call(&self, _state: &mut [u8; 5], _mem: *mut u8, _code: *mut bool) -> usize {
+            unreachable!("no host code on this platform")
+        }
+    }
+}
+
+use exec::ExecBuf;
+
+#[cfg(all(test, target_arch = "x86_64", target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::blocks::BlockCache;
+    use crate::cpu::{BusStats, CPU};
+    use crate::device::{Device, DeviceContext, TimerDevice, TIMER_BASE};
+    use crate::memory::Region;
+
+    /// Stops the CPU at a fixed cycle; not register-mapped, so programs cannot touch it.
+    #[derive(Debug)]
+    struct Deadline(u64);
+    impl Device for Deadline {
+        fn tick_with(&mut self, ctx: &mut DeviceContext<'_>) {
+            if ctx.cycle() >= self.0 {
+                ctx.request_halt();
+            }
+        }
+        fn next_event(&self, now: u64) -> Option<u64> {
+            Some(self.0.max(now + 1))
+        }
+    }
+
+    type Snapshot = ([u8; 4], usize, bool, u64, bool, Vec<u8>, BusStats, Vec<String>);
+
+    /// Run the machine `setup` builds with and without the JIT.
+    fn differential(setup: &dyn Fn(&mut CPU)) -> (Snapshot, Snapshot, JitStats) {
+        let run = |jit: bool| {
+            let mut cpu = CPU::new();
+            setup(&mut cpu);
+            if jit {
+                cpu.jit = Some(Jit::new().unwrap());
+            }
+            cpu.run();
+            let mem = (0..cpu.mem.size()).map(|a| cpu.mem.read(a)).collect();
+            let devices = cpu.device_info().into_iter().map(|d| d.description).collect();
+            let stats = cpu.jit.map(|j| j.stats).unwrap_or_default();
+            ((cpu.regs, cpu.pc, cpu.z, cpu.cycles, cpu.interrupts_enabled, mem, cpu.bus_stats, devices), stats)
+        };
+        let (interpreted, _) = run(false);
+        let (native, stats) = run(true);
+        (interpreted, native, stats)
+    }
+
+    #[test]
+    fn translated_programs_match_the_interpreter() {
+        let counting = r#"
+            LDI R1, 1
+            LDI R3, 200
+            loop:
+            LOAD R0, 0x80
+            ADD R0, R3
+            STORE R0, 0x80
+            SUB R2, R0
+            SUB R3, R1
+            JZ R3, done
+            JMP loop
+            done:
+            HLT
+        "#;
+        // rewrites the immediate of its own LDI every pass
+        let patching = r#"
+            LDI R1, 1
+            LDI R3, 50
+            patch:
+            LDI R0, 5
+            ADD R2, R0
+            LOAD R0, 0x05
+            ADD R0, R1
+            STORE R0, 0x05
+            SUB R3, R1
+            JZ R3, done
+            JMP patch
+            done:
+            HLT
+        "#;
+        // timer interrupts count at 0x90 while the main loop works through memory; the
+        // handler only uses R2
+        let interrupted = r#"
+            LDI R0, 0x80
+            STORE R0, 0xEA
+            LDI R0, 7
+            STORE R0, 0xE8
+            LDI R1, 1
+            EI
+            loop:
+            LOAD R0, 0x90
+            LDI R3, 30
+            SUB R3, R0
+            JZ R3, done
+            LOAD R3, 0x81
+            ADD R3, R1
+            STORE R3, 0x81
+            JMP loop
+            done:
+            HLT
+        "#;
+        let handler = "LDI R2, 1\nSTORE R2, 0xED\nLOAD R2, 0x90\nADD R2, R1\nSTORE R2, 0x90\nRETI";
+        let mut native_instructions = 0;
+        for (i, program) in [counting, patching, interrupted].into_iter().enumerate() {
+            let bytes = assemble(program).unwrap();
+            let setup = |cpu: &mut CPU| {
+                cpu.load(&bytes, 0);
+                if i == 1 {
+                    // slow ROM over the top of memory, which the program stores into
+                    cpu.mem.add_region(Region::new("slow", 0x00, 0x20, 2, 1));
+                    cpu.mem.add_region(Region::rom("rom", 0x80, 0x88, 1));
+                }
+                if i == 2 {
+                    cpu.attach_mapped_device(Box::new(TimerDevice::new()), TIMER_BASE).unwrap();
+                    cpu.mem.write_bytes(0xD0, &assemble(handler).unwrap());
+                }
+            };
+            let (interpreted, native, stats) = differential(&setup);
+            assert_eq!(interpreted, native, "program {}", i);
+            assert!(stats.translated > 0, "program {}: {:?}", i, stats);
+            native_instructions += stats.native_instructions;
+        }
+        assert!(native_instructions > 1000, "{}", native_instructions);
+    }
+
+    #[test]
+    fn translations_are_dropped_with_their_block_cache() {
+        let mut cpu = CPU::new();
+        cpu.jit = Some(Jit { threshold: 1, ..Jit::new().unwrap() });
+        cpu.load(&assemble("LDI R0, 1\nADD R1, R0\nJMP 0").unwrap(), 0);
+        cpu.run_for_instructions(30);
+        assert!(cpu.jit.as_ref().unwrap().stats.native_runs > 0);
+        // the new cache numbers its first block 1 again, like the translated one
+        cpu.block_cache = Some(BlockCache::new());
+        cpu.load(&assemble("LDI R0, 1\nADD R2, R0\nJMP 0").unwrap(), 0);
+        cpu.regs = [0; 4];
+        cpu.run_for_instructions(30);
+        assert_eq!(cpu.regs, [1, 0, 10, 0]);
+    }
+
+    #[test]
+    fn random_programs_match_the_interpreter() {
+        let mut seed = 0x2545_F491_4F6C_DD1Du64;
+        let mut next = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        };
+        let mut translated = 0;
+        for case in 0..300 {
+            // anything but OUT, which would print
+            let image: Vec<u8> = (0..256).map(|_| next() as u8).map(|b| if b & 0xFC == 0x50 { 0 } else { b }).collect();
+            let setup = |cpu: &mut CPU| {
+                cpu.mem.write_bytes(0, &image);
+                cpu.attach_mapped_device(Box::new(TimerDevice::new()), TIMER_BASE).unwrap();
+                cpu.attach_device(Box::new(Deadline(3000)));
+            };
+            let (interpreted, native, stats) = differential(&setup);
+            assert_eq!(interpreted, native, "case {}", case);
+            translated += stats.translated;
+        }
+        assert!(translated > 100, "{}", translated);
+    }
+}
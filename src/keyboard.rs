@@ -0,0 +1,350 @@
+// src/keyboard.rs
+use crate::device::Device;
+use crate::uart::spawn_reader;
+use std::collections::VecDeque;
+use std::fmt::{Debug, Formatter};
+use std::io;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::Receiver;
+
+/// Default base address of the keyboard's register window (see `CPU::attach_mapped_device`).
+pub const KEYBOARD_BASE: usize = 0xF5;
+
+/// Register offsets within the keyboard's window.
+/// DATA: pops the next code from the buffer (0 when empty).
+pub const KEYBOARD_DATA: usize = 0;
+/// STATUS: number of buffered codes, plus `STATUS_OVERFLOW`. Reading clears the overflow bit.
+pub const KEYBOARD_STATUS: usize = 1;
+/// CTRL (see the `CTRL_*` bits).
+pub const KEYBOARD_CTRL: usize = 2;
+/// Number of registers.
+pub const KEYBOARD_REGS: usize = 3;
+
+/// Keys were lost because the buffer was full.
+pub const STATUS_OVERFLOW: u8 = 0x80;
+/// Interrupt while the buffer is not empty.
+pub const CTRL_IRQ: u8 = 0x01;
+/// Buffer PC set-1 scan codes (make, and break = make | 0x80) instead of ASCII.
+pub const CTRL_SCAN_CODES: u8 = 0x02;
+
+const SHIFT: u8 = 0x2A;
+
+/// PC scan code set 1 make codes, indexed by position in the row strings.
+const SCAN_ROWS: &[(u8, &str, &str)] = &[
+    (0x02, "1234567890-=", "!@#$%^&*()_+"),
+    (0x10, "qwertyuiop[]", "QWERTYUIOP{}"),
+    (0x1E, "asdfghjkl;'`", "ASDFGHJKL:\"~"),
+    (0x2C, "zxcvbnm,./", "ZXCVBNM<>?"),
+];
+
+/// Make code for an ASCII character and whether it needs shift.
+pub fn scan_code(c: u8) -> Option<(u8, bool)> {
+    match c {
+        0x1B => return Some((0x01, false)),
+        0x08 => return Some((0x0E, false)),
+        b'\t' => return Some((0x0F, false)),
+        b'\r' | b'\n' => return Some((0x1C, false)),
+        b' ' => return Some((0x39, false)),
+        b'\\' => return Some((0x2B, false)),
+        b'|' => return Some((0x2B, true)),
+        _ => {}
+    }
+    for (first, plain, shifted) in SCAN_ROWS {
+        if let Some(i) = plain.bytes().position(|b| b == c) {
+            return Some((first + i as u8, false));
+        }
+        if let Some(i) = shifted.bytes().position(|b| b == c) {
+            return Some((first + i as u8, true));
+        }
+    }
+    None
+}
+
+/// Where key presses come from. `poll` returns the ASCII keys pressed up to `cycle`.
+pub trait KeySource {
+    fn poll(&mut self, cycle: u64) -> Vec<u8>;
+}
+
+impl Debug for dyn KeySource {
+    fn fmt(&self, _: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        Ok(())
+    }
+}
+
+/// Deterministic key presses at fixed cycles, e.g. for tests and CI.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyScript {
+    /// (cycle, key) in cycle order.
+    events: VecDeque<(u64, u8)>,
+}
+
+impl KeyScript {
+    pub fn new(mut events: Vec<(u64, u8)>) -> Self {
+        events.sort_by_key(|(cycle, _)| *cycle);
+        KeyScript { events: events.into() }
+    }
+
+    /// Parse a script: one `<cycle> <key>` per line, `#` starts a comment. A key is a
+    /// single character, a quoted string (all its characters at that cycle), a name
+    /// (`enter`, `space`, `tab`, `esc`, `backspace`) or a number such as `0x41`.
+    pub fn parse(src: &str) -> Result<Self, String> {
+        let mut events = Vec::new();
+        for (lineno, raw) in src.lines().enumerate() {
+            let line = match raw.find('#') {
+                Some(i) if !raw[..i].contains('"') => &raw[..i],
+                _ => raw,
+            }
+            .trim();
+            if line.is_empty() {
+                continue;
+            }
+            let err = |msg: &str| format!("line {}: {}", lineno + 1, msg);
+            let (cycle, key) = line.split_once(char::is_whitespace).ok_or_else(|| err("expected <cycle> <key>"))?;
+            let cycle: u64 = cycle.parse().map_err(|_| err("bad cycle"))?;
+            let key = key.trim();
+            let keys: Vec<u8> = if key.len() >= 2 && key.starts_with('"') && key.ends_with('"') {
+                key[1..key.len() - 1].bytes().collect()
+            } else if key.len() == 1 {
+                vec![key.as_bytes()[0]]
+            } else {
+                let named = match key.to_lowercase().as_str() {
+                    "enter" => Some(b'\n'),
+                    "space" => Some(b' '),
+                    "tab" => Some(b'\t'),
+                    "esc" => Some(0x1B),
+                    "backspace" => Some(0x08),
+                    _ => None,
+                };
+                let number = match key.strip_prefix("0x") {
+                    Some(hex) => u8::from_str_radix(hex, 16).ok(),
+                    None => key.parse().ok(),
+                };
+                vec![named.or(number).ok_or_else(|| err(&format!("unknown key '{}'", key)))?]
+            };
+            events.extend(keys.into_iter().map(|k| (cycle, k)));
+        }
+        Ok(KeyScript::new(events))
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let src = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        KeyScript::parse(&src)
+    }
+
+    /// Events not delivered yet.
+    pub fn remaining(&self) -> usize {
+        self.events.len()
+    }
+}
+
+impl KeySource for KeyScript {
+    fn poll(&mut self, cycle: u64) -> Vec<u8> {
+        let mut keys = Vec::new();
+        while let Some(&(at, key)) = self.events.front() {
+            if at > cycle {
+                break;
+            }
+            keys.push(key);
+            self.events.pop_front();
+        }
+        keys
+    }
+}
+
+/// Live key presses from the controlling terminal. The terminal is switched to
+/// unbuffered, no-echo input (through `stty`) until this is dropped;
+/// command-line runs only: the REPL refuses it.
+pub struct TerminalKeys {
+    input: Receiver<u8>,
+    saved: Option<String>,
+}
+
+impl TerminalKeys {
+    pub fn open() -> Result<Self, String> {
+        let stty = |args: &[&str]| {
+            Command::new("stty").args(args).stdin(Stdio::inherit()).output().map_err(|e| format!("stty: {}", e))
+        };
+        let saved = stty(&["-g"])?;
+        let saved = saved.status.success().then(|| String::from_utf8_lossy(&saved.stdout).trim().to_string());
+        if saved.is_some() {
+            stty(&["-icanon", "-echo", "min", "1"])?;
+        }
+        Ok(TerminalKeys { input: spawn_reader(io::stdin()), saved })
+    }
+}
+
+impl KeySource for TerminalKeys {
+    fn poll(&mut self, _cycle: u64) -> Vec<u8> {
+        self.input.try_iter().collect()
+    }
+}
+
+impl Drop for TerminalKeys {
+    fn drop(&mut self) {
+        if let Some(saved) = &self.saved {
+            let _ = Command::new("stty").arg(saved).stdin(Stdio::inherit()).status();
+        }
+    }
+}
+
+/// Keyboard controller with a code buffer. Keys from the `KeySource` are buffered as
+/// ASCII, or as scan codes when `CTRL_SCAN_CODES` is set (a press and a release per
+/// key, wrapped in shift press/release for shifted characters). Since there is no
+/// bitwise instruction, STATUS is the buffer fill level so `JZ` can poll it.
+#[derive(Debug)]
+pub struct Keyboard {
+    source: Box<dyn KeySource>,
+    buffer: VecDeque<u8>,
+    capacity: usize,
+    ctrl: u8,
+    overflow: bool,
+}
+
+impl Keyboard {
+    pub fn new(source: Box<dyn KeySource>) -> Self {
+        Keyboard::with_capacity(source, 16)
+    }
+
+    pub fn with_capacity(source: Box<dyn KeySource>, capacity: usize) -> Self {
+        Keyboard { source, buffer: VecDeque::new(), capacity: capacity.clamp(1, 0x7F), ctrl: 0, overflow: false }
+    }
+
+    fn push(&mut self, code: u8) {
+        if self.buffer.len() < self.capacity {
+            self.buffer.push_back(code);
+        } else {
+            self.overflow = true;
+        }
+    }
+
+    fn press(&mut self, key: u8) {
+        if self.ctrl & CTRL_SCAN_CODES == 0 {
+            self.push(key);
+            return;
+        }
+        // characters without a scan code are dropped
+        if let Some((code, shifted)) = scan_code(key) {
+            let mut codes = vec![code, code | 0x80];
+            if shifted {
+                codes = vec![SHIFT, code, code | 0x80, SHIFT | 0x80];
+            }
+            for c in codes {
+                self.push(c);
+            }
+        }
+    }
+}
+
+impl Device for Keyboard {
+    fn describe(&self) -> String {
+        format!(
+            "keyboard ctrl={:02X} buffered={}/{} overflow={}",
+            self.ctrl,
+            self.buffer.len(),
+            self.capacity,
+            self.overflow
+        )
+    }
+
+    fn tick(&mut self, current_cycle: u64) {
+        for key in self.source.poll(current_cycle) {
+            self.press(key);
+        }
+    }
+
+    fn mmio_size(&self) -> usize {
+        KEYBOARD_REGS
+    }
+
+    fn mmio_read(&mut self, offset: usize) -> u8 {
+        match offset {
+            KEYBOARD_DATA => self.buffer.pop_front().unwrap_or(0),
+            KEYBOARD_STATUS => {
+                let s = self.buffer.len() as u8 | if self.overflow { STATUS_OVERFLOW } else { 0 };
+                self.overflow = false;
+                s
+            }
+            KEYBOARD_CTRL => self.ctrl,
+            _ => 0,
+        }
+    }
+
+    fn mmio_write(&mut self, offset: usize, value: u8) {
+        if offset == KEYBOARD_CTRL {
+            self.ctrl = value;
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.ctrl & CTRL_IRQ != 0 && !self.buffer.is_empty()
+    }
+
+    fn watches_fetches(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::cpu::CPU;
+
+    #[test]
+    fn scripted_keys_reach_the_program() {
+        // copy keys to 0x80.. until enter (10), one per poll; there is no indirect
+        // store, so the loop bumps the STORE's operand byte at 0x0B
+        let src = r#"
+            LDI R1, 1
+            LDI R3, 10
+            loop:
+            LOAD R0, 0xF6       ; STATUS
+            JZ R0, loop
+            LOAD R0, 0xF5       ; DATA
+            STORE R0, 0x80
+            LOAD R2, 0x0B
+            ADD R2, R1
+            STORE R2, 0x0B
+            SUB R0, R3
+            JZ R0, done
+            JMP loop
+            done:
+            HLT
+        "#;
+        let script = KeyScript::parse("# greeting\n50 h\n120 \"i!\"\n400 enter\n").unwrap();
+        assert_eq!(script.remaining(), 4);
+        let mut cpu = CPU::new();
+        cpu.attach_mapped_device(Box::new(Keyboard::new(Box::new(script))), KEYBOARD_BASE).unwrap();
+        cpu.load(&assemble(src).unwrap(), 0);
+        cpu.run();
+        let copied: Vec<u8> = (0x80..0x85).map(|a| cpu.mem.read(a)).collect();
+        assert_eq!(copied, b"hi!\n\0");
+        assert!(cpu.cycles > 400);
+        assert!(KeyScript::parse("10").is_err());
+        assert!(KeyScript::parse("10 nope").is_err());
+    }
+
+    #[test]
+    fn scan_codes_and_overflow() {
+        let script = KeyScript::new(vec![(1, b'a'), (1, b'A'), (2, b'x'), (2, b'y')]);
+        let mut kb = Keyboard::with_capacity(Box::new(script), 6);
+        kb.mmio_write(KEYBOARD_CTRL, CTRL_SCAN_CODES | CTRL_IRQ);
+        assert!(!kb.irq_pending());
+        kb.tick(1);
+        assert!(kb.irq_pending());
+        let codes: Vec<u8> = (0..6).map(|_| kb.mmio_read(KEYBOARD_DATA)).collect();
+        assert_eq!(codes, vec![0x1E, 0x9E, SHIFT, 0x1E, 0x9E, SHIFT | 0x80]);
+
+        kb.mmio_write(KEYBOARD_CTRL, 0);
+        for _ in 0..3 {
+            kb.press(b'z');
+        }
+        kb.tick(2);
+        assert_eq!(kb.mmio_read(KEYBOARD_STATUS), 5);
+        for _ in 0..2 {
+            kb.press(b'z');
+        }
+        assert_eq!(kb.mmio_read(KEYBOARD_STATUS), 6 | STATUS_OVERFLOW);
+        assert_eq!(kb.mmio_read(KEYBOARD_STATUS), 6);
+    }
+}
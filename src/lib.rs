@@ -3,3 +3,22 @@ pub mod device;
 pub mod memory;
 pub mod assembler;
 pub mod repl;
+pub mod isa;
+pub mod trace;
+pub mod profiler;
+pub mod coverage;
+pub mod pipeline;
+pub mod cache;
+pub mod branch;
+pub mod dma;
+pub mod uart;
+pub mod display;
+pub mod keyboard;
+pub mod storage;
+pub mod rtc;
+pub mod gpio;
+pub mod sound;
+pub mod watchdog;
+pub mod machine;
+pub mod blocks;
+pub mod jit;
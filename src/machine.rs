@@ -0,0 +1,519 @@
+// src/machine.rs
+use crate::assembler::assemble;
+use crate::cpu::{ExecMode, Scheduling, CPU, IRQ_VECTOR, NMI_VECTOR};
+use crate::device::{Device, TimerDevice, TIMER_BASE};
+use crate::display::{AnsiSink, Display, DisplayConfig, FrameSink, PpmSink, DISPLAY_BASE};
+use crate::dma::{DmaController, DMA_BASE};
+use crate::gpio::{Gpio, SwitchScript, GPIO_BASE};
+use crate::keyboard::{KeyScript, KeySource, Keyboard, TerminalKeys, KEYBOARD_BASE};
+use crate::memory::{Memory, Region, MAX_MEMORY};
+use crate::rtc::{Rtc, RtcConfig, RTC_BASE};
+use crate::sound::{SampleSink, SoundConfig, SoundGenerator, WavSink, SOUND_BASE};
+use crate::storage::{BlockDevice, StorageConfig, STORAGE_BASE};
+use crate::uart::{self, Uart, UartConfig, UART_BASE};
+use crate::watchdog::{Watchdog, WatchdogAction, WatchdogConfig, WATCHDOG_BASE};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Parse a decimal or `0x` hexadecimal number.
+pub fn parse_num(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// Parameters of one `device` line, handed to the registry's factory. Every parameter
+/// must be consumed by the factory; leftovers are reported as errors.
+#[derive(Debug)]
+pub struct DeviceParams {
+    pub kind: String,
+    /// Machine clock rate, for devices that convert cycles to time.
+    pub clock_hz: u64,
+    base_dir: PathBuf,
+    args: Vec<(String, Option<String>)>,
+    used: RefCell<HashSet<String>>,
+}
+
+impl DeviceParams {
+    pub fn new(kind: &str, args: Vec<(String, Option<String>)>) -> Self {
+        DeviceParams { kind: kind.to_string(), clock_hz: 1_000_000, base_dir: PathBuf::new(), args, used: RefCell::default() }
+    }
+
+    fn find(&self, key: &str) -> Option<&Option<String>> {
+        let found = self.args.iter().find(|(k, _)| k == key).map(|(_, v)| v);
+        if found.is_some() {
+            self.used.borrow_mut().insert(key.to_string());
+        }
+        found
+    }
+
+    /// Value of `key=value`.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.find(key).and_then(|v| v.as_deref())
+    }
+
+    /// Whether the bare flag `key` (or `key=on`) is present.
+    pub fn flag(&self, key: &str) -> bool {
+        matches!(self.find(key), Some(None)) || matches!(self.get(key), Some("on" | "1" | "true"))
+    }
+
+    pub fn num(&self, key: &str, default: u64) -> Result<u64, String> {
+        match self.get(key) {
+            Some(v) => parse_num(v).ok_or_else(|| format!("{}: bad number '{}' for {}", self.kind, v, key)),
+            None => Ok(default),
+        }
+    }
+
+    /// `num` for a narrower register or size: values that don't fit in `T` are bad
+    /// numbers rather than being truncated.
+    pub fn num_as<T: TryFrom<u64>>(&self, key: &str, default: u64) -> Result<T, String> {
+        let n = self.num(key, default)?;
+        T::try_from(n).map_err(|_| format!("{}: bad number '{}' for {}", self.kind, self.get(key).unwrap_or_default(), key))
+    }
+
+    /// `key=path`, relative to the machine description's directory.
+    pub fn path(&self, key: &str) -> Option<String> {
+        self.get(key).map(|p| self.base_dir.join(p).to_string_lossy().into_owned())
+    }
+
+    fn unused(&self) -> Vec<String> {
+        let used = self.used.borrow();
+        self.args.iter().filter(|(k, _)| !used.contains(k)).map(|(k, _)| k.clone()).collect()
+    }
+}
+
+pub type DeviceFactory = Box<dyn Fn(&DeviceParams) -> Result<Box<dyn Device>, String>>;
+
+struct Entry {
+    name: String,
+    default_base: Option<usize>,
+    factory: DeviceFactory,
+}
+
+/// Constructs devices by name for machine descriptions. `DeviceRegistry::default()`
+/// knows every device in this crate; `register` adds (or replaces) others.
+pub struct DeviceRegistry {
+    entries: Vec<Entry>,
+}
+
+impl Default for DeviceRegistry {
+    fn default() -> Self {
+        DeviceRegistry::builtin()
+    }
+}
+
+impl DeviceRegistry {
+    pub fn empty() -> Self {
+        DeviceRegistry { entries: Vec::new() }
+    }
+
+    /// Register a device kind. `default_base` is used when a `device` line has no
+    /// `at`; without either the device is attached without a register window.
+    pub fn register<F>(&mut self, name: &str, default_base: Option<usize>, factory: F)
+    where
+        F: Fn(&DeviceParams) -> Result<Box<dyn Device>, String> + 'static,
+    {
+        self.entries.retain(|e| e.name != name);
+        self.entries.push(Entry { name: name.to_string(), default_base, factory: Box::new(factory) });
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.entries.iter().map(|e| e.name.as_str()).collect()
+    }
+
+    /// Build a device and return it with its default base address.
+    pub fn create(&self, params: &DeviceParams) -> Result<(Box<dyn Device>, Option<usize>), String> {
+        let entry = self
+            .entries
+            .iter()
+            .find(|e| e.name == params.kind)
+            .ok_or_else(|| format!("unknown device '{}' (known: {})", params.kind, self.names().join(", ")))?;
+        let dev = (entry.factory)(params)?;
+        match params.unused().as_slice() {
+            [] => Ok((dev, entry.default_base)),
+            unused => Err(format!("{}: unknown parameter(s) {}", params.kind, unused.join(", "))),
+        }
+    }
+
+    pub fn builtin() -> Self {
+        let mut r = DeviceRegistry::empty();
+        r.register("timer", Some(TIMER_BASE), |p| {
+            let (prescale, reload) = (p.num_as("prescale", 0)?, p.num_as("reload", 0)?);
+            Ok(Box::new(if p.flag("periodic") { TimerDevice::periodic(prescale, reload) } else { TimerDevice::new() }))
+        });
+        r.register("dma", Some(DMA_BASE), |_| Ok(Box::new(DmaController::new())));
+        r.register("uart", Some(UART_BASE), |p| {
+            let d = UartConfig::default();
+            let config = UartConfig {
+                cycles_per_byte: p.num("cycles_per_byte", d.cycles_per_byte)?,
+                fifo_depth: p.num_as("fifo", d.fifo_depth as u64)?,
+            };
+            Ok(Box::new(Uart::new(uart::open_port(p.get("port").unwrap_or("stdout"))?.0, config)))
+        });
+        r.register("display", Some(DISPLAY_BASE), |p| {
+            let d = DisplayConfig::default();
+            let config = DisplayConfig { vsync_cycles: p.num("vsync", d.vsync_cycles)?, dump_every: p.num("dump_every", d.dump_every)? };
+            let sink: Option<Box<dyn FrameSink>> = match p.get("frames").unwrap_or("none") {
+                "none" => None,
+                "ansi" => Some(Box::new(AnsiSink::new(io::stdout(), false))),
+                "live" => Some(Box::new(AnsiSink::new(io::stdout(), true))),
+                other => match other.strip_prefix("ppm:") {
+                    Some(prefix) => Some(Box::new(PpmSink::new(&p.base_dir.join(prefix).to_string_lossy()))),
+                    None => return Err(format!("display: unknown frames target '{}'", other)),
+                },
+            };
+            Ok(Box::new(match sink {
+                Some(sink) => Display::with_sink(sink, config),
+                None => Display::new(config),
+            }))
+        });
+        r.register("keyboard", Some(KEYBOARD_BASE), |p| {
+            let source: Box<dyn KeySource> = match (p.get("keys"), p.path("keys")) {
+                (Some("tty"), _) => Box::new(TerminalKeys::open()?),
+                (_, Some(path)) => Box::new(KeyScript::from_file(&path)?),
+                _ => Box::new(KeyScript::default()),
+            };
+            Ok(Box::new(Keyboard::with_capacity(source, p.num_as("capacity", 16)?)))
+        });
+        r.register("disk", Some(STORAGE_BASE), |p| {
+            let d = StorageConfig::default();
+            let config = StorageConfig {
+                sector_size: p.num_as("sector_size", d.sector_size as u64)?,
+                seek_cycles: p.num("seek", d.seek_cycles)?,
+                cycles_per_byte: p.num("cycles_per_byte", d.cycles_per_byte)?,
+            };
+            match p.path("image") {
+                Some(path) => Ok(Box::new(BlockDevice::open(&path, config)?)),
+                None => Ok(Box::new(BlockDevice::in_memory(vec![0; p.num_as("size", 1024)?], config))),
+            }
+        });
+        r.register("rtc", Some(RTC_BASE), |p| {
+            let d = RtcConfig::default();
+            let config = RtcConfig { clock_hz: p.num("clock", p.clock_hz)?, epoch: p.num("epoch", d.epoch)?, wall_clock: p.flag("wall") };
+            Ok(Box::new(Rtc::new(config)))
+        });
+        r.register("gpio", Some(GPIO_BASE), |p| {
+            let script = match p.path("switches") {
+                Some(path) => SwitchScript::from_file(&path)?,
+                None => SwitchScript::default(),
+            };
+            Ok(Box::new(if p.flag("panel") { Gpio::with_panel(script, Box::new(io::stdout())) } else { Gpio::new(script) }))
+        });
+        r.register("sound", Some(SOUND_BASE), |p| {
+            let d = SoundConfig::default();
+            let config = SoundConfig {
+                clock_hz: p.num("clock", p.clock_hz)?,
+                sample_rate: p.num_as("sample_rate", d.sample_rate as u64)?,
+                cycles_per_unit: p.num("unit", d.cycles_per_unit)?,
+                envelope_unit: p.num("envelope_unit", d.envelope_unit)?,
+            };
+            let sink: Box<dyn SampleSink> = match p.path("wav") {
+                Some(path) => Box::new(WavSink::create(&path, config.sample_rate)?),
+                None => Box::new(Vec::new()),
+            };
+            Ok(Box::new(SoundGenerator::new(sink, config)))
+        });
+        r.register("watchdog", Some(WATCHDOG_BASE), |p| {
+            let d = WatchdogConfig::default();
+            let action = match p.get("action").unwrap_or("reset") {
+                "reset" => WatchdogAction::Reset,
+                "nmi" => WatchdogAction::Nmi,
+                "fault" => WatchdogAction::Fault,
+                other => return Err(format!("watchdog: unknown action '{}'", other)),
+            };
+            let config = WatchdogConfig {
+                action,
+                cycles_per_unit: p.num("unit", d.cycles_per_unit)?,
+                timeout: p.num_as("timeout", d.timeout as u64)?,
+                enabled: !p.flag("disabled"),
+            };
+            Ok(Box::new(Watchdog::new(config)))
+        });
+        r
+    }
+}
+
+/// A `device` line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceSpec {
+    pub kind: String,
+    pub base: Option<usize>,
+    /// Whether the device's interrupt line reaches the CPU (`irq=off` disconnects it).
+    pub irq: bool,
+    pub args: Vec<(String, Option<String>)>,
+    pub line: usize,
+}
+
+/// A parsed machine description: one directive per line, `#` starts a comment.
+///
+/// ```text
+/// memory 256                          # bytes, at most 256
+/// region rom 0x00 0x40 1 0 ro         # <name> <start> <end> <read_wait> <write_wait> [ro]
+/// clock 1000000                       # Hz, used by time-based devices
+/// mode micro                          # instr | micro
+/// scheduling event                    # event | cycle: how devices are ticked
+/// irq_vector 0xD0
+/// nmi_vector 0xCE
+/// device timer at 0xE8 periodic reload=0xFB
+/// device uart port=stdout irq=off     # default base when `at` is omitted
+/// program boot.asm at 0x00            # .asm/.s is assembled, anything else loaded raw
+/// ```
+///
+/// Paths are relative to the description file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MachineSpec {
+    pub memory: usize,
+    pub regions: Vec<Region>,
+    pub clock_hz: u64,
+    pub mode: ExecMode,
+    pub scheduling: Scheduling,
+    pub irq_vector: usize,
+    pub nmi_vector: usize,
+    pub devices: Vec<DeviceSpec>,
+    /// Program file and load address.
+    pub program: Option<(PathBuf, usize)>,
+    pub base_dir: PathBuf,
+}
+
+impl Default for MachineSpec {
+    fn default() -> Self {
+        MachineSpec {
+            memory: MAX_MEMORY,
+            regions: Vec::new(),
+            clock_hz: 1_000_000,
+            mode: ExecMode::Instruction,
+            scheduling: Scheduling::default(),
+            irq_vector: IRQ_VECTOR,
+            nmi_vector: NMI_VECTOR,
+            devices: Vec::new(),
+            program: None,
+            base_dir: PathBuf::new(),
+        }
+    }
+}
+
+impl MachineSpec {
+    pub fn parse(src: &str, base_dir: &Path) -> Result<Self, String> {
+        let mut spec = MachineSpec { base_dir: base_dir.to_path_buf(), ..MachineSpec::default() };
+        // (line, what, address), checked against the memory size once it is known
+        let mut addresses: Vec<(usize, &str, u64)> = Vec::new();
+        for (lineno, raw) in src.lines().enumerate() {
+            let words: Vec<&str> = raw.split('#').next().unwrap_or("").split_whitespace().collect();
+            let Some((&key, rest)) = words.split_first() else { continue };
+            let err = |msg: String| format!("line {}: {}", lineno + 1, msg);
+            let num = |s: &str| parse_num(s).ok_or_else(|| err(format!("bad number '{}'", s)));
+            let one = || match rest {
+                [v] => num(v),
+                _ => Err(err(format!("expected '{} <value>'", key))),
+            };
+            match key {
+                "memory" => {
+                    spec.memory = one()? as usize;
+                    if spec.memory == 0 || spec.memory > MAX_MEMORY {
+                        return Err(err(format!("memory must be 1..={} bytes", MAX_MEMORY)));
+                    }
+                }
+                "clock" => spec.clock_hz = one()?.max(1),
+                "irq_vector" => {
+                    spec.irq_vector = one()? as usize;
+                    addresses.push((lineno + 1, key, spec.irq_vector as u64));
+                }
+                "nmi_vector" => {
+                    spec.nmi_vector = one()? as usize;
+                    addresses.push((lineno + 1, key, spec.nmi_vector as u64));
+                }
+                "mode" => {
+                    spec.mode = match rest {
+                        ["instr"] => ExecMode::Instruction,
+                        ["micro"] => ExecMode::MicroCycle,
+                        _ => return Err(err("expected 'mode instr|micro'".to_string())),
+                    }
+                }
+                "scheduling" => {
+                    spec.scheduling = match rest {
+                        ["event"] => Scheduling::EventDriven,
+                        ["cycle"] => Scheduling::PerCycle,
+                        _ => return Err(err("expected 'scheduling event|cycle'".to_string())),
+                    }
+                }
+                "region" => {
+                    let [name, start, end, rw, ww, flags @ ..] = rest else {
+                        return Err(err("expected 'region <name> <start> <end> <read_wait> <write_wait> [ro]'".to_string()));
+                    };
+                    let mut region = Region::new(name, num(start)? as usize, num(end)? as usize, num(rw)?, num(ww)?);
+                    match flags {
+                        [] => {}
+                        ["ro"] => region.read_only = true,
+                        _ => return Err(err(format!("unexpected '{}'", flags.join(" ")))),
+                    }
+                    spec.regions.push(region);
+                }
+                "device" => {
+                    let Some((kind, mut rest)) = rest.split_first() else {
+                        return Err(err("expected 'device <name> [at <addr>] [key=value]...'".to_string()));
+                    };
+                    let mut base = None;
+                    if let ["at", addr, tail @ ..] = rest {
+                        let addr = num(addr)?;
+                        addresses.push((lineno + 1, "device address", addr));
+                        base = Some(addr as usize);
+                        rest = tail;
+                    }
+                    let mut irq = true;
+                    let mut args = Vec::new();
+                    for arg in rest {
+                        match arg.split_once('=') {
+                            Some(("irq", v)) => {
+                                irq = match v {
+                                    "on" => true,
+                                    "off" => false,
+                                    _ => return Err(err(format!("irq must be on or off, not '{}'", v))),
+                                }
+                            }
+                            Some((k, v)) => args.push((k.to_string(), Some(v.to_string()))),
+                            None => args.push((arg.to_string(), None)),
+                        }
+                    }
+                    spec.devices.push(DeviceSpec { kind: kind.to_string(), base, irq, args, line: lineno + 1 });
+                }
+                "program" => {
+                    let (path, addr) = match rest {
+                        [path] => (path, 0),
+                        [path, "at", addr] => {
+                            let addr = num(addr)?;
+                            addresses.push((lineno + 1, "program address", addr));
+                            (path, addr as usize)
+                        }
+                        _ => return Err(err("expected 'program <file> [at <addr>]'".to_string())),
+                    };
+                    spec.program = Some((base_dir.join(path), addr));
+                }
+                _ => return Err(err(format!("unknown directive '{}'", key))),
+            }
+        }
+        if let Some((line, what, addr)) = addresses.into_iter().find(|&(_, _, a)| a >= spec.memory as u64) {
+            return Err(format!("line {}: {} 0x{:X} is outside the {}-byte memory", line, what, addr, spec.memory));
+        }
+        Ok(spec)
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let src = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+        MachineSpec::parse(&src, dir).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    /// Construct the CPU, its devices (through `registry`) and load the program.
+    pub fn build(&self, registry: &DeviceRegistry) -> Result<Machine, String> {
+        let mut cpu = CPU::new();
+        cpu.mem = Memory::with_size(self.memory);
+        for region in &self.regions {
+            cpu.mem.add_region(region.clone());
+        }
+        cpu.mode = self.mode;
+        cpu.scheduling = self.scheduling;
+        cpu.irq_vector = self.irq_vector;
+        cpu.nmi_vector = self.nmi_vector;
+        for d in &self.devices {
+            let err = |msg: String| format!("line {}: {}", d.line, msg);
+            let params = DeviceParams { clock_hz: self.clock_hz, base_dir: self.base_dir.clone(), ..DeviceParams::new(&d.kind, d.args.clone()) };
+            let (dev, default_base) = registry.create(&params).map_err(err)?;
+            let id = match d.base.or(default_base) {
+                Some(base) => cpu.attach_mapped_device(dev, base).map_err(|e| err(format!("{}: {}", d.kind, e)))?,
+                None => cpu.attach_device(dev),
+            };
+            cpu.set_irq_connected(id, d.irq)?;
+        }
+        if let Some((path, addr)) = &self.program {
+            let shown = path.display();
+            let bytes = std::fs::read(path).map_err(|e| format!("{}: {}", shown, e))?;
+            let bytes = match path.extension().and_then(|e| e.to_str()) {
+                Some("asm" | "s") => assemble(&String::from_utf8_lossy(&bytes)).map_err(|e| format!("{}: {}", shown, e))?,
+                _ => bytes,
+            };
+            cpu.load(&bytes, *addr);
+        }
+        Ok(Machine { cpu, clock_hz: self.clock_hz })
+    }
+}
+
+/// A CPU built from a `MachineSpec`.
+#[derive(Debug)]
+pub struct Machine {
+    pub cpu: CPU,
+    pub clock_hz: u64,
+}
+
+impl Machine {
+    /// Build from a description file with the built-in device registry.
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        MachineSpec::from_file(path)?.build(&DeviceRegistry::default())
+    }
+
+    /// Virtual time elapsed at the machine's clock rate.
+    pub fn elapsed_secs(&self) -> f64 {
+        self.cpu.cycles as f64 / self.clock_hz as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_and_runs_a_described_machine() {
+        let dir = std::env::temp_dir().join(format!("toy_cpu_machine_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // wait for the RTC (10 Hz) to reach second 2, then halt
+        std::fs::write(dir.join("wait.asm"), "LDI R1, 2\nloop:\nLOAD R0, 0xBC\nSUB R0, R1\nJZ R0, done\nJMP loop\ndone:\nHLT\n").unwrap();
+        let desc = r#"
+            # slow clock so seconds pass quickly
+            memory 256
+            clock 10
+            region rom 0x00 0x20 1 0 ro
+            device rtc                      # at RTC_BASE, clock from the machine
+            device timer at 0xE8 periodic reload=0xF0 irq=off
+            device disk size=64 seek=5
+            program wait.asm
+        "#;
+        std::fs::write(dir.join("test.machine"), desc).unwrap();
+        let mut machine = Machine::from_file(dir.join("test.machine").to_str().unwrap()).unwrap();
+        assert_eq!(machine.cpu.mem.regions().len(), 1);
+        machine.cpu.run();
+        let _ = std::fs::remove_dir_all(&dir);
+        // one pass of the polling loop is about 10 cycles with the ROM wait states
+        assert!((2.0..5.0).contains(&machine.elapsed_secs()), "{}", machine.cpu.cycles);
+        assert!(machine.cpu.bus_stats.wait_state_cycles > 0);
+    }
+
+    #[test]
+    fn registry_and_errors() {
+        let mut registry = DeviceRegistry::default();
+        registry.register("blinker", None, |p| {
+            let _ = p.num("period", 1)?;
+            Ok(Box::new(TimerDevice::new()))
+        });
+        let spec = MachineSpec::parse("memory 64\nmode micro\nscheduling cycle\ndevice blinker period=3", Path::new("")).unwrap();
+        let machine = spec.build(&registry).unwrap();
+        assert_eq!((machine.cpu.mem.size(), machine.cpu.mode), (64, ExecMode::MicroCycle));
+        assert_eq!(machine.cpu.scheduling, Scheduling::PerCycle);
+
+        let build = |src: &str| MachineSpec::parse(src, Path::new("")).and_then(|s| s.build(&registry)).err().unwrap();
+        assert_eq!(build("memory 512"), "line 1: memory must be 1..=256 bytes");
+        assert!(build("\ndevice flux").starts_with("line 2: unknown device 'flux'"));
+        assert_eq!(build("device rtc colour=red"), "line 1: rtc: unknown parameter(s) colour");
+        assert!(build("device dma\ndevice dma at 0xF2").starts_with("line 2: dma:"));
+        assert_eq!(build("device timer periodic prescale=300"), "line 1: timer: bad number '300' for prescale");
+        assert_eq!(build("device watchdog timeout=0x100"), "line 1: watchdog: bad number '0x100' for timeout");
+        assert_eq!(build("frobnicate"), "line 1: unknown directive 'frobnicate'");
+        assert_eq!(build("irq_vector 0x1000\nmemory 64"), "line 1: irq_vector 0x1000 is outside the 64-byte memory");
+        assert_eq!(build("memory 64\nnmi_vector 0x40"), "line 2: nmi_vector 0x40 is outside the 64-byte memory");
+        assert_eq!(build("device dma at 0xFFFFFFFFFFFFFFFF"), "line 1: device address 0xFFFFFFFFFFFFFFFF is outside the 256-byte memory");
+        assert_eq!(build("program boot.asm at 0x100"), "line 1: program address 0x100 is outside the 256-byte memory");
+        // in memory, but the register window runs past the end
+        assert!(build("device dma at 0xFF").starts_with("line 1: dma: cannot map"));
+    }
+}
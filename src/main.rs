@@ -1,17 +1,19 @@
-mod cpu;
-mod device;
-mod memory;
-mod assembler;
-mod repl;
-
-use cpu::CPU;
-use device::TimerDevice;
+use toy_cpu::cpu::ExecMode;
+use toy_cpu::machine::{DeviceRegistry, MachineSpec};
+use toy_cpu::repl;
 use std::env;
+use std::path::Path;
+use std::process;
+
+/// Machine used without `--machine`: an example timer (overflows every 5 cycles).
+const DEFAULT_MACHINE: &str = "device timer periodic reload=0xFB\n";
 
 fn main() {
     let args: Vec<String> = env::args().collect();
     let trace = args.iter().any(|a| a == "--trace" || a == "-t");
     let repl_mode = args.iter().any(|a| a == "--repl" || a == "-r");
+    let micro = args.iter().any(|a| a == "--micro");
+    let machine_file = args.iter().position(|a| a == "--machine" || a == "-m").and_then(|i| args.get(i + 1));
 
     if repl_mode {
         // Start REPL (it creates its own CPU)
@@ -28,16 +30,28 @@ fn main() {
         0xFF,       // HLT
     ];
 
-    let mut cpu = CPU::new();
-    // attach an example timer device (prints every 5 cycles)
-    cpu.attach_device(Box::new(TimerDevice::new(5)));
-    cpu.load(program, 0);
-
-    if trace {
-        cpu.run_with_trace();
-    } else {
-        cpu.run();
+    let spec = match machine_file {
+        Some(path) => MachineSpec::from_file(path),
+        None => MachineSpec::parse(DEFAULT_MACHINE, Path::new("")),
+    };
+    // a machine without a `program` line runs the example program
+    let has_program = spec.as_ref().is_ok_and(|s| s.program.is_some());
+    let machine = spec.and_then(|s| s.build(&DeviceRegistry::default()));
+    let mut cpu = match machine {
+        Ok(m) => m.cpu,
+        Err(e) => {
+            eprintln!("machine: {}", e);
+            process::exit(1);
+        }
+    };
+    if micro {
+        cpu.mode = ExecMode::MicroCycle;
+    }
+    if !has_program {
+        cpu.load(program, 0);
     }
 
+    let reason = if trace { cpu.run_with_trace() } else { cpu.run() };
+    println!("Stopped: {}", reason);
     cpu.dump_state();
 }
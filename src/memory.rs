@@ -1,12 +1,104 @@
 use std::fmt::{Debug, Formatter};
 
+/// Kind of a memory (bus) access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    /// Instruction byte fetch.
+    Fetch,
+    Read,
+    Write,
+}
+
+/// A single memory access performed by an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemAccess {
+    pub kind: AccessKind,
+    pub addr: usize,
+    pub value: u8,
+}
+
+/// A named address range with its own access timing. `start` is inclusive, `end` exclusive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    pub name: String,
+    pub start: usize,
+    pub end: usize,
+    /// Wait states (extra cycles) for each fetch or read in the region.
+    pub read_wait: u64,
+    /// Wait states for each write in the region.
+    pub write_wait: u64,
+    /// CPU stores into the region are ignored (ROM). `load` can still initialise it.
+    pub read_only: bool,
+}
+
+impl Region {
+    pub fn new(name: &str, start: usize, end: usize, read_wait: u64, write_wait: u64) -> Self {
+        Region { name: name.to_string(), start, end, read_wait, write_wait, read_only: false }
+    }
+
+    /// A read-only region (writes by the CPU are dropped).
+    pub fn rom(name: &str, start: usize, end: usize, read_wait: u64) -> Self {
+        Region { read_only: true, ..Region::new(name, start, end, read_wait, 0) }
+    }
+
+    pub fn contains(&self, addr: usize) -> bool {
+        addr >= self.start && addr < self.end
+    }
+}
+
+/// Largest memory the 8-bit address operands can reach.
+pub const MAX_MEMORY: usize = 256;
+
 pub struct Memory {
-    mem: [u8; 256],
+    mem: Vec<u8>,
+    regions: Vec<Region>,
+    /// Bytes predecoded by a `blocks::BlockCache`; any write clears the mark.
+    code: Vec<bool>,
 }
 
 impl Memory {
     pub fn new() -> Self {
-        Memory { mem: [0; 256] }
+        Memory::with_size(MAX_MEMORY)
+    }
+
+    /// Memory of `size` bytes (1..=`MAX_MEMORY`); addresses wrap at the end.
+    pub fn with_size(size: usize) -> Self {
+        let size = size.clamp(1, MAX_MEMORY);
+        Memory { mem: vec![0; size], regions: Vec::new(), code: vec![false; size] }
+    }
+
+    /// Add a timing region. Addresses outside every region are zero-wait RAM;
+    /// when regions overlap the one added first wins.
+    pub fn add_region(&mut self, region: Region) {
+        self.code.fill(false);
+        self.regions.push(region);
+    }
+
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    pub fn clear_regions(&mut self) {
+        self.code.fill(false);
+        self.regions.clear();
+    }
+
+    pub fn region_at(&self, addr: usize) -> Option<&Region> {
+        let a = addr % self.size();
+        self.regions.iter().find(|r| r.contains(a))
+    }
+
+    /// Wait states for one access of `kind` at `addr`.
+    pub fn wait_states(&self, addr: usize, kind: AccessKind) -> u64 {
+        match (self.region_at(addr), kind) {
+            (Some(r), AccessKind::Write) => r.write_wait,
+            (Some(r), _) => r.read_wait,
+            (None, _) => 0,
+        }
+    }
+
+    pub fn is_read_only(&self, addr: usize) -> bool {
+        self.region_at(addr).is_some_and(|r| r.read_only)
     }
 
     pub fn size(&self) -> usize {
@@ -20,17 +112,45 @@ impl Memory {
     pub fn write(&mut self, addr: usize, val: u8) {
         let a = addr % self.size();
         self.mem[a] = val;
+        self.code[a] = false;
+    }
+
+    /// Mark `addr` as predecoded code, unchanged until the next write to it (or a region
+    /// change, which alters its fetch timing).
+    pub fn mark_code(&mut self, addr: usize) {
+        let a = addr % self.size();
+        self.code[a] = true;
+    }
+
+    /// Whether `addr` is marked as code and has not been written since.
+    pub fn is_code(&self, addr: usize) -> bool {
+        match self.code.get(addr) {
+            Some(&marked) => marked,
+            None => self.code[addr % self.size()],
+        }
+    }
+
+    /// Raw pointers to the bytes and their code marks, for host code from `jit::Jit`.
+    pub(crate) fn raw_parts(&mut self) -> (*mut u8, *mut bool) {
+        (self.mem.as_mut_ptr(), self.code.as_mut_ptr())
     }
 
     pub fn write_bytes(&mut self, addr: usize, bytes: &[u8]) {
         let mut a = addr % self.size();
         for b in bytes {
             self.mem[a] = *b;
+            self.code[a] = false;
             a = (a + 1) % self.size();
         }
     }
 }
 
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Debug for Memory {
     fn fmt (&self, _: &mut Formatter::<'_>) -> Result<(), std::fmt::Error>{
         Ok(())
@@ -47,4 +167,36 @@ mod tests {
         m.write(0x10, 0xAA);
         assert_eq!(m.read(0x10), 0xAA);
     }
+
+    #[test]
+    fn small_memory_wraps_addresses() {
+        let mut small = Memory::with_size(64);
+        small.write(0x50, 7);
+        assert_eq!((small.size(), small.read(0x10)), (64, 7));
+    }
+
+    #[test]
+    fn code_marks_are_cleared_by_writes_and_region_changes() {
+        let mut small = Memory::with_size(64);
+        small.mark_code(0x11);
+        small.mark_code(0x12);
+        assert_eq!(small.read(0x11), 0);
+        assert!(small.is_code(0x11));
+        small.write_bytes(0x51, &[1]);
+        assert!(!small.is_code(0x11) && small.is_code(0x52));
+        small.add_region(Region::new("slow", 0, 8, 1, 1));
+        assert!(!small.is_code(0x12));
+    }
+
+    #[test]
+    fn region_wait_states() {
+        let mut m = Memory::new();
+        m.add_region(Region::rom("rom", 0x00, 0x40, 2));
+        m.add_region(Region::new("io", 0xF0, 0x100, 3, 5));
+        m.add_region(Region::new("shadowed", 0x00, 0x10, 9, 9));
+        assert_eq!(m.wait_states(0x05, AccessKind::Fetch), 2);
+        assert_eq!(m.wait_states(0xF1, AccessKind::Write), 5);
+        assert_eq!(m.wait_states(0x80, AccessKind::Read), 0);
+        assert!(m.is_read_only(0x3F) && !m.is_read_only(0x40));
+    }
 }
@@ -0,0 +1,372 @@
+// src/pipeline.rs
+use crate::cpu::CPU;
+use crate::isa::{self, Instruction};
+
+/// How the pipeline handles JMP/JZ, which are resolved at the end of EX.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BranchPolicy {
+    /// Stop fetching after a branch until it resolves (2 bubble cycles per branch).
+    Stall,
+    /// Keep fetching sequentially; flush the wrong-path instructions if the branch is taken.
+    PredictNotTaken,
+    /// Direct-mapped branch target buffer with `entries` slots. A hit predicts
+    /// taken to the stored target; entries are dropped when the branch falls through.
+    Btb { entries: usize },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PipelineConfig {
+    /// Forward EX/MEM results to EX. Without it, consumers wait until the producer's WB.
+    pub forwarding: bool,
+    pub branch: BranchPolicy,
+    /// Maximum number of rows (instructions, flushed ones included) kept for `diagram`.
+    pub diagram_limit: usize,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        PipelineConfig { forwarding: true, branch: BranchPolicy::PredictNotTaken, diagram_limit: 64 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PipelineStats {
+    pub cycles: u64,
+    pub instructions: u64,
+    /// Cycles instructions spent waiting in ID for operands.
+    pub data_stall_cycles: u64,
+    /// Fetch bubbles caused by the `Stall` branch policy.
+    pub control_stall_cycles: u64,
+    /// Wrong-path instructions squashed after a misprediction.
+    pub flushed: u64,
+    pub branches: u64,
+    pub mispredictions: u64,
+}
+
+impl PipelineStats {
+    pub fn cpi(&self) -> f64 {
+        if self.instructions == 0 {
+            0.0
+        } else {
+            self.cycles as f64 / self.instructions as f64
+        }
+    }
+}
+
+/// Stage entry cycles of one instruction. Flushed instructions only have IF/ID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PipeEntry {
+    pub pc: usize,
+    pub instr: Instruction,
+    pub fetch: u64,
+    pub decode: u64,
+    pub execute: u64,
+    pub memory: u64,
+    pub writeback: u64,
+    pub flushed: bool,
+}
+
+/// Cycle from which a register value can be consumed by EX.
+#[derive(Debug, Clone, Copy, Default)]
+struct RegReady {
+    forwarded: u64,
+    written: u64,
+}
+
+/// Classic in-order IF/ID/EX/MEM/WB pipeline timing model for the toy ISA.
+///
+/// Instructions are executed functionally with `CPU::step_instruction`, so the
+/// architectural results are exactly those of `CPU::run`; the pipeline only decides
+/// in which cycle each instruction occupies each stage. Operands are needed at the
+/// start of EX, ALU results (and LDI) are available after EX, LOAD results after MEM.
+/// Devices are ticked up to each instruction's WB cycle.
+#[derive(Debug)]
+pub struct Pipeline {
+    config: PipelineConfig,
+    stats: PipelineStats,
+    entries: Vec<PipeEntry>,
+    btb: Vec<Option<(usize, usize)>>,
+    ready: [RegReady; 4],
+    last: Option<PipeEntry>,
+    next_fetch: u64,
+}
+
+impl Pipeline {
+    pub fn new(config: PipelineConfig) -> Self {
+        let btb_size = match config.branch {
+            BranchPolicy::Btb { entries } => entries.max(1),
+            _ => 0,
+        };
+        Pipeline {
+            config,
+            stats: PipelineStats::default(),
+            entries: Vec::new(),
+            btb: vec![None; btb_size],
+            ready: [RegReady::default(); 4],
+            last: None,
+            next_fetch: 1,
+        }
+    }
+
+    pub fn stats(&self) -> PipelineStats {
+        self.stats
+    }
+
+    pub fn entries(&self) -> &[PipeEntry] {
+        &self.entries
+    }
+
+    /// Run `cpu` until it halts or `max_instructions` have retired.
+    pub fn run(&mut self, cpu: &mut CPU, max_instructions: u64) -> PipelineStats {
+        let base = cpu.cycles;
+        let mut retired = 0;
+        while !cpu.halted && retired < max_instructions {
+            cpu.poll_interrupt();
+            if cpu.halted {
+                break;
+            }
+            let pc = cpu.pc;
+            let opcode = cpu.mem.read(pc);
+            let instr = isa::decode(opcode, cpu.mem.read(pc + 1));
+            cpu.step_instruction();
+            let entry = self.schedule(cpu, pc, instr);
+            cpu.tick_devices((base + entry.writeback).saturating_sub(cpu.cycles));
+            retired += 1;
+        }
+        self.stats
+    }
+
+    fn schedule(&mut self, cpu: &CPU, pc: usize, instr: Instruction) -> PipeEntry {
+        let prev = self.last;
+        let fetch = self.next_fetch;
+        let decode = (fetch + 1).max(prev.map_or(0, |p| p.execute));
+        let structural = (decode + 1).max(prev.map_or(0, |p| p.memory));
+        let mut execute = structural;
+        for r in sources(&instr) {
+            let ready = self.ready[r];
+            execute = execute.max(if self.config.forwarding { ready.forwarded } else { ready.written });
+        }
+        self.stats.data_stall_cycles += execute - structural;
+        let memory = (execute + 1).max(prev.map_or(0, |p| p.writeback));
+        let writeback = memory + 1;
+        let entry = PipeEntry { pc, instr, fetch, decode, execute, memory, writeback, flushed: false };
+
+        match instr {
+            Instruction::Ldi { reg, .. } | Instruction::Add { dest: reg, .. } | Instruction::Sub { dest: reg, .. } => {
+                self.ready[reg] = RegReady { forwarded: execute + 1, written: writeback + 1 };
+            }
+            Instruction::Load { dest, .. } => {
+                self.ready[dest] = RegReady { forwarded: memory + 1, written: writeback + 1 };
+            }
+            _ => {}
+        }
+        self.push(entry);
+        self.stats.instructions += 1;
+        self.stats.cycles = writeback;
+
+        // sequential fetch by default: the next instruction enters IF when this one leaves it
+        self.next_fetch = decode;
+        if instr.is_branch() {
+            self.branch(cpu, &entry);
+        }
+        self.last = Some(entry);
+        entry
+    }
+
+    fn branch(&mut self, cpu: &CPU, b: &PipeEntry) {
+        self.stats.branches += 1;
+        let fallthrough = (b.pc + b.instr.size()) % cpu.mem.size();
+        let actual = cpu.pc;
+        let resolved = b.execute + 1;
+        let predicted = match self.config.branch {
+            BranchPolicy::Stall => {
+                self.stats.control_stall_cycles += resolved - b.decode;
+                self.next_fetch = resolved;
+                return;
+            }
+            BranchPolicy::PredictNotTaken => fallthrough,
+            BranchPolicy::Btb { .. } => {
+                let slot = b.pc % self.btb.len();
+                let predicted = match self.btb[slot] {
+                    Some((tag, target)) if tag == b.pc => target,
+                    _ => fallthrough,
+                };
+                self.btb[slot] = if actual != fallthrough { Some((b.pc, actual)) } else { None };
+                predicted
+            }
+        };
+        if predicted == actual {
+            return;
+        }
+        self.stats.mispredictions += 1;
+        // two wrong-path instructions were fetched while the branch sat in ID and EX
+        let mut wrong_pc = predicted;
+        for (fetch, decode) in [(b.decode, b.execute), (b.execute, 0)] {
+            let instr = isa::decode(cpu.mem.read(wrong_pc), cpu.mem.read(wrong_pc + 1));
+            self.push(PipeEntry {
+                pc: wrong_pc,
+                instr,
+                fetch,
+                decode,
+                execute: 0,
+                memory: 0,
+                writeback: 0,
+                flushed: true,
+            });
+            self.stats.flushed += 1;
+            wrong_pc = (wrong_pc + instr.size()) % cpu.mem.size();
+        }
+        self.next_fetch = resolved;
+    }
+
+    fn push(&mut self, entry: PipeEntry) {
+        if self.entries.len() < self.config.diagram_limit {
+            self.entries.push(entry);
+        }
+    }
+
+    /// Per-cycle pipeline diagram of the recorded instructions. Each column is a cycle;
+    /// `--` marks a cycle spent stalled in the previous stage, `xx` a flush.
+    pub fn diagram(&self) -> String {
+        let last_cycle = self.entries.iter().map(|e| e.writeback.max(e.decode).max(e.fetch)).max().unwrap_or(0);
+        let mut s = format!("{:<20}", "cycle");
+        for c in 1..=last_cycle {
+            s.push_str(&format!("{:>3}", c));
+        }
+        s.push('\n');
+        for e in &self.entries {
+            s.push_str(&format!("{:02X} {:<17}", e.pc, e.instr.to_string()));
+            for c in 1..=last_cycle {
+                s.push_str(&format!("{:>3}", cell(e, c)));
+            }
+            if e.flushed {
+                s.push_str("  (flushed)");
+            }
+            s.push('\n');
+        }
+        let st = self.stats;
+        s.push_str(&format!(
+            "cycles={} instructions={} CPI={:.2} data_stalls={} control_stalls={} flushed={} branches={} mispredicted={}\n",
+            st.cycles,
+            st.instructions,
+            st.cpi(),
+            st.data_stall_cycles,
+            st.control_stall_cycles,
+            st.flushed,
+            st.branches,
+            st.mispredictions
+        ));
+        s
+    }
+}
+
+fn cell(e: &PipeEntry, c: u64) -> &'static str {
+    if e.flushed {
+        let end = if e.decode == 0 { e.fetch } else { e.decode };
+        return match c {
+            _ if c == e.fetch => "IF",
+            _ if e.decode != 0 && c == e.decode => "ID",
+            _ if c > e.fetch && c < end => "--",
+            _ if c == end + 1 => "xx",
+            _ => "",
+        };
+    }
+    let stages = [(e.fetch, "IF"), (e.decode, "ID"), (e.execute, "EX"), (e.memory, "ME"), (e.writeback, "WB")];
+    for (i, (start, name)) in stages.iter().enumerate() {
+        if c == *start {
+            return name;
+        }
+        if let Some((next, _)) = stages.get(i + 1) {
+            if c > *start && c < *next {
+                return "--";
+            }
+        }
+    }
+    ""
+}
+
+/// Registers an instruction reads at the start of EX.
+fn sources(instr: &Instruction) -> Vec<usize> {
+    match *instr {
+        Instruction::Add { dest, src } | Instruction::Sub { dest, src } => vec![dest, src],
+        Instruction::Store { src, .. } => vec![src],
+        Instruction::Jz { reg, .. } | Instruction::Out { reg } => vec![reg],
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    const LOOP: &str = r#"
+        LDI R0, 4
+        LDI R1, 1
+        LDI R3, 0x80
+        loop:
+        JZ R0, done
+        LOAD R2, 0x80
+        ADD R2, R1
+        STORE R2, 0x80
+        SUB R0, R1
+        JMP loop
+        done:
+        HLT
+    "#;
+
+    fn run_with(config: PipelineConfig) -> (CPU, PipelineStats) {
+        let mut cpu = CPU::new();
+        cpu.load(&assemble(LOOP).unwrap(), 0);
+        let mut p = Pipeline::new(config);
+        let stats = p.run(&mut cpu, 1000);
+        (cpu, stats)
+    }
+
+    #[test]
+    fn matches_interpreter_under_every_policy() {
+        let mut reference = CPU::new();
+        reference.load(&assemble(LOOP).unwrap(), 0);
+        reference.run();
+
+        let policies = [BranchPolicy::Stall, BranchPolicy::PredictNotTaken, BranchPolicy::Btb { entries: 4 }];
+        for forwarding in [false, true] {
+            for branch in policies {
+                let (cpu, stats) = run_with(PipelineConfig { forwarding, branch, diagram_limit: 8 });
+                assert_eq!(cpu.regs, reference.regs);
+                assert_eq!(cpu.mem.read(0x80), reference.mem.read(0x80));
+                assert_eq!(cpu.pc, reference.pc);
+                assert_eq!(stats.cycles, cpu.cycles);
+            }
+        }
+    }
+
+    #[test]
+    fn forwarding_and_prediction_reduce_stalls() {
+        let slow = run_with(PipelineConfig { forwarding: false, branch: BranchPolicy::Stall, diagram_limit: 0 }).1;
+        let fwd = run_with(PipelineConfig { forwarding: true, branch: BranchPolicy::Stall, diagram_limit: 0 }).1;
+        let btb = run_with(PipelineConfig { forwarding: true, branch: BranchPolicy::Btb { entries: 4 }, diagram_limit: 0 }).1;
+        assert!(fwd.data_stall_cycles < slow.data_stall_cycles);
+        // LOAD R2 -> ADD R2 is a load-use hazard: one stall per iteration even with forwarding
+        assert_eq!(fwd.data_stall_cycles, 4);
+        assert_eq!(fwd.control_stall_cycles, 2 * fwd.branches);
+        assert!(btb.cycles < fwd.cycles);
+        assert!(btb.mispredictions < btb.branches);
+    }
+
+    #[test]
+    fn diagram_shows_flushes() {
+        let mut cpu = CPU::new();
+        cpu.load(&assemble("LDI R0, 0\nJZ R0, 6\nLDI R1, 1\nHLT\n").unwrap(), 0);
+        let mut p = Pipeline::new(PipelineConfig::default());
+        p.run(&mut cpu, 10);
+        let d = p.diagram();
+        let lines: Vec<&str> = d.lines().collect();
+        assert_eq!(lines[1].trim_end(), "00 LDI R0, 0         IF ID EX ME WB");
+        assert_eq!(lines[2].trim_end(), "02 JZ R0, 0x06          IF ID EX ME WB");
+        assert!(lines[3].starts_with("04 LDI R1, 1               IF ID xx"));
+        assert!(lines[3].ends_with("(flushed)"));
+        assert_eq!(lines[5].trim_end(), "06 HLT                           IF ID EX ME WB");
+        assert_eq!(p.stats().flushed, 2);
+    }
+}
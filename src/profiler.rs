@@ -0,0 +1,222 @@
+// src/profiler.rs
+use crate::isa::Instruction;
+use crate::trace::{TraceRecord, Tracer};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+
+/// Instructions executed and cycles consumed by one bucket (address, function, mnemonic...).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProfileCounter {
+    pub instructions: u64,
+    pub cycles: u64,
+}
+
+impl ProfileCounter {
+    fn add(&mut self, cycles: u64) {
+        self.instructions += 1;
+        self.cycles += cycles;
+    }
+}
+
+/// Per-address statistics, with the instruction last seen at that address.
+#[derive(Debug, Clone, Copy)]
+pub struct AddrProfile {
+    pub counter: ProfileCounter,
+    pub instr: Instruction,
+}
+
+/// Execution profiler. Attach it as a `Tracer` (typically through `Rc<RefCell<Profiler>>`)
+/// and it attributes every executed instruction and its cycles to the instruction's address,
+/// to the enclosing assembler label ("function") and to its mnemonic.
+///
+/// An address belongs to the closest label at or below it. Addresses below the first label
+/// are attributed to `<entry>`.
+#[derive(Debug, Default)]
+pub struct Profiler {
+    symbols: Vec<(usize, String)>,
+    by_addr: BTreeMap<usize, AddrProfile>,
+    by_func: HashMap<String, ProfileCounter>,
+    mix: HashMap<&'static str, ProfileCounter>,
+    total: ProfileCounter,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler::default()
+    }
+
+    /// Profiler that symbolizes addresses with an assembler label table.
+    pub fn with_labels(labels: &HashMap<String, usize>) -> Self {
+        let mut symbols: Vec<(usize, String)> = labels.iter().map(|(n, a)| (*a, n.clone())).collect();
+        symbols.sort();
+        Profiler { symbols, ..Profiler::default() }
+    }
+
+    /// Name of the function (label) that contains `addr`.
+    pub fn function_of(&self, addr: usize) -> String {
+        match self.symbol_index(addr) {
+            Some(i) => self.symbols[i].1.clone(),
+            None => "<entry>".to_string(),
+        }
+    }
+
+    /// `label+0xN` style name for an address, or plain hex without symbols.
+    pub fn symbolize(&self, addr: usize) -> String {
+        match self.symbol_index(addr) {
+            Some(i) if self.symbols[i].0 == addr => self.symbols[i].1.clone(),
+            Some(i) => format!("{}+0x{:X}", self.symbols[i].1, addr - self.symbols[i].0),
+            None => format!("0x{:02X}", addr),
+        }
+    }
+
+    fn symbol_index(&self, addr: usize) -> Option<usize> {
+        let n = self.symbols.partition_point(|(a, _)| *a <= addr);
+        if n == 0 {
+            None
+        } else {
+            Some(n - 1)
+        }
+    }
+
+    pub fn total(&self) -> ProfileCounter {
+        self.total
+    }
+
+    pub fn address(&self, addr: usize) -> Option<&AddrProfile> {
+        self.by_addr.get(&addr)
+    }
+
+    pub fn function(&self, name: &str) -> Option<ProfileCounter> {
+        self.by_func.get(name).copied()
+    }
+
+    /// Functions sorted by cycles, hottest first.
+    pub fn functions(&self) -> Vec<(String, ProfileCounter)> {
+        let mut v: Vec<(String, ProfileCounter)> = self.by_func.iter().map(|(k, c)| (k.clone(), *c)).collect();
+        v.sort_by(|a, b| b.1.cycles.cmp(&a.1.cycles).then_with(|| a.0.cmp(&b.0)));
+        v
+    }
+
+    /// The `n` addresses that consumed the most cycles, hottest first.
+    pub fn hot_spots(&self, n: usize) -> Vec<(usize, AddrProfile)> {
+        let mut v: Vec<(usize, AddrProfile)> = self.by_addr.iter().map(|(a, p)| (*a, *p)).collect();
+        v.sort_by(|a, b| b.1.counter.cycles.cmp(&a.1.counter.cycles).then(a.0.cmp(&b.0)));
+        v.truncate(n);
+        v
+    }
+
+    /// Instruction-mix histogram by mnemonic, most executed first.
+    pub fn instruction_mix(&self) -> Vec<(&'static str, ProfileCounter)> {
+        let mut v: Vec<(&'static str, ProfileCounter)> = self.mix.iter().map(|(m, c)| (*m, *c)).collect();
+        v.sort_by(|a, b| b.1.instructions.cmp(&a.1.instructions).then(a.0.cmp(b.0)));
+        v
+    }
+
+    /// Write cycles per function in the folded format used by flamegraph tools
+    /// (`stack <cycles>` per line). The ISA has no CALL/RET, so every stack is a
+    /// single function.
+    pub fn write_folded<W: Write>(&self, mut out: W) -> io::Result<()> {
+        let mut funcs: Vec<(&String, &ProfileCounter)> = self.by_func.iter().collect();
+        funcs.sort_by(|a, b| a.0.cmp(b.0));
+        for (func, c) in funcs {
+            writeln!(out, "{} {}", func, c.cycles)?;
+        }
+        Ok(())
+    }
+
+    /// Human-readable summary: totals, the `top` hottest addresses, functions and instruction mix.
+    pub fn report(&self, top: usize) -> String {
+        let total = self.total.cycles.max(1) as f64;
+        let pct = |c: u64| 100.0 * c as f64 / total;
+        let mut s = format!("Profile: {} instructions, {} cycles\n", self.total.instructions, self.total.cycles);
+
+        s.push_str("\nHot spots:\n");
+        for (addr, p) in self.hot_spots(top) {
+            s.push_str(&format!(
+                "  {:02X} {:<14} {:<16} {:>8} instr {:>8} cyc {:>6.2}%\n",
+                addr,
+                self.symbolize(addr),
+                p.instr.to_string(),
+                p.counter.instructions,
+                p.counter.cycles,
+                pct(p.counter.cycles)
+            ));
+        }
+
+        s.push_str("\nFunctions:\n");
+        for (name, c) in self.functions() {
+            s.push_str(&format!(
+                "  {:<14} {:>8} instr {:>8} cyc {:>6.2}%\n",
+                name,
+                c.instructions,
+                c.cycles,
+                pct(c.cycles)
+            ));
+        }
+
+        s.push_str("\nInstruction mix:\n");
+        for (m, c) in self.instruction_mix() {
+            s.push_str(&format!("  {:<6} {:>8} instr {:>8} cyc\n", m, c.instructions, c.cycles));
+        }
+        s
+    }
+}
+
+impl Tracer for Profiler {
+    fn record(&mut self, rec: &TraceRecord) {
+        let func = self.function_of(rec.pc);
+        self.total.add(rec.cycles);
+        let entry = self
+            .by_addr
+            .entry(rec.pc)
+            .or_insert(AddrProfile { counter: ProfileCounter::default(), instr: rec.instr });
+        entry.counter.add(rec.cycles);
+        entry.instr = rec.instr;
+        self.by_func.entry(func).or_default().add(rec.cycles);
+        self.mix.entry(rec.instr.mnemonic()).or_default().add(rec.cycles);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble_program;
+    use crate::cpu::CPU;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn attributes_cycles_to_labels() {
+        let src = r#"
+            start:
+            LDI R0, 3
+            LDI R1, 1
+            loop:
+            JZ R0, done
+            SUB R0, R1
+            JMP loop
+            done:
+            HLT
+        "#;
+        let prog = assemble_program(src).expect("assemble failed");
+        let prof = Rc::new(RefCell::new(Profiler::with_labels(&prog.labels)));
+        let mut cpu = CPU::new();
+        cpu.attach_tracer(Box::new(prof.clone()));
+        cpu.load(&prog.bytes, 0);
+        cpu.run();
+
+        let p = prof.borrow();
+        assert_eq!(p.total().cycles, cpu.cycles);
+        assert_eq!(p.function("start"), Some(ProfileCounter { instructions: 2, cycles: 4 }));
+        // 4 JZ + 3 SUB + 3 JMP, 3 cycles each
+        assert_eq!(p.function("loop"), Some(ProfileCounter { instructions: 10, cycles: 30 }));
+        assert_eq!(p.symbolize(0x06), "loop+0x2");
+        assert_eq!(p.hot_spots(1)[0].0, 0x04);
+        assert_eq!(p.instruction_mix()[0], ("JZ", ProfileCounter { instructions: 4, cycles: 12 }));
+
+        let mut folded = Vec::new();
+        p.write_folded(&mut folded).unwrap();
+        assert_eq!(String::from_utf8(folded).unwrap(), "done 1\nloop 30\nstart 4\n");
+        assert!(p.report(3).contains("JZ R0, 0x0A"));
+    }
+}
@@ -1,14 +1,57 @@
 // src/repl.rs
 use crate::assembler;
-use crate::cpu::CPU;
+use crate::assembler::Program;
+use crate::blocks;
+use crate::branch::{BranchUnit, PredictorKind};
+use crate::cache::{Cache, CacheConfig, Replacement, WritePolicy};
+use crate::coverage::Coverage;
+use crate::cpu::{ExecMode, Scheduling, CPU};
+use crate::device::DeviceId;
+use crate::jit::Jit;
+use crate::machine::{DeviceRegistry, DeviceSpec, MachineSpec};
+use crate::memory::Region;
+use crate::pipeline::{BranchPolicy, Pipeline, PipelineConfig};
+use crate::display::{AnsiSink, Display, DisplayConfig, FrameSink, PpmSink, DISPLAY_BASE};
+use crate::gpio::{Gpio, SwitchScript, GPIO_BASE};
+use crate::keyboard::{KeyScript, Keyboard, KEYBOARD_BASE};
+use crate::profiler::Profiler;
+use crate::rtc::{Rtc, RtcConfig, RTC_BASE};
+use crate::sound::{SoundConfig, SoundGenerator, WavSink, SOUND_BASE};
+use crate::storage::{BlockDevice, StorageConfig, STORAGE_BASE};
+use crate::trace::TextTracer;
+use crate::watchdog::{Watchdog, WatchdogAction, WatchdogConfig, WATCHDOG_BASE};
+use crate::uart::{self, Uart, UartConfig, UART_BASE};
+use std::cell::RefCell;
 use std::io::{self, Write};
+use std::rc::Rc;
+
+/// Cycle budget of `run`, `trace`, `profile` and `coverage` when none is given, so a
+/// program that never halts hands control back to the REPL.
+const DEFAULT_RUN_CYCLES: u64 = 10_000_000;
 
 /// Run a small interactive REPL for assembling and running code.
 /// Commands:
 ///  - asm        : enter assembler mode (multiline), finish with a single '.' on a line to assemble & load at addr 0
-///  - run        : run until HLT
-///  - trace      : run with trace
+///  - run [N]    : run until HLT or for at most N cycles (default 10M)
+///  - trace [N]  : run with trace, for at most N cycles
+///  - profile [N] [cycles] [file] : run with the profiler, print the top N hot spots, optionally write folded stacks
+///  - coverage [cycles] [file] : run with coverage tracking, print a summary, optionally write LCOV
+///  - pipeline [nofwd] [stall|pnt|btb[:N]] : run on the 5-stage pipeline model and print a diagram
+///  - cache [i|d <size> <line> <ways> [opts] | i|d off | reset] : configure caches / show statistics
+///  - bpred [kind [penalty] | off] : configure the branch predictor / show per-branch accuracy
+///  - region [add <name> <start> <end> <rw> <ww> [ro] | clear] : memory regions with wait states
+///  - uart <port> [base] [cycles/byte] : attach a UART (port: stdout, pty, file:[in:]out)
+///  - display <ansi|live|ppm:prefix|none> [base] [vsync] : attach a display with frame dumps
+///  - keyboard <script> [base] : attach a keyboard fed by a key script file
+///  - disk <image> [base] [seek] [cycles/byte] : attach block storage backed by an image file
+///  - rtc [hz|wall] [base] : attach a real-time clock on virtual time (or the host clock)
+///  - gpio [switch-script|none] [base] : attach GPIO pins with an LED/switch panel drawn during runs
+///  - sound <wav-file> [base] [hz] : attach a sound generator recording to a WAV file
+///  - watchdog <reset|nmi|fault> [timeout] [base] : attach a watchdog that acts on the CPU when not kicked
+///  - machine <file> : replace the CPU with one built from a machine description
 ///  - step [N]   : execute N instructions (default 1)
+///  - tick [N]   : advance N cycles, one micro-step each (default 1)
+///  - mode [instr|micro] : show or set the execution mode
 ///  - dump       : print CPU state
 ///  - regs       : print registers
 ///  - mem <addr> <len> : dump memory bytes
@@ -16,6 +59,7 @@ use std::io::{self, Write};
 ///  - help       : show help
 pub fn run_repl() {
     let mut cpu = CPU::new();
+    let mut program = Program::default();
     println!("toy_cpu REPL. Type 'help' for commands. Enter 'asm' to write assembler lines (end with a single '.' line).");
 
     loop {
@@ -53,8 +97,10 @@ pub fn run_repl() {
                     src.push_str(&t);
                     src.push('\n');
                 }
-                match assembler::assemble(&src) {
-                    Ok(bytes) => {
+                match assembler::assemble_program(&src) {
+                    Ok(assembled) => {
+                        program = assembled;
+                        let bytes = &program.bytes;
                         println!("Assembled {} bytes:", bytes.len());
                         for (i, b) in bytes.iter().enumerate() {
                             if i % 16 == 0 {
@@ -64,7 +110,7 @@ pub fn run_repl() {
                         }
                         println!();
                         // load at 0
-                        cpu.load(&bytes, 0);
+                        cpu.load(bytes, 0);
                         println!("Loaded at address 0.");
                     }
                     Err(e) => {
@@ -73,18 +119,434 @@ pub fn run_repl() {
                 }
             }
             "run" => {
-                cpu.run();
-                println!("Program finished. cycles={}", cpu.cycles);
+                let budget = parts.next().and_then(|s| s.parse().ok()).unwrap_or(DEFAULT_RUN_CYCLES);
+                let reason = cpu.run_for_cycles(budget);
+                println!("Stopped: {}. PC={:02X} cycles={}", reason, cpu.pc, cpu.cycles);
             }
             "trace" => {
-                cpu.run_with_trace();
-                println!("Program finished. cycles={}", cpu.cycles);
+                let budget = parts.next().and_then(|s| s.parse().ok()).unwrap_or(DEFAULT_RUN_CYCLES);
+                cpu.attach_tracer(Box::new(TextTracer::new(io::stdout())));
+                let reason = cpu.run_for_cycles(budget);
+                cpu.take_tracers();
+                println!("Stopped: {}. PC={:02X} cycles={}", reason, cpu.pc, cpu.cycles);
+            }
+            "break" => {
+                match parts.next().map(|s| parse_num(s).ok_or(s)) {
+                    Some(Ok(addr)) if !cpu.breakpoints.remove(&addr) => {
+                        cpu.breakpoints.insert(addr);
+                    }
+                    Some(Ok(_)) => {}
+                    Some(Err(s)) => println!("Invalid address '{}'. Usage: break [addr]", s),
+                    None => {}
+                }
+                let list: Vec<String> = cpu.breakpoints.iter().map(|a| format!("{:02X}", a)).collect();
+                println!("Breakpoints: {}", if list.is_empty() { "none".to_string() } else { list.join(" ") });
+            }
+            "profile" => {
+                // numbers are N and then the cycle budget; anything else is the file
+                let (nums, path) = split_run_args(parts.by_ref());
+                let top = nums.first().map_or(10, |&n| n as usize);
+                let prof = Rc::new(RefCell::new(Profiler::with_labels(&program.labels)));
+                cpu.attach_tracer(Box::new(prof.clone()));
+                let reason = cpu.run_for_cycles(nums.get(1).copied().unwrap_or(DEFAULT_RUN_CYCLES));
+                cpu.take_tracers();
+                println!("Stopped: {}. PC={:02X} cycles={}", reason, cpu.pc, cpu.cycles);
+                let prof = prof.borrow();
+                print!("{}", prof.report(top));
+                if let Some(path) = path {
+                    match std::fs::File::create(path).and_then(|f| prof.write_folded(f)) {
+                        Ok(_) => println!("Folded stacks written to {}", path),
+                        Err(e) => println!("Could not write {}: {}", path, e),
+                    }
+                }
+            }
+            "coverage" => {
+                let (nums, path) = split_run_args(parts.by_ref());
+                let cov = Rc::new(RefCell::new(Coverage::new()));
+                cpu.attach_tracer(Box::new(cov.clone()));
+                let reason = cpu.run_for_cycles(nums.first().copied().unwrap_or(DEFAULT_RUN_CYCLES));
+                cpu.take_tracers();
+                println!("Stopped: {}. PC={:02X} cycles={}", reason, cpu.pc, cpu.cycles);
+                let cov = cov.borrow();
+                print!("{}", cov.summary(&program));
+                if let Some(path) = path {
+                    match std::fs::File::create(path).and_then(|f| cov.write_lcov(&program, "repl", "<repl>", f)) {
+                        Ok(_) => println!("LCOV written to {}", path),
+                        Err(e) => println!("Could not write {}: {}", path, e),
+                    }
+                }
+            }
+            "pipeline" => {
+                let mut config = PipelineConfig::default();
+                let mut ok = true;
+                for opt in parts.by_ref() {
+                    match opt {
+                        "fwd" => config.forwarding = true,
+                        "nofwd" => config.forwarding = false,
+                        "stall" => config.branch = BranchPolicy::Stall,
+                        "pnt" => config.branch = BranchPolicy::PredictNotTaken,
+                        o if o.starts_with("btb") => {
+                            let entries = o.strip_prefix("btb:").and_then(|n| n.parse().ok()).unwrap_or(16);
+                            config.branch = BranchPolicy::Btb { entries };
+                        }
+                        o => {
+                            println!("Unknown pipeline option '{}'.", o);
+                            ok = false;
+                        }
+                    }
+                }
+                if ok {
+                    let mut p = Pipeline::new(config);
+                    p.run(&mut cpu, 1_000_000);
+                    print!("{}", p.diagram());
+                }
             }
+            "cache" => {
+                let args: Vec<&str> = parts.by_ref().collect();
+                match args.as_slice() {
+                    [] => {}
+                    ["reset"] => {
+                        for c in [cpu.icache.as_mut(), cpu.dcache.as_mut()].into_iter().flatten() {
+                            c.reset();
+                        }
+                    }
+                    [which, "off"] if *which == "i" || *which == "d" => {
+                        if *which == "i" {
+                            cpu.icache = None;
+                        } else {
+                            cpu.dcache = None;
+                        }
+                    }
+                    [which, rest @ ..] if *which == "i" || *which == "d" => match parse_cache_config(rest) {
+                        Ok(cache) => {
+                            if *which == "i" {
+                                cpu.icache = Some(cache);
+                            } else {
+                                cpu.dcache = Some(cache);
+                            }
+                        }
+                        Err(e) => println!("cache: {}", e),
+                    },
+                    _ => println!("Usage: cache [i|d <size> <line> <ways> [lru|fifo|random[:seed]] [wb|wt] [hit=N] [miss=N] | i|d off | reset]"),
+                }
+                print_cache_stats(&cpu);
+            }
+            "bpred" => match parts.next() {
+                None => match &cpu.branch_unit {
+                    Some(unit) => print!("{}", unit.report()),
+                    None => println!("No branch predictor configured."),
+                },
+                Some("off") => {
+                    cpu.branch_unit = None;
+                    println!("Branch predictor removed.");
+                }
+                Some(kind) => match parse_predictor(kind) {
+                    Some(kind) => {
+                        let penalty = parts.next().and_then(|s| s.parse().ok()).unwrap_or(2);
+                        let unit = BranchUnit::new(kind, penalty);
+                        println!("Branch predictor: {} (penalty {})", unit.predictor_name(), penalty);
+                        cpu.branch_unit = Some(unit);
+                    }
+                    None => println!("Unknown predictor '{}'. Use nt, btfn, 1bit[:N], 2bit[:N], gshare[:bits] or btb[:N].", kind),
+                },
+            },
+            "region" => match parts.next() {
+                None => {
+                    if cpu.mem.regions().is_empty() {
+                        println!("No regions configured (all memory has zero wait states).");
+                    }
+                    for r in cpu.mem.regions() {
+                        println!(
+                            "  {:<8} {:02X}..{:02X} read_wait={} write_wait={}{}",
+                            r.name,
+                            r.start,
+                            r.end,
+                            r.read_wait,
+                            r.write_wait,
+                            if r.read_only { " ro" } else { "" }
+                        );
+                    }
+                    let s = cpu.bus_stats;
+                    println!("Wait-state cycles: {}  contention cycles: {}", s.wait_state_cycles, s.contention_cycles);
+                }
+                Some("clear") => {
+                    cpu.mem.clear_regions();
+                    println!("Regions cleared.");
+                }
+                Some("add") => {
+                    let name = parts.next();
+                    let nums: Vec<Option<usize>> = (0..4).map(|_| parts.next().and_then(parse_num)).collect();
+                    match (name, nums[0], nums[1], nums[2], nums[3]) {
+                        (Some(name), Some(start), Some(end), Some(rw), Some(ww)) if start < end => {
+                            let mut region = Region::new(name, start, end, rw as u64, ww as u64);
+                            region.read_only = parts.next() == Some("ro");
+                            cpu.mem.add_region(region);
+                            println!("Region '{}' added.", name);
+                        }
+                        _ => println!("Usage: region add <name> <start> <end> <read_wait> <write_wait> [ro]"),
+                    }
+                }
+                Some(_) => println!("Usage: region [add <name> <start> <end> <read_wait> <write_wait> [ro] | clear]"),
+            },
+            "uart" => match parts.next() {
+                // its stdin reader would compete with the REPL for input lines
+                Some("stdio") => println!("uart: stdio is not available in the REPL; use stdout, pty or file:<in>:<out>."),
+                Some(spec) => {
+                    let base = parts.next().and_then(parse_num).unwrap_or(UART_BASE);
+                    let mut config = UartConfig::default();
+                    if let Some(c) = parts.next().and_then(|s| s.parse().ok()) {
+                        config.cycles_per_byte = c;
+                    }
+                    match uart::open_port(spec).and_then(|(port, pty)| Ok((cpu.attach_mapped_device(Box::new(Uart::new(port, config)), base)?, pty))) {
+                        Ok((_, pty)) => {
+                            println!("UART on '{}' at 0x{:02X}, {} cycles/byte.", spec, base, config.cycles_per_byte);
+                            if let Some(path) = pty {
+                                println!("Connect a terminal to {}", path);
+                            }
+                        }
+                        Err(e) => println!("uart: {}", e),
+                    }
+                }
+                None => println!("Usage: uart <stdout|pty|file:[in:]out> [base] [cycles/byte]"),
+            },
+            "display" => match parts.next() {
+                Some(target) => {
+                    let base = parts.next().and_then(parse_num).unwrap_or(DISPLAY_BASE);
+                    let mut config = DisplayConfig::default();
+                    if let Some(v) = parts.next().and_then(|s| s.parse().ok()) {
+                        config.vsync_cycles = v;
+                    }
+                    let sink: Option<Box<dyn FrameSink>> = match target {
+                        "ansi" => Some(Box::new(AnsiSink::new(io::stdout(), false))),
+                        "live" => Some(Box::new(AnsiSink::new(io::stdout(), true))),
+                        "none" => None,
+                        _ => target.strip_prefix("ppm:").map(|p| Box::new(PpmSink::new(p)) as Box<dyn FrameSink>),
+                    };
+                    if sink.is_none() && target != "none" {
+                        println!("Unknown frame target '{}'. Use ansi, live, ppm:<prefix> or none.", target);
+                    } else {
+                        let display = match sink {
+                            Some(sink) => Display::with_sink(sink, config),
+                            None => Display::new(config),
+                        };
+                        match cpu.attach_mapped_device(Box::new(display), base) {
+                            Ok(_) => println!("Display at 0x{:02X}, vsync every {} cycles.", base, config.vsync_cycles),
+                            Err(e) => println!("display: {}", e),
+                        }
+                    }
+                }
+                None => println!("Usage: display <ansi|live|ppm:<prefix>|none> [base] [vsync_cycles]"),
+            },
+            "keyboard" => match parts.next() {
+                // raw mode would outlive the run and swallow the REPL's own input
+                Some("tty") => println!("keyboard: tty is not available in the REPL; use a key script file."),
+                Some(path) => {
+                    let base = parts.next().and_then(parse_num).unwrap_or(KEYBOARD_BASE);
+                    match KeyScript::from_file(path)
+                        .and_then(|s| cpu.attach_mapped_device(Box::new(Keyboard::new(Box::new(s))), base))
+                    {
+                        Ok(_) => println!("Keyboard at 0x{:02X} reading from {}.", base, path),
+                        Err(e) => println!("keyboard: {}", e),
+                    }
+                }
+                None => println!("Usage: keyboard <script-file> [base]"),
+            },
+            "disk" => match parts.next() {
+                Some(path) => {
+                    let base = parts.next().and_then(parse_num).unwrap_or(STORAGE_BASE);
+                    let mut config = StorageConfig::default();
+                    if let Some(s) = parts.next().and_then(|s| s.parse().ok()) {
+                        config.seek_cycles = s;
+                    }
+                    if let Some(c) = parts.next().and_then(|s| s.parse().ok()) {
+                        config.cycles_per_byte = c;
+                    }
+                    match BlockDevice::open(path, config) {
+                        Ok(disk) => {
+                            let sectors = disk.sectors();
+                            match cpu.attach_mapped_device(Box::new(disk), base) {
+                                Ok(_) => println!("Disk '{}' ({} sectors of {} bytes) at 0x{:02X}.", path, sectors, config.sector_size, base),
+                                Err(e) => println!("disk: {}", e),
+                            }
+                        }
+                        Err(e) => println!("disk: {}", e),
+                    }
+                }
+                None => println!("Usage: disk <image-file> [base] [seek_cycles] [cycles/byte]"),
+            },
+            "rtc" => {
+                let mut config = RtcConfig::default();
+                match parts.next() {
+                    Some("wall") => config.wall_clock = true,
+                    Some(hz) => config.clock_hz = hz.parse().unwrap_or(config.clock_hz),
+                    None => {}
+                }
+                let base = parts.next().and_then(parse_num).unwrap_or(RTC_BASE);
+                match cpu.attach_mapped_device(Box::new(Rtc::new(config)), base) {
+                    Ok(_) if config.wall_clock => println!("RTC at 0x{:02X} on the host clock.", base),
+                    Ok(_) => println!("RTC at 0x{:02X}, {} Hz virtual clock.", base, config.clock_hz),
+                    Err(e) => println!("rtc: {}", e),
+                }
+            }
+            "gpio" => {
+                let script = match parts.next() {
+                    None | Some("none") => Ok(SwitchScript::default()),
+                    Some(path) => SwitchScript::from_file(path),
+                };
+                let base = parts.next().and_then(parse_num).unwrap_or(GPIO_BASE);
+                match script.and_then(|s| cpu.attach_mapped_device(Box::new(Gpio::with_panel(s, Box::new(io::stdout()))), base)) {
+                    Ok(_) => println!("GPIO at 0x{:02X}.", base),
+                    Err(e) => println!("gpio: {}", e),
+                }
+            }
+            "sound" => match parts.next() {
+                Some(path) => {
+                    let base = parts.next().and_then(parse_num).unwrap_or(SOUND_BASE);
+                    let mut config = SoundConfig::default();
+                    if let Some(hz) = parts.next().and_then(|s| s.parse().ok()) {
+                        config.clock_hz = hz;
+                    }
+                    let sink = WavSink::create(path, config.sample_rate);
+                    match sink.and_then(|w| cpu.attach_mapped_device(Box::new(SoundGenerator::new(Box::new(w), config)), base)) {
+                        Ok(_) => println!("Sound at 0x{:02X} recording to '{}' ({} Hz clock, {} Hz samples).", base, path, config.clock_hz, config.sample_rate),
+                        Err(e) => println!("sound: {}", e),
+                    }
+                }
+                None => println!("Usage: sound <wav-file> [base] [clock_hz]"),
+            },
+            "machine" => match parts.next() {
+                Some(path) => match MachineSpec::from_file(path).and_then(|spec| match terminal_device(&spec) {
+                    Some(d) => Err(format!("line {}: {} would read the REPL's terminal", d.line, d.kind)),
+                    None => spec.build(&DeviceRegistry::default()),
+                }) {
+                    Ok(m) => {
+                        cpu = m.cpu;
+                        println!("Machine '{}' loaded ({} bytes of memory, {} Hz clock).", path, cpu.mem.size(), m.clock_hz);
+                    }
+                    Err(e) => println!("machine: {}", e),
+                },
+                None => println!("Usage: machine <file>"),
+            },
+            "watchdog" => {
+                let action = match parts.next() {
+                    Some("reset") => Some(WatchdogAction::Reset),
+                    Some("nmi") => Some(WatchdogAction::Nmi),
+                    Some("fault") => Some(WatchdogAction::Fault),
+                    _ => None,
+                };
+                let mut config = WatchdogConfig::default();
+                let timeout = match parts.next() {
+                    Some(t) => parse_num(t).filter(|t| (1..=0xFF).contains(t)).map(|t| t as u8),
+                    None => Some(config.timeout),
+                };
+                let base = match parts.next() {
+                    Some(b) => parse_num(b),
+                    None => Some(WATCHDOG_BASE),
+                };
+                match (action, timeout, base) {
+                    (Some(action), Some(timeout), Some(base)) => {
+                        config.action = action;
+                        config.timeout = timeout;
+                        match cpu.attach_mapped_device(Box::new(Watchdog::new(config)), base) {
+                            Ok(_) => println!("Watchdog at 0x{:02X}: {:?} after {} cycles without a kick.", base, action, config.timeout as u64 * config.cycles_per_unit),
+                            Err(e) => println!("watchdog: {}", e),
+                        }
+                    }
+                    _ => println!("Usage: watchdog <reset|nmi|fault> [timeout 1-255] [base]"),
+                }
+            }
+            "devices" => {
+                cpu.sync_devices();
+                let devices = cpu.device_info();
+                if devices.is_empty() {
+                    println!("No devices attached.");
+                }
+                for d in devices {
+                    let window = match d.base {
+                        Some(b) => format!("0x{:02X}-0x{:02X}", b, b + d.mmio_size - 1),
+                        None => "unmapped".to_string(),
+                    };
+                    let irq = match (d.irq_connected, d.irq_pending) {
+                        (false, _) => "irq off",
+                        (true, true) => "IRQ",
+                        (true, false) => "",
+                    };
+                    println!("{:>4} {:<11} {:<7} {}", d.id.to_string(), window, irq, d.description);
+                }
+            }
+            "detach" => match parts.next().and_then(|s| s.trim_start_matches('#').parse().ok()) {
+                Some(n) => match cpu.detach_device(DeviceId(n)) {
+                    Some(dev) => println!("Detached {}: {}", DeviceId(n), dev.describe()),
+                    None => println!("No device {}.", DeviceId(n)),
+                },
+                None => println!("Usage: detach <id>  (ids are listed by 'devices')"),
+            },
             "step" => {
                 let n: usize = parts.next().and_then(|s| s.parse().ok()).unwrap_or(1);
                 let (executed, cycles) = cpu.step_n_instructions(n);
                 println!("Stepped {} instruction(s) consuming {} cycles. PC={:02X} cycles={}", executed, cycles, cpu.pc, cpu.cycles);
             }
+            "tick" => {
+                let n: usize = parts.next().and_then(|s| s.parse().ok()).unwrap_or(1);
+                let (start, mut retired) = (cpu.cycles, 0);
+                for _ in 0..n {
+                    let before = cpu.cycles;
+                    if cpu.tick() {
+                        retired += 1;
+                    }
+                    // a halted CPU with no instruction in flight does not advance
+                    if cpu.cycles == before {
+                        break;
+                    }
+                }
+                println!("Ticked {} cycle(s), {} instruction(s) completed. PC={:02X} cycles={}", cpu.cycles - start, retired, cpu.pc, cpu.cycles);
+            }
+            "mode" => {
+                match parts.next() {
+                    Some("instr") => cpu.mode = ExecMode::Instruction,
+                    Some("micro") => cpu.mode = ExecMode::MicroCycle,
+                    Some(other) => println!("Unknown mode '{}'. Use 'instr' or 'micro'.", other),
+                    None => {}
+                }
+                println!("Mode: {:?}", cpu.mode);
+            }
+            "bench" => {
+                let n = parts.next().and_then(|s| s.parse().ok()).unwrap_or(1_000_000);
+                let b = blocks::benchmark_default(n);
+                println!("{} instructions of the benchmark loop:", b.instructions);
+                println!("  interpreter  {:>12.0} instr/s", b.interpreter);
+                println!("  predecoded   {:>12.0} instr/s  ({:.2}x)", b.predecoded, b.speedup());
+                if let Some(c) = &cpu.block_cache {
+                    println!("This CPU: {} block(s) cached, {} built, {} hits, {} invalidated.", c.len(), c.stats.built, c.stats.hits, c.stats.invalidated);
+                }
+            }
+            "jit" => {
+                match parts.next() {
+                    Some("on") if cpu.jit.is_none() => match Jit::new() {
+                        Ok(jit) => cpu.jit = Some(jit),
+                        Err(e) => println!("{}", e),
+                    },
+                    Some("off") => cpu.jit = None,
+                    Some("on") | None => {}
+                    Some(other) => println!("Unknown jit setting '{}'. Use 'on' or 'off'.", other),
+                }
+                match &cpu.jit {
+                    Some(j) => println!(
+                        "JIT on: {} block(s) translated ({} translations, {} rejected), {} native runs, {} instructions.",
+                        j.translated_blocks(), j.stats.translated, j.stats.rejected, j.stats.native_runs, j.stats.native_instructions
+                    ),
+                    None => println!("JIT off."),
+                }
+            }
+            "sched" => {
+                match parts.next() {
+                    Some("event") => cpu.scheduling = Scheduling::EventDriven,
+                    Some("cycle") => cpu.scheduling = Scheduling::PerCycle,
+                    Some(other) => println!("Unknown scheduling '{}'. Use 'event' or 'cycle'.", other),
+                    None => {}
+                }
+                println!("Device scheduling: {:?}", cpu.scheduling);
+            }
             "dump" => {
                 cpu.dump_state();
             }
@@ -92,7 +554,7 @@ pub fn run_repl() {
                 println!("R: {:?}", cpu.regs);
             }
             "mem" => {
-                let a = parts.next().and_then(|s| parse_num(s));
+                let a = parts.next().and_then(parse_num);
                 let l = parts.next().and_then(|s| s.parse::<usize>().ok()).unwrap_or(16);
                 if let Some(addr) = a {
                     for i in 0..l {
@@ -121,9 +583,74 @@ fn print_help() {
     println!(
         r#"Commands:
   asm                Enter assembler mode (end with a single '.' line). Assembles and loads at address 0.
-  run                Run until HLT.
-  trace              Run with trace output.
+  run [N]            Run until HLT, a fault or a breakpoint, or for at most N cycles
+                     (default 10000000), and print why the run stopped.
+  trace [N]          Like run, with trace output.
+  break [addr]       Toggle a breakpoint at <addr> and list breakpoints.
+  profile [N] [cycles] [file]
+                     Run with the profiler (at most <cycles>, default 10000000) and print the N
+                     hottest addresses (default 10). If <file> is given, also write flamegraph
+                     folded stacks to it.
+  coverage [cycles] [file]
+                     Run with coverage tracking (at most <cycles>) and print a summary. If <file>
+                     is given, also write an LCOV tracefile to it.
+  pipeline [opts]    Run on the 5-stage pipeline model and print a per-cycle diagram.
+                     Options: fwd|nofwd (forwarding, default on), stall|pnt|btb[:N]
+                     (branch handling, default pnt).
+  cache              Show I-cache / D-cache statistics.
+  cache i|d <size> <line> <ways> [lru|fifo|random[:seed]] [wb|wt] [hit=N] [miss=N]
+                     Configure the instruction (i) or data (d) cache.
+  cache i|d off      Remove a cache.  cache reset: clear cache contents and statistics.
+  bpred              Show per-branch prediction accuracy.
+  bpred <kind> [penalty]
+                     Use a branch predictor: nt, btfn, 1bit[:N], 2bit[:N], gshare[:bits], btb[:N].
+                     Mispredictions cost <penalty> extra cycles (default 2). 'bpred off' removes it.
+  region             List memory regions and wait-state / bus contention cycles.
+  region add <name> <start> <end> <read_wait> <write_wait> [ro]
+                     Add a region [start, end) with per-access wait states; 'ro' ignores writes.
+  region clear       Remove all regions.
+  uart <port> [base] [cycles/byte]
+                     Attach a UART (registers at <base>, default 0xF8) connected to the host:
+                     stdout, pty (prints the slave path) or file:[<in>:]<out>. (stdio is
+                     for machine files run from the command line only.)
+  display <target> [base] [vsync_cycles]
+                     Attach a display (registers at <base>, default 0xE0). Frames are dumped at
+                     every vsync as ANSI text (ansi, or live to redraw in place), to
+                     <prefix>NNNN.ppm files (ppm:<prefix>), or not at all (none).
+  keyboard <script-file> [base]
+                     Attach a keyboard (registers at <base>, default 0xF5). Keys come from a
+                     script of '<cycle> <key>' lines. (Live terminal keys, keys=tty, are for
+                     machine files run from the command line only.)
+  disk <image-file> [base] [seek_cycles] [cycles/byte]
+                     Attach block storage (registers at <base>, default 0xC8) over an image
+                     file of 16-byte sectors. Sector writes go through to the file.
+  rtc [hz|wall] [base]
+                     Attach a real-time clock (registers at <base>, default 0xBC). Time is
+                     cycles / <hz> (default 1000000) from 2000-01-01, or the host clock (wall).
+  gpio [switch-script|none] [base]
+                     Attach 8 GPIO pins (registers at <base>, default 0xB6). The LED row is
+                     redrawn as outputs change; switches flip per '<cycle> <pin> <0|1>' lines.
+  sound <wav-file> [base] [clock_hz]
+                     Attach a sound generator (registers at <base>, default 0xA8). Output is
+                     synthesized from cycles at <clock_hz> (default 1000000) into a WAV file.
+  watchdog <reset|nmi|fault> [timeout] [base]
+                     Attach a watchdog (registers at <base>, default 0xB0), running from power-on.
+                     Unless 0x5A is written to KICK every <timeout> x 256 cycles (1-255, default 255)
+                     it resets the CPU, raises an NMI (vector 0xCE) or stops it with a fault.
+  machine <file>     Replace the CPU with one built from a machine description (memory, regions,
+                     clock, devices and program; see machine::MachineSpec).
+  devices            List attached devices: id, register window, interrupt line and state.
+  detach <id>        Remove a device.
   step [N]           Execute N instructions (default 1).
+  tick [N]           Advance N cycles, one micro-step per cycle (default 1).
+  mode [instr|micro] Show or set the execution mode used by run/step/trace.
+  bench [N]          Time N instructions (default 1000000) of a loop on the plain interpreter
+                     and with predecoded basic blocks, and show this CPU's block cache stats.
+  jit [on|off]       Show or set translation of hot blocks to host code (x86-64 Linux) for run,
+                     with its stats. Results are the same as interpreting.
+  sched [event|cycle]
+                     Show or set device scheduling: only at the cycles devices ask for (event,
+                     default) or every device on every cycle (cycle). Results are the same.
   dump               Dump CPU state.
   regs               Print registers.
   mem <addr> <len>   Dump memory starting at <addr> for <len> bytes (len defaults to 16).
@@ -133,6 +660,78 @@ fn print_help() {
     );
 }
 
+fn print_cache_stats(cpu: &CPU) {
+    if cpu.icache.is_none() && cpu.dcache.is_none() {
+        println!("No caches configured.");
+    }
+    if let Some(c) = &cpu.icache {
+        println!("{}", c.report("I-cache"));
+    }
+    if let Some(c) = &cpu.dcache {
+        println!("{}", c.report("D-cache"));
+    }
+}
+
+fn parse_cache_config(args: &[&str]) -> Result<Cache, String> {
+    if args.len() < 3 {
+        return Err("expected <size> <line> <ways>".to_string());
+    }
+    let num = |s: &str| parse_num(s).ok_or_else(|| format!("invalid number '{}'", s));
+    let mut config = CacheConfig { size: num(args[0])?, line_size: num(args[1])?, associativity: num(args[2])?, ..CacheConfig::default() };
+    for opt in &args[3..] {
+        match *opt {
+            "lru" => config.replacement = Replacement::Lru,
+            "fifo" => config.replacement = Replacement::Fifo,
+            "wb" => config.write_policy = WritePolicy::WriteBack,
+            "wt" => config.write_policy = WritePolicy::WriteThrough,
+            o if o.starts_with("random") => {
+                let seed = o.strip_prefix("random:").and_then(parse_num).unwrap_or(1) as u64;
+                config.replacement = Replacement::Random { seed };
+            }
+            o if o.starts_with("hit=") => config.hit_latency = num(&o[4..])? as u64,
+            o if o.starts_with("miss=") => config.miss_latency = num(&o[5..])? as u64,
+            o => return Err(format!("unknown option '{}'", o)),
+        }
+    }
+    Cache::new(config)
+}
+
+fn parse_predictor(s: &str) -> Option<PredictorKind> {
+    let (name, arg) = match s.split_once(':') {
+        Some((n, a)) => (n, Some(parse_num(a)?)),
+        None => (s, None),
+    };
+    match name {
+        "nt" => Some(PredictorKind::StaticNotTaken),
+        "btfn" => Some(PredictorKind::StaticBackwardTaken),
+        "1bit" => Some(PredictorKind::OneBit { entries: arg.unwrap_or(16) }),
+        "2bit" => Some(PredictorKind::TwoBit { entries: arg.unwrap_or(16) }),
+        "gshare" => Some(PredictorKind::Gshare { history_bits: arg.unwrap_or(4) as u32 }),
+        "btb" => Some(PredictorKind::Btb { entries: arg.unwrap_or(16) }),
+        _ => None,
+    }
+}
+
+/// A device of `spec` that reads stdin, which the REPL needs for its own input.
+fn terminal_device(spec: &MachineSpec) -> Option<&DeviceSpec> {
+    spec.devices.iter().find(|d| {
+        let arg = |key: &str| d.args.iter().find(|(k, _)| k == key).and_then(|(_, v)| v.as_deref());
+        (d.kind == "uart" && arg("port") == Some("stdio")) || (d.kind == "keyboard" && arg("keys") == Some("tty"))
+    })
+}
+
+/// Numeric arguments of a run command in order, and the first other one (a file).
+fn split_run_args<'a>(args: impl Iterator<Item = &'a str>) -> (Vec<u64>, Option<&'a str>) {
+    let (mut nums, mut path) = (Vec::new(), None);
+    for arg in args {
+        match arg.parse() {
+            Ok(n) => nums.push(n),
+            Err(_) => path = path.or(Some(arg)),
+        }
+    }
+    (nums, path)
+}
+
 fn parse_num(s: &str) -> Option<usize> {
     let s = s.trim();
     if s.starts_with("0x") || s.starts_with("0X") {
@@ -0,0 +1,275 @@
+// src/rtc.rs
+use crate::device::Device;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Default base address of the RTC's register window (see `CPU::attach_mapped_device`).
+pub const RTC_BASE: usize = 0xBC;
+
+/// Register offsets within the RTC's window. The time registers are read-only binary
+/// values: SEC 0-59, MIN 0-59, HOUR 0-23, WEEKDAY 0-6 (Sunday = 0), DAY 1-31,
+/// MONTH 1-12, YEAR since 2000.
+pub const RTC_SEC: usize = 0;
+pub const RTC_MIN: usize = 1;
+pub const RTC_HOUR: usize = 2;
+pub const RTC_WEEKDAY: usize = 3;
+pub const RTC_DAY: usize = 4;
+pub const RTC_MONTH: usize = 5;
+pub const RTC_YEAR: usize = 6;
+/// Alarm time of day; the alarm fires every day when the clock reaches it.
+pub const RTC_ALARM_SEC: usize = 7;
+pub const RTC_ALARM_MIN: usize = 8;
+pub const RTC_ALARM_HOUR: usize = 9;
+/// CTRL (see the `CTRL_*` bits).
+pub const RTC_CTRL: usize = 10;
+/// STATUS: `STATUS_ALARM` once the alarm has fired. Reading it clears the flag.
+pub const RTC_STATUS: usize = 11;
+/// Number of registers.
+pub const RTC_REGS: usize = 12;
+
+/// Enable the alarm.
+pub const CTRL_ALARM: u8 = 0x01;
+/// Interrupt when the alarm fires.
+pub const CTRL_ALARM_IRQ: u8 = 0x02;
+
+pub const STATUS_ALARM: u8 = 0x01;
+
+const DAY_SECS: u64 = 86_400;
+
+/// Broken-down UTC time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u32,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    /// 0 = Sunday.
+    pub weekday: u8,
+}
+
+impl DateTime {
+    /// Convert seconds since 1970-01-01 00:00:00 UTC.
+    pub fn from_unix(secs: u64) -> Self {
+        let days = secs / DAY_SECS;
+        let tod = secs % DAY_SECS;
+        // civil-from-days over 400-year eras starting 0000-03-01
+        let z = days as i64 + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z - era * 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+        let year = (yoe + era * 400 + (month <= 2) as i64) as u32;
+        DateTime {
+            year,
+            month,
+            day,
+            hour: (tod / 3600) as u8,
+            minute: (tod / 60 % 60) as u8,
+            second: (tod % 60) as u8,
+            // 1970-01-01 was a Thursday
+            weekday: ((days + 4) % 7) as u8,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RtcConfig {
+    /// CPU clock frequency: virtual time is `cycles / clock_hz` seconds after `epoch`.
+    pub clock_hz: u64,
+    /// Start of virtual time, in seconds since 1970 (default 2000-01-01 00:00:00).
+    pub epoch: u64,
+    /// Follow the host's wall clock instead of virtual time. Runs are then not reproducible.
+    pub wall_clock: bool,
+}
+
+impl Default for RtcConfig {
+    fn default() -> Self {
+        RtcConfig { clock_hz: 1_000_000, epoch: 946_684_800, wall_clock: false }
+    }
+}
+
+/// Real-time clock. By default time is derived from the cycle count, so the same
+/// program sees the same times on every run.
+#[derive(Debug)]
+pub struct Rtc {
+    config: RtcConfig,
+    /// Current time in seconds since 1970.
+    now: u64,
+    alarm: [u8; 3],
+    ctrl: u8,
+    fired: bool,
+}
+
+impl Rtc {
+    pub fn new(config: RtcConfig) -> Self {
+        let config = RtcConfig { clock_hz: config.clock_hz.max(1), ..config };
+        let mut rtc = Rtc { config, now: 0, alarm: [0; 3], ctrl: 0, fired: false };
+        rtc.now = rtc.time_at(0);
+        rtc
+    }
+
+    /// Seconds since 1970 at `cycle`.
+    fn time_at(&self, cycle: u64) -> u64 {
+        if self.config.wall_clock {
+            SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+        } else {
+            self.config.epoch + cycle / self.config.clock_hz
+        }
+    }
+
+    /// Current time in seconds since 1970.
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    pub fn datetime(&self) -> DateTime {
+        DateTime::from_unix(self.now)
+    }
+
+    fn alarm_secs(&self) -> u64 {
+        (self.alarm[2] as u64 % 24) * 3600 + (self.alarm[1] as u64 % 60) * 60 + self.alarm[0] as u64 % 60
+    }
+
+    /// Whether the alarm time of day falls in (from, to].
+    fn alarm_between(&self, from: u64, to: u64) -> bool {
+        if to <= from {
+            return false;
+        }
+        let mut at = from - from % DAY_SECS + self.alarm_secs();
+        if at <= from {
+            at += DAY_SECS;
+        }
+        at <= to
+    }
+}
+
+impl Device for Rtc {
+    fn describe(&self) -> String {
+        let t = self.datetime();
+        format!(
+            "rtc {:04}-{:02}-{:02} {:02}:{:02}:{:02} alarm={:02}:{:02}:{:02} ctrl={:02X} fired={}",
+            t.year, t.month, t.day, t.hour, t.minute, t.second, self.alarm[2], self.alarm[1], self.alarm[0], self.ctrl, self.fired
+        )
+    }
+
+    fn tick(&mut self, current_cycle: u64) {
+        let now = self.time_at(current_cycle);
+        if self.ctrl & CTRL_ALARM != 0 && self.alarm_between(self.now, now) {
+            self.fired = true;
+        }
+        self.now = now;
+    }
+
+    /// The start of the alarm second, while the alarm can interrupt.
+    fn next_event(&self, now: u64) -> Option<u64> {
+        if self.ctrl & CTRL_ALARM == 0 || self.ctrl & CTRL_ALARM_IRQ == 0 {
+            return None;
+        }
+        if self.config.wall_clock {
+            return Some(now + 1);
+        }
+        let secs = self.time_at(now);
+        let mut at = secs - secs % DAY_SECS + self.alarm_secs();
+        if at <= secs {
+            at += DAY_SECS;
+        }
+        Some((at - self.config.epoch) * self.config.clock_hz)
+    }
+
+    fn mmio_size(&self) -> usize {
+        RTC_REGS
+    }
+
+    fn mmio_read(&mut self, offset: usize) -> u8 {
+        let t = self.datetime();
+        match offset {
+            RTC_SEC => t.second,
+            RTC_MIN => t.minute,
+            RTC_HOUR => t.hour,
+            RTC_WEEKDAY => t.weekday,
+            RTC_DAY => t.day,
+            RTC_MONTH => t.month,
+            RTC_YEAR => t.year.saturating_sub(2000).min(255) as u8,
+            RTC_ALARM_SEC..=RTC_ALARM_HOUR => self.alarm[offset - RTC_ALARM_SEC],
+            RTC_CTRL => self.ctrl,
+            RTC_STATUS => {
+                let s = self.fired as u8 * STATUS_ALARM;
+                self.fired = false;
+                s
+            }
+            _ => 0,
+        }
+    }
+
+    fn mmio_write(&mut self, offset: usize, value: u8) {
+        match offset {
+            RTC_ALARM_SEC..=RTC_ALARM_HOUR => self.alarm[offset - RTC_ALARM_SEC] = value,
+            RTC_CTRL => self.ctrl = value,
+            _ => {}
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.fired && self.ctrl & CTRL_ALARM_IRQ != 0
+    }
+
+    fn watches_fetches(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::cpu::CPU;
+
+    #[test]
+    fn calendar_and_wall_clock() {
+        let t = DateTime::from_unix(946_684_800);
+        assert_eq!((t.year, t.month, t.day, t.weekday), (2000, 1, 1, 6));
+        // leap day
+        let t = DateTime::from_unix(1_709_210_096);
+        assert_eq!((t.year, t.month, t.day, t.hour, t.minute, t.second), (2024, 2, 29, 12, 34, 56));
+        assert_eq!(t.weekday, 4);
+
+        let mut rtc = Rtc::new(RtcConfig { clock_hz: 10, ..RtcConfig::default() });
+        rtc.tick(10 * 3725);
+        assert_eq!((rtc.mmio_read(RTC_HOUR), rtc.mmio_read(RTC_MIN), rtc.mmio_read(RTC_SEC)), (1, 2, 5));
+        assert_eq!(rtc.mmio_read(RTC_YEAR), 0);
+
+        let wall = Rtc::new(RtcConfig { wall_clock: true, ..RtcConfig::default() });
+        assert!(wall.datetime().year >= 2024);
+    }
+
+    #[test]
+    fn alarm_interrupt_is_reproducible() {
+        // alarm at 00:00:03; the handler records the seconds register
+        let main = r#"
+            LDI R0, 3
+            STORE R0, 0xC3      ; ALARM_SEC
+            STORE R0, 0xC6      ; CTRL = ALARM | ALARM_IRQ
+            EI
+            wait:
+            JZ R3, wait
+            HLT
+        "#;
+        let handler = "LOAD R2, 0xC7\nLOAD R1, 0xBC\nLDI R3, 1\nRETI";
+        let run = || {
+            let mut cpu = CPU::new();
+            cpu.attach_mapped_device(Box::new(Rtc::new(RtcConfig { clock_hz: 100, ..RtcConfig::default() })), RTC_BASE).unwrap();
+            cpu.mem.write_bytes(0xD0, &assemble(handler).unwrap());
+            cpu.load(&assemble(main).unwrap(), 0);
+            cpu.run();
+            (cpu.regs[1], cpu.regs[2], cpu.cycles)
+        };
+        let (sec, status, cycles) = run();
+        assert_eq!((sec, status), (3, STATUS_ALARM));
+        assert!((300..320).contains(&cycles), "{}", cycles);
+        assert_eq!(run(), (sec, status, cycles));
+    }
+}
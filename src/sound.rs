@@ -0,0 +1,362 @@
+// src/sound.rs
+use crate::device::Device;
+use std::cell::RefCell;
+use std::fmt::{Debug, Formatter};
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::rc::Rc;
+
+/// Default base address of the sound generator's register window (see `CPU::attach_mapped_device`).
+pub const SOUND_BASE: usize = 0xA8;
+
+/// Register offsets within the sound generator's window.
+/// Square channel 0 / 1 half-period in units of `SoundConfig::cycles_per_unit` (0 = silent),
+/// and volume 0-15. Writing a volume (re)starts that channel's envelope.
+pub const SOUND_TONE0: usize = 0;
+pub const SOUND_VOL0: usize = 1;
+pub const SOUND_TONE1: usize = 2;
+pub const SOUND_VOL1: usize = 3;
+/// Noise channel: LFSR shift period in units and volume.
+pub const SOUND_NOISE: usize = 4;
+pub const SOUND_VOL_NOISE: usize = 5;
+/// ENV: cycles between envelope decay steps, in units of `SoundConfig::envelope_unit`.
+pub const SOUND_ENV: usize = 6;
+/// CTRL (see the `CTRL_*` bits).
+pub const SOUND_CTRL: usize = 7;
+/// Number of registers.
+pub const SOUND_REGS: usize = 8;
+
+/// Channel enables.
+pub const CTRL_TONE0: u8 = 0x01;
+pub const CTRL_TONE1: u8 = 0x02;
+pub const CTRL_NOISE: u8 = 0x04;
+/// Decay the channel's volume by one step every ENV period down to 0.
+pub const CTRL_ENV_TONE0: u8 = 0x10;
+pub const CTRL_ENV_TONE1: u8 = 0x20;
+pub const CTRL_ENV_NOISE: u8 = 0x40;
+
+const CHANNELS: usize = 3;
+const MAX_VOLUME: u8 = 15;
+
+/// Receives synthesized mono 16-bit samples.
+pub trait SampleSink {
+    fn samples(&mut self, samples: &[i16]);
+}
+
+impl Debug for dyn SampleSink {
+    fn fmt(&self, _: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        Ok(())
+    }
+}
+
+impl<T: SampleSink + ?Sized> SampleSink for Rc<RefCell<T>> {
+    fn samples(&mut self, samples: &[i16]) {
+        self.borrow_mut().samples(samples);
+    }
+}
+
+/// Keeps every sample in memory.
+impl SampleSink for Vec<i16> {
+    fn samples(&mut self, samples: &[i16]) {
+        self.extend_from_slice(samples);
+    }
+}
+
+/// Write a 44-byte PCM WAV header for mono 16-bit audio with `data_len` bytes of samples.
+pub fn write_wav_header<W: Write>(mut out: W, sample_rate: u32, data_len: u32) -> io::Result<()> {
+    out.write_all(b"RIFF")?;
+    out.write_all(&(36 + data_len).to_le_bytes())?;
+    out.write_all(b"WAVEfmt ")?;
+    out.write_all(&16u32.to_le_bytes())?;
+    out.write_all(&1u16.to_le_bytes())?; // PCM
+    out.write_all(&1u16.to_le_bytes())?; // mono
+    out.write_all(&sample_rate.to_le_bytes())?;
+    out.write_all(&(sample_rate * 2).to_le_bytes())?;
+    out.write_all(&2u16.to_le_bytes())?; // block align
+    out.write_all(&16u16.to_le_bytes())?; // bits per sample
+    out.write_all(b"data")?;
+    out.write_all(&data_len.to_le_bytes())
+}
+
+/// Streams samples to a WAV file. The header sizes are filled in when it is dropped.
+pub struct WavSink {
+    out: BufWriter<File>,
+    sample_rate: u32,
+    data_len: u32,
+}
+
+impl WavSink {
+    pub fn create(path: &str, sample_rate: u32) -> Result<Self, String> {
+        let mut out = BufWriter::new(File::create(path).map_err(|e| format!("{}: {}", path, e))?);
+        write_wav_header(&mut out, sample_rate, 0).map_err(|e| format!("{}: {}", path, e))?;
+        Ok(WavSink { out, sample_rate, data_len: 0 })
+    }
+}
+
+impl SampleSink for WavSink {
+    fn samples(&mut self, samples: &[i16]) {
+        for s in samples {
+            let _ = self.out.write_all(&s.to_le_bytes());
+        }
+        self.data_len += 2 * samples.len() as u32;
+    }
+}
+
+impl Drop for WavSink {
+    fn drop(&mut self) {
+        let _ = self.out.seek(SeekFrom::Start(0));
+        let _ = write_wav_header(&mut self.out, self.sample_rate, self.data_len);
+        let _ = self.out.flush();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SoundConfig {
+    /// CPU clock frequency: samples are produced at `sample_rate` per `clock_hz` cycles.
+    pub clock_hz: u64,
+    pub sample_rate: u32,
+    /// Cycles per tone/noise period unit.
+    pub cycles_per_unit: u64,
+    /// Cycles per envelope period unit.
+    pub envelope_unit: u64,
+}
+
+impl Default for SoundConfig {
+    fn default() -> Self {
+        SoundConfig { clock_hz: 1_000_000, sample_rate: 22_050, cycles_per_unit: 16, envelope_unit: 1024 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Channel {
+    period: u8,
+    volume: u8,
+    /// Volume after the envelope.
+    level: u8,
+    counter: u64,
+    high: bool,
+    env_counter: u64,
+}
+
+/// Programmable sound generator: two square-wave channels and a noise channel with
+/// per-channel volume envelopes. The output is synthesized from elapsed cycles, so a
+/// run always produces the same audio; samples go to a `SampleSink` such as a WAV file.
+#[derive(Debug)]
+pub struct SoundGenerator {
+    config: SoundConfig,
+    sink: Box<dyn SampleSink>,
+    channels: [Channel; CHANNELS],
+    env: u8,
+    ctrl: u8,
+    /// 15-bit LFSR for the noise channel.
+    lfsr: u16,
+    pending: Vec<i16>,
+    produced: u64,
+}
+
+impl SoundGenerator {
+    pub fn new(sink: Box<dyn SampleSink>, config: SoundConfig) -> Self {
+        let config = SoundConfig {
+            clock_hz: config.clock_hz.max(1),
+            cycles_per_unit: config.cycles_per_unit.max(1),
+            envelope_unit: config.envelope_unit.max(1),
+            ..config
+        };
+        SoundGenerator {
+            config,
+            sink,
+            channels: [Channel::default(); CHANNELS],
+            env: 0,
+            ctrl: 0,
+            lfsr: 1,
+            pending: Vec::new(),
+            produced: 0,
+        }
+    }
+
+    /// Samples synthesized so far.
+    pub fn samples_produced(&self) -> u64 {
+        self.produced
+    }
+
+    /// Hand buffered samples to the sink.
+    pub fn flush(&mut self) {
+        if !self.pending.is_empty() {
+            self.sink.samples(&self.pending);
+            self.pending.clear();
+        }
+    }
+
+    fn sample(&self) -> i16 {
+        let mut mix = 0i32;
+        for (i, ch) in self.channels.iter().enumerate() {
+            if self.ctrl & (1 << i) != 0 && ch.period != 0 {
+                let level = ch.level as i32;
+                mix += if ch.high { level } else { -level };
+            }
+        }
+        (mix * i16::MAX as i32 / (CHANNELS as i32 * MAX_VOLUME as i32)) as i16
+    }
+}
+
+impl Device for SoundGenerator {
+    fn describe(&self) -> String {
+        let levels: Vec<String> = self.channels.iter().map(|c| format!("{}/{}", c.period, c.level)).collect();
+        format!("sound ctrl={:02X} env={:02X} tone/level={} samples={}", self.ctrl, self.env, levels.join(" "), self.produced)
+    }
+
+    fn tick(&mut self, current_cycle: u64) {
+        let unit = self.config.cycles_per_unit;
+        let env_period = self.env as u64 * self.config.envelope_unit;
+        for (i, ch) in self.channels.iter_mut().enumerate() {
+            if ch.period != 0 {
+                ch.counter += 1;
+                if ch.counter >= ch.period as u64 * unit {
+                    ch.counter = 0;
+                    if i == 2 {
+                        let bit = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+                        self.lfsr = (self.lfsr >> 1) | (bit << 14);
+                        ch.high = self.lfsr & 1 != 0;
+                    } else {
+                        ch.high = !ch.high;
+                    }
+                }
+            }
+            if self.ctrl & (0x10 << i) != 0 && env_period != 0 && ch.level > 0 {
+                ch.env_counter += 1;
+                if ch.env_counter >= env_period {
+                    ch.env_counter = 0;
+                    ch.level -= 1;
+                }
+            }
+        }
+        let due = current_cycle as u128 * self.config.sample_rate as u128 / self.config.clock_hz as u128;
+        while (self.produced as u128) < due {
+            let s = self.sample();
+            self.pending.push(s);
+            self.produced += 1;
+        }
+        if self.pending.len() >= 1024 {
+            self.flush();
+        }
+    }
+
+    fn mmio_size(&self) -> usize {
+        SOUND_REGS
+    }
+
+    fn mmio_read(&mut self, offset: usize) -> u8 {
+        match offset {
+            SOUND_TONE0 | SOUND_TONE1 | SOUND_NOISE => self.channels[offset / 2].period,
+            SOUND_VOL0 | SOUND_VOL1 | SOUND_VOL_NOISE => self.channels[offset / 2].level,
+            SOUND_ENV => self.env,
+            SOUND_CTRL => self.ctrl,
+            _ => 0,
+        }
+    }
+
+    fn mmio_write(&mut self, offset: usize, value: u8) {
+        match offset {
+            SOUND_TONE0 | SOUND_TONE1 | SOUND_NOISE => self.channels[offset / 2].period = value,
+            SOUND_VOL0 | SOUND_VOL1 | SOUND_VOL_NOISE => {
+                let ch = &mut self.channels[offset / 2];
+                ch.volume = value.min(MAX_VOLUME);
+                ch.level = ch.volume;
+                ch.env_counter = 0;
+            }
+            SOUND_ENV => self.env = value,
+            SOUND_CTRL => self.ctrl = value,
+            _ => {}
+        }
+    }
+
+    fn watches_fetches(&self) -> bool {
+        false
+    }
+}
+
+impl Drop for SoundGenerator {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::cpu::CPU;
+
+    fn zero_crossings(samples: &[i16]) -> usize {
+        samples.windows(2).filter(|w| (w[0] < 0) != (w[1] < 0)).count()
+    }
+
+    #[test]
+    fn square_wave_with_envelope_from_program() {
+        // half-period of 5 units x 10 cycles: a 10 kHz square wave at a 1 MHz clock
+        let src = r#"
+            LDI R0, 5
+            STORE R0, 0xA8      ; TONE0
+            LDI R0, 1
+            STORE R0, 0xAE      ; ENV
+            LDI R0, 0x11
+            STORE R0, 0xAF      ; CTRL = TONE0 | ENV_TONE0
+            LDI R0, 15
+            STORE R0, 0xA9      ; VOL0
+            LDI R1, 1
+            LDI R2, 0
+            loop:
+            SUB R2, R1
+            JZ R2, done
+            JMP loop
+            done:
+            HLT
+        "#;
+        let config = SoundConfig { clock_hz: 1_000_000, sample_rate: 20_000, cycles_per_unit: 10, envelope_unit: 100 };
+        let out: Rc<RefCell<Vec<i16>>> = Rc::default();
+        let mut cpu = CPU::new();
+        cpu.attach_mapped_device(Box::new(SoundGenerator::new(Box::new(out.clone()), config)), SOUND_BASE).unwrap();
+        cpu.load(&assemble(src).unwrap(), 0);
+        cpu.run();
+        let cycles = cpu.cycles;
+        drop(cpu);
+
+        let samples = out.borrow();
+        assert_eq!(samples.len() as u64, cycles * 20_000 / 1_000_000);
+        let peak = |s: &[i16]| s.iter().map(|v| v.unsigned_abs()).max().unwrap_or(0);
+        // volume 15 on one of three channels peaks at 15 * 32767 / 45 = 10922, then decays
+        // one step per 100 cycles (2 samples) and is silent after 1500 cycles
+        let start = samples.iter().position(|&s| s != 0).unwrap();
+        assert_eq!(peak(&samples[start..start + 2]), 10922);
+        assert!(peak(&samples[start + 10..start + 12]) < 10922);
+        assert_eq!(peak(&samples[start + 32..]), 0);
+        assert!(zero_crossings(&samples[start..start + 20]) >= 3);
+    }
+
+    #[test]
+    fn noise_channel_to_wav_file() {
+        let path = std::env::temp_dir().join(format!("toy_cpu_sound_{}.wav", std::process::id()));
+        let path_str = path.to_str().unwrap();
+        let config = SoundConfig { clock_hz: 8000, sample_rate: 8000, cycles_per_unit: 1, envelope_unit: 1 };
+        let mut psg = SoundGenerator::new(Box::new(WavSink::create(path_str, 8000).unwrap()), config);
+        psg.mmio_write(SOUND_NOISE, 1);
+        psg.mmio_write(SOUND_VOL_NOISE, 15);
+        psg.mmio_write(SOUND_CTRL, CTRL_NOISE);
+        for cycle in 1..=1000 {
+            psg.tick(cycle);
+        }
+        assert_eq!(psg.samples_produced(), 1000);
+        drop(psg);
+
+        let wav = std::fs::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(wav.len(), 44 + 2000);
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(wav[40..44].try_into().unwrap()), 2000);
+        let samples: Vec<i16> = wav[44..].chunks(2).map(|c| i16::from_le_bytes([c[0], c[1]])).collect();
+        // noise is irregular: both polarities, without the fixed period of a square wave
+        assert!(samples.iter().any(|&s| s > 0) && samples.iter().any(|&s| s < 0));
+        let runs: Vec<usize> = samples.chunk_by(|a, b| a == b).map(|r| r.len()).collect();
+        assert!(runs.iter().max() > runs.iter().min());
+    }
+}
@@ -0,0 +1,357 @@
+// src/storage.rs
+use crate::device::Device;
+use crate::memory::Memory;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// Default base address of the storage device's register window (see `CPU::attach_mapped_device`).
+pub const STORAGE_BASE: usize = 0xC8;
+
+/// Register offsets within the storage device's window.
+/// SECTOR: first sector of the transfer.
+pub const STORAGE_SECTOR: usize = 0;
+/// ADDR: memory address of the transfer.
+pub const STORAGE_ADDR: usize = 1;
+/// COUNT: number of sectors (0 completes immediately).
+pub const STORAGE_COUNT: usize = 2;
+/// CMD: writing `CMD_READ` or `CMD_WRITE` starts a transfer (ignored while busy).
+pub const STORAGE_CMD: usize = 3;
+/// STATUS: `STATUS_BUSY` / `STATUS_ERROR`, so 0 means the last command succeeded.
+/// Reading it acknowledges the completion interrupt.
+pub const STORAGE_STATUS: usize = 4;
+/// CTRL (see `CTRL_IRQ`).
+pub const STORAGE_CTRL: usize = 5;
+/// Number of registers.
+pub const STORAGE_REGS: usize = 6;
+
+/// Copy sectors from the image into memory.
+pub const CMD_READ: u8 = 1;
+/// Copy memory into sectors of the image.
+pub const CMD_WRITE: u8 = 2;
+
+pub const STATUS_BUSY: u8 = 0x01;
+/// The last command was unknown, addressed a sector past the end of the image, or
+/// could not write through to the image file.
+pub const STATUS_ERROR: u8 = 0x80;
+
+/// Interrupt when a command completes.
+pub const CTRL_IRQ: u8 = 0x01;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StorageConfig {
+    pub sector_size: usize,
+    /// Cycles before the first byte moves whenever the head changes sector position.
+    pub seek_cycles: u64,
+    /// Cycles per transferred byte; each byte also takes one bus cycle from the CPU.
+    pub cycles_per_byte: u64,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig { sector_size: 16, seek_cycles: 50, cycles_per_byte: 2 }
+    }
+}
+
+/// A transfer in progress.
+#[derive(Debug, Clone, Copy)]
+struct Transfer {
+    write: bool,
+    sector: usize,
+    addr: usize,
+    sectors_left: usize,
+    /// Byte offset within the current sector.
+    offset: usize,
+    /// No bus request before this cycle (seek, or pacing between bytes).
+    ready_at: Option<u64>,
+}
+
+/// Block storage with sector transfers straight to and from memory (like DMA, each
+/// byte steals a bus cycle). The image lives in memory; a file-backed device writes
+/// modified sectors through to its file.
+#[derive(Debug)]
+pub struct BlockDevice {
+    config: StorageConfig,
+    image: Vec<u8>,
+    file: Option<File>,
+    sector: u8,
+    addr: u8,
+    count: u8,
+    ctrl: u8,
+    error: bool,
+    irq: bool,
+    /// Sector the head is over (next sector after the last transfer).
+    head: usize,
+    transfer: Option<Transfer>,
+    cycle: u64,
+}
+
+impl BlockDevice {
+    /// Device over an in-memory image, padded to a whole number of sectors.
+    pub fn in_memory(mut image: Vec<u8>, config: StorageConfig) -> Self {
+        let config = StorageConfig { sector_size: config.sector_size.max(1), ..config };
+        image.resize(image.len().div_ceil(config.sector_size) * config.sector_size, 0);
+        BlockDevice {
+            config,
+            image,
+            file: None,
+            sector: 0,
+            addr: 0,
+            count: 0,
+            ctrl: 0,
+            error: false,
+            irq: false,
+            head: 0,
+            transfer: None,
+            cycle: 0,
+        }
+    }
+
+    /// Device backed by the image file at `path`.
+    pub fn open(path: &str, config: StorageConfig) -> Result<Self, String> {
+        let mut file = OpenOptions::new().read(true).write(true).open(path).map_err(|e| format!("{}: {}", path, e))?;
+        let mut image = Vec::new();
+        file.read_to_end(&mut image).map_err(|e| format!("{}: {}", path, e))?;
+        Ok(BlockDevice { file: Some(file), ..BlockDevice::in_memory(image, config) })
+    }
+
+    pub fn image(&self) -> &[u8] {
+        &self.image
+    }
+
+    pub fn sectors(&self) -> usize {
+        self.image.len() / self.config.sector_size
+    }
+
+    pub fn busy(&self) -> bool {
+        self.transfer.is_some()
+    }
+
+    fn start(&mut self, cmd: u8) {
+        self.error = false;
+        self.irq = false;
+        let sector = self.sector as usize;
+        let count = self.count as usize;
+        if !(cmd == CMD_READ || cmd == CMD_WRITE) || sector + count > self.sectors() {
+            self.finish(true);
+            return;
+        }
+        if count == 0 {
+            self.finish(false);
+            return;
+        }
+        let seek = if sector == self.head { 0 } else { self.config.seek_cycles };
+        self.transfer = Some(Transfer {
+            write: cmd == CMD_WRITE,
+            sector,
+            addr: self.addr as usize,
+            sectors_left: count,
+            offset: 0,
+            ready_at: Some(self.cycle + seek + self.config.cycles_per_byte),
+        });
+    }
+
+    fn finish(&mut self, error: bool) {
+        self.transfer = None;
+        self.error = error;
+        self.irq = self.ctrl & CTRL_IRQ != 0;
+    }
+
+    /// Write one sector of the image through to the backing file.
+    fn flush_sector(&mut self, sector: usize) -> std::io::Result<()> {
+        let size = self.config.sector_size;
+        match self.file.as_mut() {
+            Some(f) => {
+                let data = &self.image[sector * size..(sector + 1) * size];
+                f.seek(SeekFrom::Start((sector * size) as u64)).and_then(|_| f.write_all(data))
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl Device for BlockDevice {
+    fn describe(&self) -> String {
+        let transfer = match &self.transfer {
+            Some(t) => format!("{} sector {} ({} left)", if t.write { "writing" } else { "reading" }, t.sector, t.sectors_left),
+            None => "idle".to_string(),
+        };
+        format!(
+            "disk {} sectors sector={} addr={:02X} count={} ctrl={:02X} error={} {}",
+            self.sectors(),
+            self.sector,
+            self.addr,
+            self.count,
+            self.ctrl,
+            self.error,
+            transfer
+        )
+    }
+
+    fn tick(&mut self, current_cycle: u64) {
+        self.cycle = current_cycle;
+    }
+
+    fn bus_request(&mut self, current_cycle: u64) -> bool {
+        match self.transfer {
+            Some(t) => t.ready_at.is_none_or(|at| current_cycle >= at),
+            None => false,
+        }
+    }
+
+    fn bus_grant(&mut self, current_cycle: u64, mem: &mut Memory) {
+        let size = self.config.sector_size;
+        let Some(mut t) = self.transfer else { return };
+        let pos = t.sector * size + t.offset;
+        let addr = t.addr + (t.sector - self.sector as usize) * size + t.offset;
+        if t.write {
+            self.image[pos] = mem.read(addr);
+        } else if !mem.is_read_only(addr) {
+            mem.write(addr, self.image[pos]);
+        }
+        t.offset += 1;
+        t.ready_at = Some(current_cycle + self.config.cycles_per_byte);
+        if t.offset == size {
+            if t.write && self.flush_sector(t.sector).is_err() {
+                self.head = t.sector + 1;
+                self.finish(true);
+                return;
+            }
+            t.offset = 0;
+            t.sector += 1;
+            t.sectors_left -= 1;
+            self.head = t.sector;
+        }
+        self.transfer = Some(t);
+        if t.sectors_left == 0 {
+            self.finish(false);
+        }
+    }
+
+    fn mmio_size(&self) -> usize {
+        STORAGE_REGS
+    }
+
+    fn mmio_read(&mut self, offset: usize) -> u8 {
+        match offset {
+            STORAGE_SECTOR => self.sector,
+            STORAGE_ADDR => self.addr,
+            STORAGE_COUNT => self.count,
+            STORAGE_STATUS => {
+                self.irq = false;
+                (self.busy() as u8 * STATUS_BUSY) | (self.error as u8 * STATUS_ERROR)
+            }
+            STORAGE_CTRL => self.ctrl,
+            _ => 0,
+        }
+    }
+
+    fn mmio_write(&mut self, offset: usize, value: u8) {
+        if self.busy() && offset != STORAGE_CTRL {
+            return;
+        }
+        match offset {
+            STORAGE_SECTOR => self.sector = value,
+            STORAGE_ADDR => self.addr = value,
+            STORAGE_COUNT => self.count = value,
+            STORAGE_CMD => self.start(value),
+            STORAGE_CTRL => self.ctrl = value,
+            _ => {}
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq
+    }
+
+    fn watches_fetches(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::cpu::CPU;
+
+    #[test]
+    fn boot_loader_pulls_code_from_disk() {
+        // sector 2 holds a program that stores 42 at 0x90 and halts
+        let payload = assemble("LDI R0, 42\nSTORE R0, 0x90\nHLT").unwrap();
+        let mut image = vec![0u8; 4 * 16];
+        image[32..32 + payload.len()].copy_from_slice(&payload);
+        let loader = r#"
+            LDI R0, 2
+            STORE R0, 0xC8      ; SECTOR
+            LDI R0, 0x40
+            STORE R0, 0xC9      ; ADDR
+            LDI R0, 1
+            STORE R0, 0xCA      ; COUNT
+            STORE R0, 0xCB      ; CMD_READ
+            wait:
+            LOAD R1, 0xCC       ; STATUS
+            JZ R1, boot
+            JMP wait
+            boot:
+            JMP 0x40
+        "#;
+        let config = StorageConfig { sector_size: 16, seek_cycles: 100, cycles_per_byte: 3 };
+        let mut cpu = CPU::new();
+        cpu.attach_mapped_device(Box::new(BlockDevice::in_memory(image, config)), STORAGE_BASE).unwrap();
+        cpu.load(&assemble(loader).unwrap(), 0);
+        cpu.run();
+        assert_eq!(cpu.mem.read(0x90), 42);
+        // seek plus 16 paced bytes, each also stealing a bus cycle
+        assert!(cpu.cycles > 100 + 16 * 3, "{}", cpu.cycles);
+        assert_eq!(cpu.bus_stats.device_cycles, 16);
+    }
+
+    #[test]
+    fn file_image_write_through_and_errors() {
+        let path = std::env::temp_dir().join(format!("toy_cpu_disk_{}.img", std::process::id()));
+        let path_str = path.to_str().unwrap();
+        std::fs::write(&path, [0u8; 32]).unwrap();
+        let mut disk = BlockDevice::open(path_str, StorageConfig { sector_size: 8, seek_cycles: 0, cycles_per_byte: 1 }).unwrap();
+        assert_eq!(disk.sectors(), 4);
+
+        let mut mem = Memory::new();
+        mem.write_bytes(0x10, b"ABCDEFGH");
+        disk.mmio_write(STORAGE_SECTOR, 3);
+        disk.mmio_write(STORAGE_ADDR, 0x10);
+        disk.mmio_write(STORAGE_COUNT, 1);
+        disk.mmio_write(STORAGE_CTRL, CTRL_IRQ);
+        disk.mmio_write(STORAGE_CMD, CMD_WRITE);
+        let mut cycle = 0;
+        while disk.busy() {
+            cycle += 1;
+            if disk.bus_request(cycle) {
+                disk.bus_grant(cycle, &mut mem);
+            }
+            disk.tick(cycle);
+        }
+        assert!(disk.irq_pending());
+        assert_eq!(disk.mmio_read(STORAGE_STATUS), 0);
+        assert!(!disk.irq_pending());
+        assert_eq!(&std::fs::read(&path).unwrap()[24..], b"ABCDEFGH");
+
+        disk.mmio_write(STORAGE_COUNT, 2);
+        disk.mmio_write(STORAGE_CMD, CMD_READ);
+        assert_eq!(disk.mmio_read(STORAGE_STATUS), STATUS_ERROR);
+
+        // a failed write-through ends the command with an error
+        let read_only = File::open(&path).unwrap();
+        let mut disk = BlockDevice { file: Some(read_only), ..disk };
+        disk.mmio_write(STORAGE_COUNT, 1);
+        disk.mmio_write(STORAGE_CMD, CMD_WRITE);
+        while disk.busy() {
+            cycle += 1;
+            if disk.bus_request(cycle) {
+                disk.bus_grant(cycle, &mut mem);
+            }
+            disk.tick(cycle);
+        }
+        assert!(disk.irq_pending());
+        assert_eq!(disk.mmio_read(STORAGE_STATUS), STATUS_ERROR);
+        let _ = std::fs::remove_file(&path);
+    }
+}
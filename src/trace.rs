@@ -0,0 +1,472 @@
+// src/trace.rs
+use crate::isa::{self, Instruction};
+use crate::memory::{AccessKind, MemAccess};
+use std::cell::RefCell;
+use std::fmt::{Debug, Formatter};
+use std::io::Write;
+use std::rc::Rc;
+
+/// A register whose value changed while executing one instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegDelta {
+    pub reg: usize,
+    pub before: u8,
+    pub after: u8,
+}
+
+/// Everything observable about one executed instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub pc: usize,
+    pub opcode: u8,
+    pub operand: Option<u8>,
+    pub instr: Instruction,
+    pub next_pc: usize,
+    pub reg_deltas: Vec<RegDelta>,
+    pub z_before: bool,
+    pub z_after: bool,
+    /// Data accesses (`Read`/`Write`); instruction fetches are implied by `pc` and not listed.
+    pub mem: Vec<MemAccess>,
+    /// CPU cycle counter before the instruction started.
+    pub cycle: u64,
+    /// Cycles consumed by the instruction.
+    pub cycles: u64,
+    /// How many of those cycles a device (e.g. DMA) owned the bus.
+    pub stolen: u64,
+    /// Whether the instruction branched: every JMP, and a JZ whose register was zero.
+    /// (`next_pc` alone cannot tell when a JZ targets the next instruction.)
+    pub taken: bool,
+}
+
+impl TraceRecord {
+    /// Raw instruction bytes as fetched.
+    pub fn bytes(&self) -> Vec<u8> {
+        let mut b = vec![self.opcode];
+        if let Some(op) = self.operand {
+            b.push(op);
+        }
+        b
+    }
+}
+
+/// Trace sink. The CPU calls `record` once per executed instruction, in every run mode
+/// (`run`, `step_n_instructions`, `step_and_tick_instruction`).
+pub trait Tracer {
+    fn record(&mut self, rec: &TraceRecord);
+
+    /// Flush any buffered output. Called when the sink is detached.
+    fn finish(&mut self) {}
+}
+
+impl Debug for dyn Tracer {
+    fn fmt(&self, _: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        Ok(())
+    }
+}
+
+/// Lets callers keep a handle on a tracer (e.g. to read it back after a run)
+/// while the CPU owns a clone.
+impl<T: Tracer + ?Sized> Tracer for Rc<RefCell<T>> {
+    fn record(&mut self, rec: &TraceRecord) {
+        self.borrow_mut().record(rec);
+    }
+
+    fn finish(&mut self) {
+        self.borrow_mut().finish();
+    }
+}
+
+fn access_letter(kind: AccessKind) -> char {
+    match kind {
+        AccessKind::Fetch => 'F',
+        AccessKind::Read => 'R',
+        AccessKind::Write => 'W',
+    }
+}
+
+/// Human readable trace with disassembly, one line per instruction.
+pub struct TextTracer<W: Write> {
+    out: W,
+}
+
+impl<W: Write> TextTracer<W> {
+    pub fn new(out: W) -> Self {
+        TextTracer { out }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W: Write> Tracer for TextTracer<W> {
+    fn record(&mut self, rec: &TraceRecord) {
+        let bytes: Vec<String> = rec.bytes().iter().map(|b| format!("{:02X}", b)).collect();
+        let mut line = format!(
+            "[trace] {:02X}: {:<5} {:<16} CYC={} +{}",
+            rec.pc,
+            bytes.join(" "),
+            rec.instr.to_string(),
+            rec.cycle,
+            rec.cycles
+        );
+        if rec.stolen > 0 {
+            line.push_str(&format!(" STOLEN={}", rec.stolen));
+        }
+        for d in &rec.reg_deltas {
+            line.push_str(&format!(" R{}:{:02X}->{:02X}", d.reg, d.before, d.after));
+        }
+        if rec.z_before != rec.z_after {
+            line.push_str(&format!(" Z:{}->{}", rec.z_before as u8, rec.z_after as u8));
+        }
+        for a in &rec.mem {
+            line.push_str(&format!(" {}[{:02X}]={:02X}", access_letter(a.kind), a.addr, a.value));
+        }
+        let _ = writeln!(self.out, "{}", line);
+    }
+
+    fn finish(&mut self) {
+        let _ = self.out.flush();
+    }
+}
+
+/// JSON Lines trace: one JSON object per instruction.
+pub struct JsonLinesTracer<W: Write> {
+    out: W,
+}
+
+impl<W: Write> JsonLinesTracer<W> {
+    pub fn new(out: W) -> Self {
+        JsonLinesTracer { out }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W: Write> Tracer for JsonLinesTracer<W> {
+    fn record(&mut self, rec: &TraceRecord) {
+        let regs: Vec<String> = rec
+            .reg_deltas
+            .iter()
+            .map(|d| format!("{{\"reg\":{},\"before\":{},\"after\":{}}}", d.reg, d.before, d.after))
+            .collect();
+        let mem: Vec<String> = rec
+            .mem
+            .iter()
+            .map(|a| {
+                let kind = match a.kind {
+                    AccessKind::Fetch => "fetch",
+                    AccessKind::Read => "read",
+                    AccessKind::Write => "write",
+                };
+                format!("{{\"kind\":\"{}\",\"addr\":{},\"value\":{}}}", kind, a.addr, a.value)
+            })
+            .collect();
+        let operand = match rec.operand {
+            Some(v) => v.to_string(),
+            None => "null".to_string(),
+        };
+        let _ = writeln!(
+            self.out,
+            "{{\"pc\":{},\"opcode\":{},\"operand\":{},\"instr\":\"{}\",\"next_pc\":{},\"cycle\":{},\"cycles\":{},\"stolen\":{},\"taken\":{},\"regs\":[{}],\"z\":{{\"before\":{},\"after\":{}}},\"mem\":[{}]}}",
+            rec.pc,
+            rec.opcode,
+            operand,
+            rec.instr,
+            rec.next_pc,
+            rec.cycle,
+            rec.cycles,
+            rec.stolen,
+            rec.taken,
+            regs.join(","),
+            rec.z_before,
+            rec.z_after,
+            mem.join(",")
+        );
+    }
+
+    fn finish(&mut self) {
+        let _ = self.out.flush();
+    }
+}
+
+/// CSV trace with a header row. Register deltas and memory accesses are
+/// `;`-separated lists inside their columns.
+pub struct CsvTracer<W: Write> {
+    out: W,
+    header_written: bool,
+}
+
+impl<W: Write> CsvTracer<W> {
+    pub fn new(out: W) -> Self {
+        CsvTracer { out, header_written: false }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W: Write> Tracer for CsvTracer<W> {
+    fn record(&mut self, rec: &TraceRecord) {
+        if !self.header_written {
+            let _ = writeln!(self.out, "pc,opcode,operand,instr,next_pc,cycle,cycles,regs,z_before,z_after,mem,stolen,taken");
+            self.header_written = true;
+        }
+        let regs: Vec<String> =
+            rec.reg_deltas.iter().map(|d| format!("R{}:{:02X}->{:02X}", d.reg, d.before, d.after)).collect();
+        let mem: Vec<String> =
+            rec.mem.iter().map(|a| format!("{}:{:02X}={:02X}", access_letter(a.kind), a.addr, a.value)).collect();
+        let operand = rec.operand.map(|v| format!("{:02X}", v)).unwrap_or_default();
+        let _ = writeln!(
+            self.out,
+            "{:02X},{:02X},{},\"{}\",{:02X},{},{},{},{},{},{},{},{}",
+            rec.pc,
+            rec.opcode,
+            operand,
+            rec.instr,
+            rec.next_pc,
+            rec.cycle,
+            rec.cycles,
+            regs.join(";"),
+            rec.z_before as u8,
+            rec.z_after as u8,
+            mem.join(";"),
+            rec.stolen,
+            rec.taken as u8
+        );
+    }
+
+    fn finish(&mut self) {
+        let _ = self.out.flush();
+    }
+}
+
+/// Magic bytes at the start of a binary trace, followed by a version byte.
+pub const BINARY_TRACE_MAGIC: &[u8; 4] = b"TTRC";
+pub const BINARY_TRACE_VERSION: u8 = 1;
+
+/// Compact binary trace. Layout (all integers little-endian):
+///
+/// ```text
+/// header:  "TTRC" version:u8
+/// record:  pc:u16 opcode:u8 operand:u8 next_pc:u16 cycle:u64 cycles:u32 stolen:u32
+///          flags:u8   (bits 0-3: changed register mask, bit 4: Z before, bit 5: Z after,
+///                      bit 6: branch taken)
+///          per changed register (ascending): before:u8 after:u8
+///          mem_count:u8, per access: kind:u8 (0 read, 1 write, 2 fetch) addr:u16 value:u8
+/// ```
+///
+/// The operand byte is always present; it is ignored for 1-byte instructions.
+pub struct BinaryTracer<W: Write> {
+    out: W,
+    header_written: bool,
+}
+
+impl<W: Write> BinaryTracer<W> {
+    pub fn new(out: W) -> Self {
+        BinaryTracer { out, header_written: false }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W: Write> Tracer for BinaryTracer<W> {
+    fn record(&mut self, rec: &TraceRecord) {
+        let mut buf: Vec<u8> = Vec::with_capacity(32);
+        if !self.header_written {
+            buf.extend_from_slice(BINARY_TRACE_MAGIC);
+            buf.push(BINARY_TRACE_VERSION);
+            self.header_written = true;
+        }
+        buf.extend_from_slice(&(rec.pc as u16).to_le_bytes());
+        buf.push(rec.opcode);
+        buf.push(rec.operand.unwrap_or(0));
+        buf.extend_from_slice(&(rec.next_pc as u16).to_le_bytes());
+        buf.extend_from_slice(&rec.cycle.to_le_bytes());
+        buf.extend_from_slice(&(rec.cycles as u32).to_le_bytes());
+        buf.extend_from_slice(&(rec.stolen as u32).to_le_bytes());
+        let mut deltas = rec.reg_deltas.clone();
+        deltas.sort_by_key(|d| d.reg);
+        let mut flags = 0u8;
+        for d in &deltas {
+            flags |= 1 << d.reg;
+        }
+        flags |= (rec.z_before as u8) << 4;
+        flags |= (rec.z_after as u8) << 5;
+        flags |= (rec.taken as u8) << 6;
+        buf.push(flags);
+        for d in &deltas {
+            buf.push(d.before);
+            buf.push(d.after);
+        }
+        buf.push(rec.mem.len() as u8);
+        for a in &rec.mem {
+            buf.push(match a.kind {
+                AccessKind::Read => 0,
+                AccessKind::Write => 1,
+                AccessKind::Fetch => 2,
+            });
+            buf.extend_from_slice(&(a.addr as u16).to_le_bytes());
+            buf.push(a.value);
+        }
+        let _ = self.out.write_all(&buf);
+    }
+
+    fn finish(&mut self) {
+        let _ = self.out.flush();
+    }
+}
+
+/// Parse a trace produced by `BinaryTracer` back into records.
+pub fn read_binary_trace(data: &[u8]) -> Result<Vec<TraceRecord>, String> {
+    if data.is_empty() {
+        return Ok(Vec::new());
+    }
+    if data.len() < 5 || &data[..4] != BINARY_TRACE_MAGIC {
+        return Err("Not a binary trace (bad magic)".to_string());
+    }
+    let version = data[4];
+    if version != BINARY_TRACE_VERSION {
+        return Err(format!("Unsupported binary trace version {}", version));
+    }
+    let mut pos = 5usize;
+    let mut records = Vec::new();
+    // a record may only end at the end of the data; anything shorter is a truncated trace
+    while pos < data.len() {
+        let mut take = |n: usize| -> Result<&[u8], String> {
+            if pos + n > data.len() {
+                return Err(format!("Truncated binary trace at offset {}", pos));
+            }
+            let s = &data[pos..pos + n];
+            pos += n;
+            Ok(s)
+        };
+        let b = take(2)?;
+        let pc = u16::from_le_bytes([b[0], b[1]]) as usize;
+        let hdr = take(16)?;
+        let opcode = hdr[0];
+        let operand = if isa::has_operand(opcode) { Some(hdr[1]) } else { None };
+        let next_pc = u16::from_le_bytes([hdr[2], hdr[3]]) as usize;
+        let mut c = [0u8; 8];
+        c.copy_from_slice(&hdr[4..12]);
+        let cycle = u64::from_le_bytes(c);
+        let cycles = u32::from_le_bytes([hdr[12], hdr[13], hdr[14], hdr[15]]) as u64;
+        let s = take(4)?;
+        let stolen = u32::from_le_bytes([s[0], s[1], s[2], s[3]]) as u64;
+        let flags = take(1)?[0];
+        let mut reg_deltas = Vec::new();
+        for reg in 0..4 {
+            if flags & (1 << reg) != 0 {
+                let v = take(2)?;
+                reg_deltas.push(RegDelta { reg, before: v[0], after: v[1] });
+            }
+        }
+        let count = take(1)?[0];
+        let mut mem = Vec::new();
+        for _ in 0..count {
+            let a = take(4)?;
+            let kind = match a[0] {
+                0 => AccessKind::Read,
+                1 => AccessKind::Write,
+                _ => AccessKind::Fetch,
+            };
+            mem.push(MemAccess { kind, addr: u16::from_le_bytes([a[1], a[2]]) as usize, value: a[3] });
+        }
+        records.push(TraceRecord {
+            pc,
+            opcode,
+            operand,
+            instr: isa::decode(opcode, operand.unwrap_or(0)),
+            next_pc,
+            reg_deltas,
+            z_before: flags & 0x10 != 0,
+            z_after: flags & 0x20 != 0,
+            mem,
+            cycle,
+            cycles,
+            stolen,
+            taken: flags & 0x40 != 0,
+        });
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CPU;
+
+    fn sample_program() -> Vec<u8> {
+        vec![
+            0x10, 0x05, // LDI R0,5
+            0x34, 0x80, // STORE R0,0x80
+            0x31, 0x80, // LOAD R1,0x80
+            0x25, 0x00, // SUB R1,R0
+            0xFF,       // HLT
+        ]
+    }
+
+    #[test]
+    fn binary_trace_roundtrip() {
+        let sink = Rc::new(RefCell::new(BinaryTracer::new(Vec::new())));
+        let text = Rc::new(RefCell::new(TextTracer::new(Vec::new())));
+        let mut cpu = CPU::new();
+        cpu.attach_tracer(Box::new(sink.clone()));
+        cpu.attach_tracer(Box::new(text.clone()));
+        cpu.load(&sample_program(), 0);
+        cpu.run();
+
+        let bin = sink.borrow().out.clone();
+        let recs = read_binary_trace(&bin).expect("parse");
+        assert_eq!(recs.len(), 5);
+        // a cut-off record is an error, even a one-byte tail
+        assert!(read_binary_trace(&bin[..bin.len() - 3]).is_err());
+        assert!(read_binary_trace(&[&bin[..], &[0]].concat()).is_err());
+        let mut other_version = bin.clone();
+        other_version[4] = BINARY_TRACE_VERSION + 1;
+        assert_eq!(read_binary_trace(&other_version).unwrap_err(), "Unsupported binary trace version 2");
+        assert_eq!(recs[0].reg_deltas, vec![RegDelta { reg: 0, before: 0, after: 5 }]);
+        assert_eq!(recs[1].mem, vec![MemAccess { kind: AccessKind::Write, addr: 0x80, value: 5 }]);
+        assert_eq!(recs[2].instr, Instruction::Load { dest: 1, addr: 0x80 });
+        assert!(!recs[3].z_before && recs[3].z_after);
+        assert_eq!(recs[4].cycle, 2 + 4 + 4 + 3);
+
+        let text = String::from_utf8(text.borrow().out.clone()).unwrap();
+        assert!(text.lines().nth(3).unwrap().contains("SUB R1, R0"));
+
+        // JZ to the next instruction: only the taken flag shows the outcome
+        let sink = Rc::new(RefCell::new(BinaryTracer::new(Vec::new())));
+        let mut cpu = CPU::new();
+        cpu.attach_tracer(Box::new(sink.clone()));
+        cpu.load(&[0x44, 0x02, 0x45, 0x04, 0xFF], 0); // JZ R0,0x02; JZ R1,0x04; HLT
+        cpu.regs[1] = 1;
+        cpu.run();
+        let recs = read_binary_trace(&sink.borrow().out).expect("parse");
+        assert_eq!(recs.iter().map(|r| r.taken).collect::<Vec<_>>(), vec![true, false, false]);
+    }
+
+    #[test]
+    fn json_and_csv_work_with_stepping() {
+        let json = Rc::new(RefCell::new(JsonLinesTracer::new(Vec::new())));
+        let csv = Rc::new(RefCell::new(CsvTracer::new(Vec::new())));
+        let mut cpu = CPU::new();
+        cpu.attach_tracer(Box::new(json.clone()));
+        cpu.attach_tracer(Box::new(csv.clone()));
+        cpu.load(&sample_program(), 0);
+        cpu.step_n_instructions(2);
+
+        let json = String::from_utf8(json.borrow().out.clone()).unwrap();
+        let first = json.lines().next().unwrap();
+        assert!(first.starts_with("{\"pc\":0,\"opcode\":16,\"operand\":5,\"instr\":\"LDI R0, 5\""));
+        assert!(json.lines().nth(1).unwrap().contains("\"mem\":[{\"kind\":\"write\",\"addr\":128,\"value\":5}]"));
+
+        let csv = String::from_utf8(csv.borrow().out.clone()).unwrap();
+        assert_eq!(csv.lines().count(), 3);
+        assert_eq!(csv.lines().nth(1).unwrap(), "00,10,05,\"LDI R0, 5\",02,0,2,R0:00->05,0,0,,0,0");
+    }
+}
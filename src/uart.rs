@@ -0,0 +1,528 @@
+// src/uart.rs
+use crate::device::Device;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt::{Debug, Formatter};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver};
+
+/// Default base address of the UART register window (see `CPU::attach_mapped_device`).
+pub const UART_BASE: usize = 0xF8;
+
+/// Register offsets within the UART's window.
+/// DATA: reading pops the RX FIFO (0 when empty), writing pushes the TX FIFO.
+pub const UART_DATA: usize = 0;
+/// STATUS (read-only, see the `STATUS_*` bits). Reading clears `STATUS_OVERRUN`.
+pub const UART_STATUS: usize = 1;
+/// CTRL (see the `CTRL_*` bits).
+pub const UART_CTRL: usize = 2;
+/// BAUD: cycles per byte. Writing 0 keeps the current setting.
+pub const UART_BAUD: usize = 3;
+/// RXCOUNT (read-only): bytes waiting in the RX FIFO. Handy without bitwise instructions.
+pub const UART_RX_COUNT: usize = 4;
+/// TXPEND (read-only): bytes not yet sent, including the one being shifted out.
+pub const UART_TX_PENDING: usize = 5;
+/// Number of registers.
+pub const UART_REGS: usize = 6;
+
+/// At least one received byte is waiting in the RX FIFO.
+pub const STATUS_RX_READY: u8 = 0x01;
+/// TX FIFO empty and nothing being shifted out.
+pub const STATUS_TX_EMPTY: u8 = 0x02;
+/// TX FIFO full: further writes to DATA are dropped.
+pub const STATUS_TX_FULL: u8 = 0x04;
+/// A received byte was lost because the RX FIFO was full.
+pub const STATUS_OVERRUN: u8 = 0x08;
+
+/// Raise the interrupt line while received data is waiting.
+pub const CTRL_RX_IRQ: u8 = 0x01;
+/// Raise the interrupt line while the transmitter is empty.
+pub const CTRL_TX_IRQ: u8 = 0x02;
+
+/// Host side of a serial line. Both calls must not block: `read_byte` returns
+/// `None` when no input is available yet.
+pub trait SerialPort {
+    fn read_byte(&mut self) -> Option<u8>;
+    fn write_byte(&mut self, byte: u8);
+
+    /// Where the port connects to, for `Uart::describe` (e.g. a pty's slave path).
+    fn describe(&self) -> String {
+        String::new()
+    }
+}
+
+impl Debug for dyn SerialPort {
+    fn fmt(&self, _: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        Ok(())
+    }
+}
+
+/// Lets callers keep a handle on a port (e.g. a test's `BufferPort`).
+impl<T: SerialPort + ?Sized> SerialPort for Rc<RefCell<T>> {
+    fn read_byte(&mut self) -> Option<u8> {
+        self.borrow_mut().read_byte()
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        self.borrow_mut().write_byte(byte)
+    }
+
+    fn describe(&self) -> String {
+        self.borrow().describe()
+    }
+}
+
+/// In-memory port: input is queued up front, output is collected.
+#[derive(Debug, Default)]
+pub struct BufferPort {
+    pub input: VecDeque<u8>,
+    pub output: Vec<u8>,
+}
+
+impl BufferPort {
+    pub fn new(input: &[u8]) -> Self {
+        BufferPort { input: input.iter().copied().collect(), output: Vec::new() }
+    }
+
+    /// Output so far as (lossy) UTF-8.
+    pub fn output_string(&self) -> String {
+        String::from_utf8_lossy(&self.output).into_owned()
+    }
+}
+
+impl SerialPort for BufferPort {
+    fn read_byte(&mut self) -> Option<u8> {
+        self.input.pop_front()
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        self.output.push(byte);
+    }
+}
+
+/// Reads `reader` on a background thread so the emulator can poll it without blocking.
+pub(crate) fn spawn_reader<R: Read + Send + 'static>(mut reader: R) -> Receiver<u8> {
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut buf = [0u8; 64];
+        while let Ok(n) = reader.read(&mut buf) {
+            if n == 0 || buf[..n].iter().any(|b| tx.send(*b).is_err()) {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+/// Host stdout, and optionally stdin. With input enabled a background thread owns
+/// stdin for the rest of the process, so it is for command-line runs only: the REPL
+/// refuses it.
+pub struct StdioPort {
+    input: Option<Receiver<u8>>,
+}
+
+impl StdioPort {
+    pub fn new(input: bool) -> Self {
+        StdioPort { input: input.then(|| spawn_reader(io::stdin())) }
+    }
+}
+
+impl SerialPort for StdioPort {
+    fn read_byte(&mut self) -> Option<u8> {
+        self.input.as_ref().and_then(|rx| rx.try_recv().ok())
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        let mut out = io::stdout();
+        let _ = out.write_all(&[byte]);
+        let _ = out.flush();
+    }
+}
+
+/// Input read from one file, output appended to another (either may be absent).
+pub struct FilePort {
+    input: VecDeque<u8>,
+    output: Option<File>,
+}
+
+impl FilePort {
+    pub fn open(input: Option<&str>, output: Option<&str>) -> Result<Self, String> {
+        let input = match input {
+            Some(path) => std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?.into(),
+            None => VecDeque::new(),
+        };
+        let output = match output {
+            Some(path) => Some(File::create(path).map_err(|e| format!("{}: {}", path, e))?),
+            None => None,
+        };
+        Ok(FilePort { input, output })
+    }
+}
+
+impl SerialPort for FilePort {
+    fn read_byte(&mut self) -> Option<u8> {
+        self.input.pop_front()
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        if let Some(f) = self.output.as_mut() {
+            let _ = f.write_all(&[byte]);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod pty {
+    use std::ffi::CStr;
+    use std::fs::File;
+    use std::os::fd::FromRawFd;
+    use std::os::raw::{c_char, c_int};
+
+    const O_RDWR: c_int = 0o2;
+    const O_NOCTTY: c_int = 0o400;
+    const O_NONBLOCK: c_int = 0o4000;
+
+    extern "C" {
+        fn posix_openpt(flags: c_int) -> c_int;
+        fn grantpt(fd: c_int) -> c_int;
+        fn unlockpt(fd: c_int) -> c_int;
+        fn ptsname_r(fd: c_int, buf: *mut c_char, len: usize) -> c_int;
+        fn close(fd: c_int) -> c_int;
+    }
+
+    /// Open a non-blocking pseudo-terminal master; returns it with the slave's path.
+    pub fn open() -> Result<(File, String), String> {
+        // SAFETY: plain libc calls on a descriptor we own; the buffer outlives ptsname_r.
+        unsafe {
+            let fd = posix_openpt(O_RDWR | O_NOCTTY | O_NONBLOCK);
+            if fd < 0 {
+                return Err(format!("posix_openpt failed: {}", std::io::Error::last_os_error()));
+            }
+            let mut buf = [0 as c_char; 128];
+            if grantpt(fd) != 0 || unlockpt(fd) != 0 || ptsname_r(fd, buf.as_mut_ptr(), buf.len()) != 0 {
+                let err = std::io::Error::last_os_error();
+                close(fd);
+                return Err(format!("pty setup failed: {}", err));
+            }
+            let name = CStr::from_ptr(buf.as_ptr()).to_string_lossy().into_owned();
+            Ok((File::from_raw_fd(fd), name))
+        }
+    }
+}
+
+/// Linux pseudo-terminal. Connect a terminal program (e.g. `screen <slave_path>`)
+/// to the slave side to talk to the emulated program.
+#[cfg(target_os = "linux")]
+pub struct PtyPort {
+    master: File,
+    slave_path: String,
+}
+
+#[cfg(target_os = "linux")]
+impl PtyPort {
+    pub fn open() -> Result<Self, String> {
+        let (master, slave_path) = pty::open()?;
+        Ok(PtyPort { master, slave_path })
+    }
+
+    pub fn slave_path(&self) -> &str {
+        &self.slave_path
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl SerialPort for PtyPort {
+    fn read_byte(&mut self) -> Option<u8> {
+        let mut b = [0u8; 1];
+        match self.master.read(&mut b) {
+            Ok(1) => Some(b[0]),
+            _ => None,
+        }
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        let _ = self.master.write_all(&[byte]);
+    }
+
+    fn describe(&self) -> String {
+        format!("pty {}", self.slave_path)
+    }
+}
+
+/// Build a host port from a short spec: `stdio`, `stdout` (no input), `pty` (Linux),
+/// `file:<out>` or `file:<in>:<out>` (an empty path disables that direction).
+/// Returns the port and, for `pty`, the slave path a terminal program can open.
+pub fn open_port(spec: &str) -> Result<(Box<dyn SerialPort>, Option<String>), String> {
+    fn nonempty(s: &str) -> Option<&str> {
+        if s.is_empty() {
+            None
+        } else {
+            Some(s)
+        }
+    }
+    match spec {
+        "stdio" => Ok((Box::new(StdioPort::new(true)), None)),
+        "stdout" => Ok((Box::new(StdioPort::new(false)), None)),
+        #[cfg(target_os = "linux")]
+        "pty" => {
+            let port = PtyPort::open()?;
+            let path = port.slave_path().to_string();
+            Ok((Box::new(port), Some(path)))
+        }
+        _ => match spec.strip_prefix("file:") {
+            Some(paths) => {
+                let (input, output) = match paths.split_once(':') {
+                    Some((i, o)) => (nonempty(i), nonempty(o)),
+                    None => (None, nonempty(paths)),
+                };
+                Ok((Box::new(FilePort::open(input, output)?), None))
+            }
+            None => Err(format!("unknown serial port '{}' (use stdio, stdout, pty or file:...)", spec)),
+        },
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UartConfig {
+    /// Cycles to shift one byte in or out (the baud rate, in CPU cycles).
+    pub cycles_per_byte: u64,
+    /// Depth of each of the RX and TX FIFOs.
+    pub fifo_depth: usize,
+}
+
+impl Default for UartConfig {
+    fn default() -> Self {
+        UartConfig { cycles_per_byte: 10, fifo_depth: 16 }
+    }
+}
+
+/// Serial port device with RX/TX FIFOs. Attach it with `CPU::attach_mapped_device`
+/// (conventionally at `UART_BASE`).
+///
+/// The transmitter sends the head of the TX FIFO to the host `cycles_per_byte` cycles
+/// after it starts shifting it; the receiver polls the host once every `cycles_per_byte`
+/// cycles. Bytes still in the TX FIFO when the program halts are not sent, so programs
+/// should wait for `STATUS_TX_EMPTY` first.
+#[derive(Debug)]
+pub struct Uart {
+    port: Box<dyn SerialPort>,
+    config: UartConfig,
+    ctrl: u8,
+    rx: VecDeque<u8>,
+    tx: VecDeque<u8>,
+    /// Byte being shifted out and the cycle it finishes.
+    shifting: Option<(u8, u64)>,
+    next_rx: u64,
+    overrun: bool,
+    cycle: u64,
+}
+
+impl Uart {
+    pub fn new(port: Box<dyn SerialPort>, config: UartConfig) -> Self {
+        let config = UartConfig { cycles_per_byte: config.cycles_per_byte.max(1), fifo_depth: config.fifo_depth.max(1) };
+        Uart {
+            port,
+            config,
+            ctrl: 0,
+            rx: VecDeque::new(),
+            tx: VecDeque::new(),
+            shifting: None,
+            next_rx: config.cycles_per_byte,
+            overrun: false,
+            cycle: 0,
+        }
+    }
+
+    pub fn config(&self) -> UartConfig {
+        self.config
+    }
+
+    fn tx_empty(&self) -> bool {
+        self.tx.is_empty() && self.shifting.is_none()
+    }
+
+    fn status(&self) -> u8 {
+        let mut s = 0;
+        if !self.rx.is_empty() {
+            s |= STATUS_RX_READY;
+        }
+        if self.tx_empty() {
+            s |= STATUS_TX_EMPTY;
+        }
+        if self.tx.len() >= self.config.fifo_depth {
+            s |= STATUS_TX_FULL;
+        }
+        if self.overrun {
+            s |= STATUS_OVERRUN;
+        }
+        s
+    }
+}
+
+impl Device for Uart {
+    fn describe(&self) -> String {
+        let mut s = format!(
+            "uart ctrl={:02X} rx={} tx={} shifting={} overrun={}",
+            self.ctrl,
+            self.rx.len(),
+            self.tx.len(),
+            self.shifting.is_some(),
+            self.overrun
+        );
+        let port = self.port.describe();
+        if !port.is_empty() {
+            s.push_str(&format!(" port=\"{}\"", port));
+        }
+        s
+    }
+
+    fn tick(&mut self, current_cycle: u64) {
+        self.cycle = current_cycle;
+        if let Some((byte, done)) = self.shifting {
+            if current_cycle >= done {
+                self.port.write_byte(byte);
+                self.shifting = None;
+            }
+        }
+        if self.shifting.is_none() {
+            if let Some(byte) = self.tx.pop_front() {
+                self.shifting = Some((byte, current_cycle + self.config.cycles_per_byte));
+            }
+        }
+        if current_cycle >= self.next_rx {
+            self.next_rx = current_cycle + self.config.cycles_per_byte;
+            if let Some(byte) = self.port.read_byte() {
+                if self.rx.len() < self.config.fifo_depth {
+                    self.rx.push_back(byte);
+                } else {
+                    self.overrun = true;
+                }
+            }
+        }
+    }
+
+    fn mmio_size(&self) -> usize {
+        UART_REGS
+    }
+
+    fn mmio_read(&mut self, offset: usize) -> u8 {
+        match offset {
+            UART_DATA => self.rx.pop_front().unwrap_or(0),
+            UART_STATUS => {
+                let s = self.status();
+                self.overrun = false;
+                s
+            }
+            UART_CTRL => self.ctrl,
+            UART_BAUD => self.config.cycles_per_byte.min(255) as u8,
+            UART_RX_COUNT => self.rx.len() as u8,
+            UART_TX_PENDING => (self.tx.len() + self.shifting.is_some() as usize) as u8,
+            _ => 0,
+        }
+    }
+
+    fn mmio_write(&mut self, offset: usize, value: u8) {
+        match offset {
+            // dropped when the TX FIFO is full
+            UART_DATA if self.tx.len() < self.config.fifo_depth => self.tx.push_back(value),
+            UART_CTRL => self.ctrl = value,
+            UART_BAUD if value != 0 => {
+                self.config.cycles_per_byte = value as u64;
+                self.next_rx = self.cycle + value as u64;
+            }
+            _ => {}
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        (self.ctrl & CTRL_RX_IRQ != 0 && !self.rx.is_empty()) || (self.ctrl & CTRL_TX_IRQ != 0 && self.tx_empty())
+    }
+
+    fn watches_fetches(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::cpu::CPU;
+
+    /// Echo characters back until a NUL byte arrives, then wait for the transmitter to drain.
+    const ECHO: &str = r#"
+        loop:
+        LOAD R0, 0xFC       ; RXCOUNT
+        JZ R0, loop
+        LOAD R2, 0xF8       ; DATA
+        JZ R2, drain
+        STORE R2, 0xF8
+        JMP loop
+        drain:
+        LOAD R0, 0xFD       ; TXPEND
+        JZ R0, done
+        JMP drain
+        done:
+        HLT
+    "#;
+
+    #[test]
+    fn echoes_through_buffer_port_with_baud_timing() {
+        let port = Rc::new(RefCell::new(BufferPort::new(b"hi!\0")));
+        let mut cpu = CPU::new();
+        let config = UartConfig { cycles_per_byte: 20, fifo_depth: 4 };
+        cpu.attach_mapped_device(Box::new(Uart::new(Box::new(port.clone()), config)), UART_BASE).unwrap();
+        cpu.load(&assemble(ECHO).unwrap(), 0);
+        cpu.run();
+        assert_eq!(port.borrow().output_string(), "hi!");
+        // 4 bytes arrive one per 20 cycles; the last echo needs another 20 to shift out
+        assert!(cpu.cycles >= 4 * 20 + 20, "{}", cpu.cycles);
+    }
+
+    #[test]
+    fn fifo_overrun_and_interrupt_line() {
+        let port = Rc::new(RefCell::new(BufferPort::new(b"abc")));
+        let mut uart = Uart::new(Box::new(port.clone()), UartConfig { cycles_per_byte: 1, fifo_depth: 2 });
+        assert!(!uart.irq_pending());
+        uart.mmio_write(UART_CTRL, CTRL_RX_IRQ);
+        for c in 1..=3 {
+            uart.tick(c);
+        }
+        assert!(uart.irq_pending());
+        assert_eq!(uart.mmio_read(UART_STATUS), STATUS_RX_READY | STATUS_TX_EMPTY | STATUS_OVERRUN);
+        assert_eq!(uart.mmio_read(UART_DATA), b'a');
+        assert_eq!(uart.mmio_read(UART_DATA), b'b');
+        assert_eq!(uart.mmio_read(UART_STATUS), STATUS_TX_EMPTY);
+        assert!(!uart.irq_pending());
+
+        uart.mmio_write(UART_DATA, b'x');
+        uart.tick(4);
+        assert_eq!(uart.mmio_read(UART_STATUS) & STATUS_TX_EMPTY, 0);
+        uart.tick(5);
+        assert_eq!(port.borrow().output, b"x");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn pty_port_reads_what_the_slave_writes() {
+        let mut port = match PtyPort::open() {
+            Ok(p) => p,
+            Err(_) => return, // no pty support in this environment
+        };
+        let mut slave = std::fs::OpenOptions::new().write(true).open(port.slave_path()).unwrap();
+        slave.write_all(b"ok").unwrap();
+        let mut got = Vec::new();
+        for _ in 0..1000 {
+            if let Some(b) = port.read_byte() {
+                got.push(b);
+            }
+            if got.len() == 2 {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        assert_eq!(got, b"ok");
+    }
+}
@@ -0,0 +1,234 @@
+// src/watchdog.rs
+use crate::device::{CpuRequest, Device, DeviceContext};
+
+/// Default base address of the watchdog's register window (see `CPU::attach_mapped_device`).
+pub const WATCHDOG_BASE: usize = 0xB0;
+
+/// Register offsets within the watchdog's window.
+/// KICK: writing `KICK_VALUE` restarts the countdown; other values are ignored.
+pub const WATCHDOG_KICK: usize = 0;
+/// CTRL (see `CTRL_ENABLE`). Enabling restarts the countdown.
+pub const WATCHDOG_CTRL: usize = 1;
+/// TIMEOUT: countdown length in units of `WatchdogConfig::cycles_per_unit`.
+pub const WATCHDOG_TIMEOUT: usize = 2;
+/// COUNT: units left before expiry (read-only).
+pub const WATCHDOG_COUNT: usize = 3;
+/// STATUS: number of expiries since it was last read (saturating), so a program can
+/// tell a watchdog reset from a cold start. Reading clears it.
+pub const WATCHDOG_STATUS: usize = 4;
+/// Number of registers.
+pub const WATCHDOG_REGS: usize = 5;
+
+pub const KICK_VALUE: u8 = 0x5A;
+pub const CTRL_ENABLE: u8 = 0x01;
+
+/// What happens to the CPU when the watchdog expires.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WatchdogAction {
+    /// Warm reset: execution restarts at address 0.
+    #[default]
+    Reset,
+    /// Non-maskable interrupt at `CPU::nmi_vector`.
+    Nmi,
+    /// Stop the CPU with `CPU::fault` set.
+    Fault,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchdogConfig {
+    pub action: WatchdogAction,
+    pub cycles_per_unit: u64,
+    /// TIMEOUT at power-on.
+    pub timeout: u8,
+    /// Counting from power-on, so a program that never touches the watchdog is caught too.
+    pub enabled: bool,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        WatchdogConfig { action: WatchdogAction::Reset, cycles_per_unit: 256, timeout: 0xFF, enabled: true }
+    }
+}
+
+/// Watchdog timer. Unless kicked within TIMEOUT units it expires, acts on the CPU
+/// through its `DeviceContext` and starts counting again.
+#[derive(Debug)]
+pub struct Watchdog {
+    config: WatchdogConfig,
+    ctrl: u8,
+    timeout: u8,
+    /// Cycles left before expiry.
+    remaining: u64,
+    expired: u8,
+    /// Cycle of the previous tick.
+    last: Option<u64>,
+}
+
+impl Watchdog {
+    pub fn new(config: WatchdogConfig) -> Self {
+        let config = WatchdogConfig { cycles_per_unit: config.cycles_per_unit.max(1), ..config };
+        let mut wd = Watchdog {
+            config,
+            ctrl: if config.enabled { CTRL_ENABLE } else { 0 },
+            timeout: config.timeout,
+            remaining: 0,
+            expired: 0,
+            last: None,
+        };
+        wd.kick();
+        wd
+    }
+
+    fn kick(&mut self) {
+        self.remaining = self.timeout.max(1) as u64 * self.config.cycles_per_unit;
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.ctrl & CTRL_ENABLE != 0
+    }
+}
+
+impl Device for Watchdog {
+    fn describe(&self) -> String {
+        format!(
+            "watchdog {:?} ctrl={:02X} timeout={} remaining={} cycles expired={}",
+            self.config.action, self.ctrl, self.timeout, self.remaining, self.expired
+        )
+    }
+
+    fn tick_with(&mut self, ctx: &mut DeviceContext<'_>) {
+        let now = ctx.cycle();
+        let mut elapsed = now.saturating_sub(self.last.unwrap_or(now.saturating_sub(1)));
+        self.last = Some(now);
+        if !self.enabled() {
+            return;
+        }
+        while elapsed >= self.remaining {
+            elapsed -= self.remaining;
+            self.expired = self.expired.saturating_add(1);
+            ctx.request(match self.config.action {
+                WatchdogAction::Reset => CpuRequest::Reset,
+                WatchdogAction::Nmi => CpuRequest::Nmi,
+                WatchdogAction::Fault => CpuRequest::Fault(format!("watchdog expired at cycle {}", ctx.cycle())),
+            });
+            self.kick();
+        }
+        self.remaining -= elapsed;
+    }
+
+    fn next_event(&self, now: u64) -> Option<u64> {
+        self.enabled().then_some(now + self.remaining)
+    }
+
+    fn mmio_size(&self) -> usize {
+        WATCHDOG_REGS
+    }
+
+    fn mmio_read(&mut self, offset: usize) -> u8 {
+        match offset {
+            WATCHDOG_CTRL => self.ctrl,
+            WATCHDOG_TIMEOUT => self.timeout,
+            WATCHDOG_COUNT => self.remaining.div_ceil(self.config.cycles_per_unit) as u8,
+            WATCHDOG_STATUS => std::mem::take(&mut self.expired),
+            _ => 0,
+        }
+    }
+
+    fn mmio_write(&mut self, offset: usize, value: u8) {
+        match offset {
+            WATCHDOG_KICK if value == KICK_VALUE => self.kick(),
+            WATCHDOG_CTRL => {
+                self.ctrl = value;
+                self.kick();
+            }
+            WATCHDOG_TIMEOUT => self.timeout = value,
+            _ => {}
+        }
+    }
+
+    fn watches_fetches(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::cpu::{ExecMode, CPU};
+
+    /// Counts boots at 0x80 and spins without kicking once it has booted three times.
+    const RUNAWAY: &str = r#"
+        LOAD R0, 0x80
+        LDI R1, 1
+        ADD R0, R1
+        STORE R0, 0x80
+        LDI R2, 3
+        SUB R2, R0
+        JZ R2, done
+        spin:
+        JMP spin
+        done:
+        HLT
+    "#;
+
+    fn config(action: WatchdogAction) -> WatchdogConfig {
+        WatchdogConfig { action, cycles_per_unit: 10, timeout: 5, enabled: true }
+    }
+
+    #[test]
+    fn expiry_resets_or_faults_a_runaway_program() {
+        for mode in [ExecMode::Instruction, ExecMode::MicroCycle] {
+            let mut cpu = CPU::new();
+            cpu.mode = mode;
+            cpu.attach_mapped_device(Box::new(Watchdog::new(config(WatchdogAction::Reset))), WATCHDOG_BASE).unwrap();
+            cpu.load(&assemble(RUNAWAY).unwrap(), 0);
+            cpu.run();
+            assert_eq!(cpu.mem.read(0x80), 3);
+            assert_eq!(cpu.resets, 2);
+            assert!(cpu.fault.is_none());
+            assert!((100..150).contains(&cpu.cycles), "{}", cpu.cycles);
+        }
+
+        let mut cpu = CPU::new();
+        cpu.attach_mapped_device(Box::new(Watchdog::new(config(WatchdogAction::Fault))), WATCHDOG_BASE).unwrap();
+        cpu.load(&assemble(RUNAWAY).unwrap(), 0);
+        cpu.run();
+        assert_eq!(cpu.mem.read(0x80), 1);
+        assert_eq!(cpu.fault.as_deref(), Some("watchdog expired at cycle 50"));
+        assert!(cpu.halted);
+    }
+
+    #[test]
+    fn kicked_watchdog_stays_quiet_and_nmi_ignores_di() {
+        // kick every loop pass for 255 passes, well beyond the 50-cycle timeout
+        let kicker = r#"
+            LDI R1, 1
+            LDI R2, 0x5A
+            LDI R3, 0
+            loop:
+            STORE R2, 0xB0
+            SUB R3, R1
+            JZ R3, done
+            JMP loop
+            done:
+            HLT
+        "#;
+        let mut cpu = CPU::new();
+        cpu.attach_mapped_device(Box::new(Watchdog::new(config(WatchdogAction::Reset))), WATCHDOG_BASE).unwrap();
+        cpu.load(&assemble(kicker).unwrap(), 0);
+        cpu.run();
+        assert!(cpu.cycles > 500);
+        assert_eq!(cpu.resets, 0);
+
+        // the NMI handler (reached through the JMP at the NMI vector) reads STATUS and stops
+        let mut cpu = CPU::new();
+        cpu.attach_mapped_device(Box::new(Watchdog::new(config(WatchdogAction::Nmi))), WATCHDOG_BASE).unwrap();
+        cpu.mem.write_bytes(0xCE, &assemble("JMP 0x60").unwrap());
+        cpu.mem.write_bytes(0x60, &assemble("LOAD R3, 0xB4\nHLT").unwrap());
+        cpu.load(&assemble("DI\nspin:\nJMP spin").unwrap(), 0);
+        cpu.run();
+        assert_eq!(cpu.regs[3], 1);
+        assert_eq!(cpu.resets, 0);
+    }
+}